@@ -0,0 +1,522 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::UdpSocket;
+use std::os::unix::net::UnixDatagram;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Local, Utc};
+use log::{error, warn};
+use pingora::prelude::*;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde_json::{Map, Value};
+
+use crate::config::{AccessLogConfig, AccessLogFormat, AccessLogOutput, CONFIG_ERROR};
+use crate::template::{header, Segment, Template};
+
+/// Lines buffered for the writer thread before new ones are dropped
+const ACCESS_LOG_QUEUE: usize = 8192;
+
+/// The syslog priority of access log lines: facility local0, severity info
+const SYSLOG_PRIORITY: u8 = 16 * 8 + 6;
+
+const COMMON_TEMPLATE: &str =
+    "$remote_addr - $remote_user [$time_local] \"$request\" $status $body_bytes_sent";
+const COMBINED_TEMPLATE: &str = "$remote_addr - $remote_user [$time_local] \"$request\" $status \
+     $body_bytes_sent \"$http_referer\" \"$http_user_agent\"";
+
+/// Everything known about a finished request that can be logged
+pub struct AccessRecord<'a> {
+    pub client_addr: Option<&'a SocketAddr>,
+    /// The authenticated user
+    pub user: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    pub start: SystemTime,
+    pub duration: Duration,
+    pub req: &'a RequestHeader,
+    /// 0 if no response was sent
+    pub status: u16,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub route: Option<&'a str>,
+    pub upstream: Option<&'a str>,
+    pub upstream_addr: Option<&'a str>,
+    /// The request-target sent upstream, after any rewrite
+    pub upstream_uri: Option<&'a str>,
+    /// `HIT`, `MISS` etc. if the cache applies to the request
+    pub cache_status: Option<&'a str>,
+    /// Machine-readable, such as `connect_refused`, if the request failed
+    pub error_code: Option<&'a str>,
+    pub error_cause: Option<&'a str>,
+}
+
+/// A value of the template language, `$name` or `${name}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    RemoteAddr,
+    RemoteUser,
+    RequestId,
+    TimeLocal,
+    TimeIso8601,
+    Request,
+    RequestMethod,
+    RequestUri,
+    ServerProtocol,
+    Status,
+    BodyBytesSent,
+    BytesReceived,
+    RequestTime,
+    Host,
+    Route,
+    Upstream,
+    UpstreamAddr,
+    UpstreamUri,
+    UpstreamCacheStatus,
+    ErrorCode,
+    ErrorCause,
+}
+
+const VARIABLES: &[(&str, Variable)] = &[
+    ("remote_addr", Variable::RemoteAddr),
+    ("remote_user", Variable::RemoteUser),
+    ("request_id", Variable::RequestId),
+    ("time_local", Variable::TimeLocal),
+    ("time_iso8601", Variable::TimeIso8601),
+    ("request", Variable::Request),
+    ("request_method", Variable::RequestMethod),
+    ("request_uri", Variable::RequestUri),
+    ("server_protocol", Variable::ServerProtocol),
+    ("status", Variable::Status),
+    ("body_bytes_sent", Variable::BodyBytesSent),
+    ("bytes_received", Variable::BytesReceived),
+    ("request_time", Variable::RequestTime),
+    ("host", Variable::Host),
+    ("route", Variable::Route),
+    ("upstream", Variable::Upstream),
+    ("upstream_addr", Variable::UpstreamAddr),
+    ("upstream_uri", Variable::UpstreamUri),
+    ("upstream_cache_status", Variable::UpstreamCacheStatus),
+    ("error_code", Variable::ErrorCode),
+    ("error_cause", Variable::ErrorCause),
+];
+
+impl Variable {
+    /// The value of this variable for `record`, `None` if it has none.
+    fn value(self, record: &AccessRecord) -> Option<String> {
+        let req = record.req;
+        match self {
+            Variable::RemoteAddr => record.client_addr.map(|addr| match addr.as_inet() {
+                Some(inet) => inet.ip().to_string(),
+                None => addr.to_string(),
+            }),
+            Variable::RemoteUser => record.user.map(str::to_string),
+            Variable::RequestId => record.request_id.map(str::to_string),
+            Variable::TimeLocal => Some(
+                DateTime::<Local>::from(record.start)
+                    .format("%d/%b/%Y:%H:%M:%S %z")
+                    .to_string(),
+            ),
+            Variable::TimeIso8601 => Some(
+                DateTime::<Utc>::from(record.start)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            ),
+            Variable::Request => Some(format!(
+                "{} {} {:?}",
+                req.method,
+                String::from_utf8_lossy(req.raw_path()),
+                req.version
+            )),
+            Variable::RequestMethod => Some(req.method.to_string()),
+            Variable::RequestUri => Some(String::from_utf8_lossy(req.raw_path()).into_owned()),
+            Variable::ServerProtocol => Some(format!("{:?}", req.version)),
+            Variable::Status => Some(record.status.to_string()),
+            Variable::BodyBytesSent => Some(record.bytes_sent.to_string()),
+            Variable::BytesReceived => Some(record.bytes_received.to_string()),
+            Variable::RequestTime => Some(format!("{:.3}", record.duration.as_secs_f64())),
+            Variable::Host => header(req, "host"),
+            Variable::Route => record.route.map(str::to_string),
+            Variable::Upstream => record.upstream.map(str::to_string),
+            Variable::UpstreamAddr => record.upstream_addr.map(str::to_string),
+            Variable::UpstreamUri => record.upstream_uri.map(str::to_string),
+            Variable::UpstreamCacheStatus => record.cache_status.map(str::to_string),
+            Variable::ErrorCode => record.error_code.map(str::to_string),
+            Variable::ErrorCause => record.error_cause.map(str::to_string),
+        }
+    }
+}
+
+/// Renders a template for `record`.
+fn render(template: &Template<Variable>, record: &AccessRecord) -> String {
+    let mut line = String::new();
+    for segment in template.segments() {
+        let value = match segment {
+            Segment::Literal(literal) | Segment::Numbered(literal) => {
+                line.push_str(literal);
+                continue;
+            }
+            Segment::Variable(variable) => variable.value(record),
+            Segment::Header(name) => header(record.req, name),
+        };
+        match value.as_deref() {
+            None | Some("") => line.push('-'),
+            // Keep each record on one line and quoted values unambiguous
+            Some(value) => line.extend(value.escape_default()),
+        }
+    }
+    line
+}
+
+enum Format {
+    Template(Template<Variable>),
+    Json,
+}
+
+impl Format {
+    fn render(&self, record: &AccessRecord) -> String {
+        match self {
+            Format::Template(template) => render(template, record),
+            Format::Json => {
+                let mut object = Map::new();
+                for (name, variable) in VARIABLES {
+                    let value = match (variable, variable.value(record)) {
+                        (_, None) => Value::Null,
+                        (Variable::Status, _) => record.status.into(),
+                        (Variable::BodyBytesSent, _) => record.bytes_sent.into(),
+                        (Variable::BytesReceived, _) => record.bytes_received.into(),
+                        (Variable::RequestTime, _) => record.duration.as_secs_f64().into(),
+                        (_, Some(value)) => value.into(),
+                    };
+                    object.insert(name.to_string(), value);
+                }
+                for name in ["referer", "user-agent"] {
+                    let key = format!("http_{}", name.replace('-', "_"));
+                    object.insert(key, header(record.req, name).into());
+                }
+                Value::Object(object).to_string()
+            }
+        }
+    }
+}
+
+/// The access log: one line per finished request or tunnel, written by a
+/// dedicated thread so slow output never holds up requests.
+///
+/// Access logs are kept apart from the diagnostic log, which goes to stderr.
+pub struct AccessLog {
+    format: Format,
+    lines: SyncSender<String>,
+    /// The output and the queue it is written from, until the writer thread
+    /// takes them
+    writer: Mutex<Option<(Sink, Receiver<String>)>>,
+}
+
+impl AccessLog {
+    pub fn new(config: &AccessLogConfig) -> Result<Self> {
+        let format = match config.format {
+            AccessLogFormat::Common => {
+                Format::Template(Template::parse(COMMON_TEMPLATE, VARIABLES)?)
+            }
+            AccessLogFormat::Combined => {
+                Format::Template(Template::parse(COMBINED_TEMPLATE, VARIABLES)?)
+            }
+            AccessLogFormat::Json => Format::Json,
+            AccessLogFormat::Custom => {
+                let template = config.template.as_deref().or_err(
+                    CONFIG_ERROR,
+                    "the custom access log format needs a template",
+                )?;
+                let template = Template::parse(template, VARIABLES)
+                    .map_err(|e| e.more_context("access log template"))?;
+                Format::Template(template)
+            }
+        };
+        let sink = Sink::open(config)?;
+        let (lines, receiver) = mpsc::sync_channel(ACCESS_LOG_QUEUE);
+        Ok(AccessLog {
+            format,
+            lines,
+            writer: Mutex::new(Some((sink, receiver))),
+        })
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        match self.lines.try_send(self.format.render(record)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Access log queue full, dropping a record"),
+            Err(TrySendError::Disconnected(_)) => error!("Access log writer is gone"),
+        }
+    }
+}
+
+/// Starts the writer thread once the server runs, which in daemon mode is
+/// after it forked: threads started before are not in the forked process.
+#[async_trait]
+impl BackgroundService for AccessLog {
+    async fn start(&self, _shutdown: ShutdownWatch) {
+        let Some((sink, lines)) = self.writer.lock().unwrap().take() else {
+            return;
+        };
+        let started = std::thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || sink.run(lines));
+        if let Err(e) = started {
+            error!("Failed to start the access log writer: {e}");
+        }
+    }
+}
+
+/// A file that is rotated to `path.1`, `path.2`, ... once it reaches
+/// `max_size`
+struct RotatingFile {
+    path: String,
+    /// 0 disables rotation
+    max_size: u64,
+    max_files: usize,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &str, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            max_size,
+            max_files: max_files.max(1),
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.max_size > 0 && self.size > 0 && self.size + line.len() as u64 >= self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{i}", self.path);
+            if std::path::Path::new(&from).exists() {
+                std::fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, format!("{}.1", self.path))?;
+        *self = RotatingFile::open(&self.path, self.max_size, self.max_files)?;
+        Ok(())
+    }
+}
+
+enum Syslog {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+}
+
+enum Sink {
+    Stdout(io::Stdout),
+    File(RotatingFile),
+    Syslog(Syslog),
+}
+
+impl Sink {
+    fn open(config: &AccessLogConfig) -> Result<Self> {
+        match config.output {
+            AccessLogOutput::Stdout => Ok(Sink::Stdout(io::stdout())),
+            AccessLogOutput::File => {
+                let path = config.path.as_deref().or_err(
+                    CONFIG_ERROR,
+                    "the file access log output needs a path",
+                )?;
+                let file =
+                    RotatingFile::open(path, config.max_size_mb * 1024 * 1024, config.max_files)
+                        .or_err_with(FileOpenError, || format!("opening access log {path}"))?;
+                Ok(Sink::File(file))
+            }
+            AccessLogOutput::Syslog => {
+                let address = config.syslog_address.as_str();
+                let syslog = if address.starts_with('/') {
+                    let socket = UnixDatagram::unbound()
+                        .and_then(|socket| socket.connect(address).map(|_| socket));
+                    socket.map(Syslog::Unix)
+                } else {
+                    let socket = UdpSocket::bind(if address.starts_with('[') {
+                        "[::]:0"
+                    } else {
+                        "0.0.0.0:0"
+                    });
+                    socket
+                        .and_then(|socket| socket.connect(address).map(|_| socket))
+                        .map(Syslog::Udp)
+                };
+                let syslog = syslog
+                    .or_err_with(ConnectError, || format!("connecting to syslog at {address}"))?;
+                Ok(Sink::Syslog(syslog))
+            }
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout(stdout) => writeln!(stdout.lock(), "{line}"),
+            Sink::File(file) => file.write_line(line),
+            Sink::Syslog(syslog) => {
+                let timestamp = Local::now().format("%b %e %H:%M:%S");
+                let message = format!(
+                    "<{SYSLOG_PRIORITY}>{timestamp} pinproxy[{}]: {line}",
+                    std::process::id()
+                );
+                match syslog {
+                    Syslog::Unix(socket) => socket.send(message.as_bytes()).map(|_| ()),
+                    Syslog::Udp(socket) => socket.send(message.as_bytes()).map(|_| ()),
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(stdout) => stdout.flush(),
+            Sink::File(file) => file.file.flush(),
+            Sink::Syslog(_) => Ok(()),
+        }
+    }
+
+    /// Writes lines until every sender is gone, flushing whenever the queue
+    /// runs empty.
+    fn run(mut self, lines: Receiver<String>) {
+        while let Ok(line) = lines.recv() {
+            let mut result = self.write_line(&line);
+            while result.is_ok() {
+                match lines.try_recv() {
+                    Ok(line) => result = self.write_line(&line),
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = result.and_then(|_| self.flush()) {
+                error!("Failed to write access log: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("pinproxy-{}-{name}", std::process::id()));
+        path.to_string_lossy().into_owned()
+    }
+
+    fn record(req: &RequestHeader, status: u16) -> AccessRecord<'_> {
+        AccessRecord {
+            client_addr: None,
+            user: Some("alice"),
+            request_id: None,
+            start: SystemTime::now(),
+            duration: Duration::from_millis(5),
+            req,
+            status,
+            bytes_received: 0,
+            bytes_sent: 12,
+            route: Some("api"),
+            upstream: None,
+            upstream_addr: None,
+            upstream_uri: None,
+            cache_status: None,
+            error_code: None,
+            error_cause: None,
+        }
+    }
+
+    /// Writes the queued lines and returns the file's lines.
+    fn write_out(access_log: AccessLog, path: &str) -> Vec<String> {
+        let (sink, lines) = access_log.writer.lock().unwrap().take().unwrap();
+        // The writer stops once the log is gone and the queue is empty
+        drop(access_log);
+        sink.run(lines);
+        let written = std::fs::read_to_string(path).unwrap();
+        written.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_file_sink() {
+        let path = temp_path("access.log");
+        std::fs::remove_file(&path).ok();
+        let config = AccessLogConfig {
+            format: AccessLogFormat::Custom,
+            template: Some("$request_method $request_uri $status $remote_user $upstream".into()),
+            output: AccessLogOutput::File,
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let access_log = AccessLog::new(&config).unwrap();
+        let get = RequestHeader::build("GET", b"/a?b=c", None).unwrap();
+        let post = RequestHeader::build("POST", b"/\"quoted\"", None).unwrap();
+        access_log.log(&record(&get, 200));
+        access_log.log(&record(&post, 502));
+
+        let lines = write_out(access_log, &path);
+        assert_eq!(
+            lines,
+            ["GET /a?b=c 200 alice -", "POST /\\\"quoted\\\" 502 alice -"]
+        );
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_file_sink_json() {
+        let path = temp_path("access.json");
+        std::fs::remove_file(&path).ok();
+        let config = AccessLogConfig {
+            format: AccessLogFormat::Json,
+            output: AccessLogOutput::File,
+            path: Some(path.clone()),
+            ..Default::default()
+        };
+        let access_log = AccessLog::new(&config).unwrap();
+        let req = RequestHeader::build("GET", b"/", None).unwrap();
+        access_log.log(&record(&req, 404));
+
+        let lines = write_out(access_log, &path);
+        assert_eq!(lines.len(), 1);
+        let line: Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(line["status"], 404);
+        assert_eq!(line["body_bytes_sent"], 12);
+        assert_eq!(line["route"], "api");
+        assert_eq!(line["upstream"], Value::Null);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path = temp_path("rotated.log");
+        for suffix in ["", ".1", ".2"] {
+            std::fs::remove_file(format!("{path}{suffix}")).ok();
+        }
+        let mut file = RotatingFile::open(&path, 20, 1).unwrap();
+        for line in ["first line", "second line", "third line"] {
+            file.write_line(line).unwrap();
+        }
+        file.file.flush().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "third line\n");
+        assert_eq!(std::fs::read_to_string(format!("{path}.1")).unwrap(), "second line\n");
+        // Only one rotated file is kept
+        assert!(!std::path::Path::new(&format!("{path}.2")).exists());
+        for suffix in ["", ".1"] {
+            std::fs::remove_file(format!("{path}{suffix}")).ok();
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+use pingora::prelude::*;
+
+use crate::authority::Host;
+use crate::config::{AclAction, AclConfig, AclRuleConfig, CONFIG_ERROR};
+use crate::routing::HostMatch;
+
+/// Destinations that forwarding must not reach unless explicitly allowed:
+/// private, loopback, link-local (including cloud metadata endpoints such as
+/// 169.254.169.254), shared, unspecified, multicast and reserved addresses,
+/// the latter including the broadcast address. NAT64 prefixes are blocked
+/// whole, a gateway would forward them to any IPv4 address, private or not.
+const PRIVATE_RANGES: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "64:ff9b::/96",
+    "64:ff9b:1::/48",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// What a request is checked against
+pub struct AclRequest<'a> {
+    pub client: Option<IpAddr>,
+    pub host: &'a Host,
+    pub port: u16,
+    /// The addresses the destination resolved to, `None` for configured
+    /// upstreams, which are trusted
+    pub resolved: Option<&'a [IpAddr]>,
+}
+
+struct Rule {
+    name: String,
+    action: AclAction,
+    clients: Vec<IpNet>,
+    hosts: Vec<HostMatch>,
+    ports: Vec<u16>,
+    destinations: Vec<IpNet>,
+}
+
+impl Rule {
+    fn new(index: usize, config: &AclRuleConfig) -> Result<Self> {
+        let name = config
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{index}"));
+        let context = |e: Box<Error>| e.more_context(format!("acl rule {name}"));
+        Ok(Rule {
+            action: config.action,
+            clients: parse_nets(&config.clients).map_err(context)?,
+            hosts: config
+                .hosts
+                .iter()
+                .map(|pattern| HostMatch::new(pattern))
+                .collect::<Result<_>>()
+                .map_err(context)?,
+            ports: config.ports.clone(),
+            destinations: parse_nets(&config.destinations).map_err(context)?,
+            name,
+        })
+    }
+
+    fn matches(&self, req: &AclRequest) -> bool {
+        // Every resolved address must be in range, or one outside could be used
+        let destination = self.destinations.is_empty()
+            || req.resolved.is_some_and(|ips| {
+                !ips.is_empty()
+                    && ips
+                        .iter()
+                        .all(|ip| self.destinations.iter().any(|net| net.contains(ip)))
+            });
+        self.matches_unresolved(req) && destination
+    }
+
+    /// Whether the request matches the criteria other than `destinations`.
+    fn matches_unresolved(&self, req: &AclRequest) -> bool {
+        let client = self.clients.is_empty()
+            || req
+                .client
+                .is_some_and(|ip| self.clients.iter().any(|net| net.contains(&ip)));
+        let host = self.hosts.is_empty() || self.hosts.iter().any(|h| h.matches(req.host));
+        let port = self.ports.is_empty() || self.ports.contains(&req.port);
+        client && host && port
+    }
+}
+
+pub fn parse_nets(nets: &[String]) -> Result<Vec<IpNet>> {
+    nets.iter()
+        .map(|net| {
+            // A bare address is a single-host network
+            net.parse::<IpNet>()
+                .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                .or_err_with(CONFIG_ERROR, || format!("invalid network {net:?}"))
+        })
+        .collect()
+}
+
+/// Allow and deny rules for clients and destinations.
+///
+/// Rules are tried in order and the first one matching decides, requests no
+/// rule matches get the default action. Resolved destinations in private
+/// ranges are denied on top of that, unless `block_private` is off or the
+/// deciding rule allowed them by listing `destinations`.
+pub struct Acl {
+    default: AclAction,
+    block_private: bool,
+    private: Vec<IpNet>,
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn new(config: &AclConfig) -> Result<Self> {
+        Ok(Acl {
+            default: config.default,
+            block_private: config.block_private,
+            private: PRIVATE_RANGES.iter().map(|net| net.parse().unwrap()).collect(),
+            rules: config
+                .rules
+                .iter()
+                .enumerate()
+                .map(|(i, rule)| Rule::new(i, rule))
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Checks a request before its destination is resolved, denying it if
+    /// every outcome of the resolution would, so denied clients neither make
+    /// the proxy look names up nor verify their credentials.
+    ///
+    /// Requests this lets through still need [`Acl::check`].
+    pub fn check_unresolved(&self, req: &AclRequest) -> std::result::Result<(), String> {
+        for rule in self.rules.iter().filter(|rule| rule.matches_unresolved(req)) {
+            match (rule.action, rule.destinations.is_empty()) {
+                (AclAction::Deny, true) => return Err(format!("denied by rule {}", rule.name)),
+                (AclAction::Allow, true) => return Ok(()),
+                // Decided by the resolved addresses, and allowed if they match
+                (AclAction::Allow, false) => return Ok(()),
+                // Denied if the resolved addresses match, decided later if not
+                (AclAction::Deny, false) => {}
+            }
+        }
+        match self.default {
+            AclAction::Deny => Err("no rule allows it".into()),
+            AclAction::Allow => Ok(()),
+        }
+    }
+
+    /// Checks a request, returning why it is denied if it is.
+    pub fn check(&self, req: &AclRequest) -> std::result::Result<(), String> {
+        let rule = self.rules.iter().find(|rule| rule.matches(req));
+        match rule {
+            Some(rule) if rule.action == AclAction::Deny => {
+                return Err(format!("denied by rule {}", rule.name));
+            }
+            Some(rule) if !rule.destinations.is_empty() => return Ok(()),
+            Some(_) => {}
+            None if self.default == AclAction::Deny => return Err("no rule allows it".into()),
+            None => {}
+        }
+
+        if self.block_private {
+            let private = req.resolved.unwrap_or_default().iter().find(|ip| {
+                let ip = ip.to_canonical();
+                self.private.iter().any(|net| net.contains(&ip))
+            });
+            if let Some(ip) = private {
+                return Err(format!("destination resolves to private address {ip}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: AclAction, clients: &[&str], destinations: &[&str]) -> AclRuleConfig {
+        AclRuleConfig {
+            action,
+            clients: clients.iter().map(|net| net.to_string()).collect(),
+            destinations: destinations.iter().map(|net| net.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn new_acl(default: AclAction, block_private: bool, rules: Vec<AclRuleConfig>) -> Acl {
+        Acl::new(&AclConfig {
+            default,
+            block_private,
+            rules,
+        })
+        .unwrap()
+    }
+
+    /// Checks a forwarded request from `client` to `host` resolving to `ips`.
+    fn check(acl: &Acl, client: &str, host: &str, port: u16, ips: &[&str]) -> bool {
+        let host = crate::authority::Authority::parse(host).unwrap().host;
+        let ips: Vec<IpAddr> = ips.iter().map(|ip| ip.parse().unwrap()).collect();
+        let req = AclRequest {
+            client: Some(client.parse().unwrap()),
+            host: &host,
+            port,
+            resolved: Some(&ips),
+        };
+        acl.check(&req).is_ok()
+    }
+
+    #[test]
+    fn test_parse_nets() {
+        let nets = parse_nets(&[
+            "10.0.0.0/8".to_string(),
+            "192.168.1.7".to_string(),
+            "2001:db8::/32".to_string(),
+            "::1".to_string(),
+        ])
+        .unwrap();
+        let expected = ["10.0.0.0/8", "192.168.1.7/32", "2001:db8::/32", "::1/128"];
+        let expected: Vec<IpNet> = expected.iter().map(|net| net.parse().unwrap()).collect();
+        assert_eq!(nets, expected);
+
+        for invalid in ["10.0.0.0/33", "10.0.0", "example.com", "::1/129", ""] {
+            let result = parse_nets(&[invalid.to_string()]);
+            assert!(result.is_err(), "{invalid:?} should be rejected");
+            assert_eq!(result.unwrap_err().etype(), &CONFIG_ERROR);
+        }
+    }
+
+    #[test]
+    fn test_private_ranges() {
+        let acl = new_acl(AclAction::Allow, true, vec![]);
+        for (ip, allowed) in [
+            ("93.184.216.34", true),
+            ("2606:2800:220:1::1", true),
+            ("0.0.0.0", false),
+            ("10.1.2.3", false),
+            ("100.64.0.1", false),
+            ("100.127.255.254", false),
+            ("100.128.0.1", true),
+            ("127.0.0.1", false),
+            ("169.254.169.254", false),
+            ("172.16.0.1", false),
+            ("172.32.0.1", true),
+            ("192.168.0.1", false),
+            ("224.0.0.251", false),
+            ("239.255.255.250", false),
+            ("240.0.0.1", false),
+            ("255.255.255.255", false),
+            ("::", false),
+            ("::1", false),
+            ("fd00::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+            // IPv4-mapped addresses are checked as IPv4
+            ("::ffff:127.0.0.1", false),
+            ("::ffff:93.184.216.34", true),
+            // NAT64, which reaches IPv4 through a gateway
+            ("64:ff9b::a00:1", false),
+            ("64:ff9b::7f00:1", false),
+            ("64:ff9b::a9fe:a9fe", false),
+            ("64:ff9b::5db8:d822", false),
+            ("64:ff9b:1::a00:1", false),
+            ("64:ff9b:2::1", true),
+        ] {
+            assert_eq!(check(&acl, "192.0.2.1", "example.com", 80, &[ip]), allowed, "{ip}");
+        }
+        // One private address among public ones is enough
+        assert!(!check(&acl, "192.0.2.1", "example.com", 80, &["93.184.216.34", "10.0.0.1"]));
+
+        let acl = new_acl(AclAction::Allow, false, vec![]);
+        assert!(check(&acl, "192.0.2.1", "example.com", 80, &["10.1.2.3"]));
+    }
+
+    #[test]
+    fn test_rules() {
+        let internal = AclRuleConfig {
+            hosts: vec!["*.internal.test".to_string()],
+            ports: vec![443],
+            ..rule(AclAction::Allow, &["10.0.0.0/8"], &["10.0.0.0/8"])
+        };
+        let acl = new_acl(
+            AclAction::Deny,
+            true,
+            vec![
+                rule(AclAction::Deny, &["10.9.0.0/16"], &[]),
+                internal,
+                rule(AclAction::Allow, &["10.0.0.0/8", "2001:db8::/32"], &[]),
+            ],
+        );
+        for (client, host, port, ips, allowed) in [
+            // The first matching rule decides
+            ("10.9.0.1", "example.com", 80, &["93.184.216.34"][..], false),
+            ("10.1.0.1", "example.com", 80, &["93.184.216.34"], true),
+            ("2001:db8::5", "example.com", 80, &["93.184.216.34"], true),
+            // No rule matches, the default denies
+            ("192.0.2.1", "example.com", 80, &["93.184.216.34"], false),
+            // Listed destinations lift the private range block
+            ("10.1.0.1", "db.internal.test", 443, &["10.2.0.1"], true),
+            // but every resolved address must be listed
+            ("10.1.0.1", "db.internal.test", 443, &["10.2.0.1", "192.168.0.1"], false),
+            // and all criteria must match
+            ("10.1.0.1", "db.internal.test", 80, &["10.2.0.1"], false),
+            ("10.1.0.1", "db.other.test", 443, &["10.2.0.1"], false),
+            // Other allow rules keep the private range block
+            ("10.1.0.1", "example.com", 80, &["10.2.0.1"], false),
+        ] {
+            assert_eq!(
+                check(&acl, client, host, port, ips),
+                allowed,
+                "{client} -> {host}:{port} {ips:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_unresolved() {
+        let acl = new_acl(
+            AclAction::Deny,
+            true,
+            vec![
+                rule(AclAction::Deny, &["10.9.0.0/16"], &[]),
+                rule(AclAction::Deny, &["10.8.0.0/16"], &["10.0.0.0/8"]),
+                rule(AclAction::Allow, &["10.7.0.0/16"], &["10.0.0.0/8"]),
+                rule(AclAction::Allow, &["10.0.0.0/8"], &[]),
+            ],
+        );
+        let host = Host::Domain("example.com".to_string());
+        for (client, allowed) in [
+            ("10.9.0.1", false),
+            // Denied only for some destinations, so not yet
+            ("10.8.0.1", true),
+            ("10.7.0.1", true),
+            ("10.1.0.1", true),
+            ("192.0.2.1", false),
+        ] {
+            let req = AclRequest {
+                client: Some(client.parse().unwrap()),
+                host: &host,
+                port: 80,
+                resolved: None,
+            };
+            assert_eq!(acl.check_unresolved(&req).is_ok(), allowed, "{client}");
+        }
+    }
+}
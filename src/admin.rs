@@ -0,0 +1,416 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use http::header::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, WWW_AUTHENTICATE};
+use http::{Method, Response, StatusCode};
+use log::{info, warn};
+use pingora::apps::http_app::ServeHttp;
+use pingora::cache::key::CacheHashKey;
+use pingora::cache::CacheMeta;
+use pingora::prelude::*;
+use pingora::protocols::http::ServerSession;
+use serde_json::{json, Map, Value};
+
+use crate::cache::{self, Cache};
+use crate::cache_storage::IndexEntry;
+use crate::config::{AdminConfig, CONFIG_ERROR};
+use crate::metrics;
+
+/// The admin API, served on a listener of its own:
+///
+/// - `GET /cache/stats`: hit ratio and storage usage
+/// - `GET /cache/entries`: the metadata of stored responses
+/// - `POST` or `DELETE /cache/purge`: removes stored responses
+///
+/// Entries and purges select responses with one of the query parameters
+/// `url` (the exact URL a response was stored for, such as
+/// `http://example.com/index.html`), `prefix` (URLs starting with it) or
+/// `tag` (a `Surrogate-Key` or `Cache-Tag` value of the response).
+pub struct AdminService {
+    token: String,
+    cache: Option<Arc<Cache>>,
+}
+
+impl AdminService {
+    pub fn new(config: &AdminConfig, cache: Option<Arc<Cache>>) -> Result<Self> {
+        if config.token.is_empty() {
+            return Error::e_explain(CONFIG_ERROR, "admin token must not be empty");
+        }
+        Ok(AdminService {
+            token: config.token.clone(),
+            cache,
+        })
+    }
+
+    fn authorized(&self, req: &RequestHeader) -> bool {
+        let presented = req
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        // Compared in constant time, so the token cannot be guessed byte by
+        // byte from response times
+        presented.is_some_and(|presented| {
+            presented.len() == self.token.len()
+                && presented
+                    .bytes()
+                    .zip(self.token.bytes())
+                    .fold(0, |diff, (a, b)| diff | (a ^ b))
+                    == 0
+        })
+    }
+
+    async fn handle(&self, req: &RequestHeader) -> Result<Value> {
+        let cache = self
+            .cache
+            .as_deref()
+            .or_err(HTTPStatus(404), "no cache is configured")?;
+        match (&req.method, req.uri.path()) {
+            (&Method::GET, "/cache/stats") => Ok(stats(cache)),
+            (&Method::GET, "/cache/entries") => {
+                let selector = Selector::from_query(req)?;
+                let entries = cache.entries(|entry| selector.matches(entry)).await?;
+                Ok(entries
+                    .iter()
+                    .map(|(entry, meta)| entry_json(entry, meta))
+                    .collect())
+            }
+            (&Method::POST | &Method::DELETE, "/cache/purge") => {
+                let selector = Selector::from_query(req)?;
+                let purged = cache.purge(|entry| selector.matches(entry)).await?;
+                info!(target: "audit", "Purged {purged} cached responses by {selector:?}");
+                Ok(json!({ "purged": purged }))
+            }
+            (_, "/cache/stats" | "/cache/entries" | "/cache/purge") => {
+                Error::e_explain(HTTPStatus(405), format!("{} is not allowed", req.method))
+            }
+            (_, path) => Error::e_explain(HTTPStatus(404), format!("no such endpoint {path}")),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminService {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req = session.req_header();
+        let (status, body) = if !self.authorized(req) {
+            warn!(target: "audit", "Rejected admin request {} {}", req.method, req.uri);
+            let error = json!({ "error": "missing or wrong bearer token" });
+            (StatusCode::UNAUTHORIZED, error)
+        } else {
+            match self.handle(req).await {
+                Ok(body) => (StatusCode::OK, body),
+                Err(e) => {
+                    let status = match e.etype() {
+                        HTTPStatus(code) => StatusCode::from_u16(*code).ok(),
+                        _ => None,
+                    };
+                    let message = e.context.as_ref().map_or(e.etype().as_str(), |c| c.as_str());
+                    let error = json!({ "error": message });
+                    (status.unwrap_or(StatusCode::INTERNAL_SERVER_ERROR), error)
+                }
+            }
+        };
+        let body = format!("{body}\n").into_bytes();
+        let mut resp = Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, body.len());
+        if status == StatusCode::UNAUTHORIZED {
+            resp = resp.header(WWW_AUTHENTICATE, "Bearer");
+        }
+        resp.body(body).unwrap()
+    }
+}
+
+/// Which stored responses an entries or purge request is about
+#[derive(Debug)]
+enum Selector {
+    Url(String),
+    Prefix(String),
+    Tag(String),
+}
+
+impl Selector {
+    fn from_query(req: &RequestHeader) -> Result<Self> {
+        let params = (param(req, "url"), param(req, "prefix"), param(req, "tag"));
+        let selector = match params {
+            (Some(url), None, None) => Selector::Url(url),
+            (None, Some(prefix), None) => Selector::Prefix(prefix),
+            (None, None, Some(tag)) => Selector::Tag(tag),
+            _ => return Error::e_explain(HTTPStatus(400), "needs one of url, prefix or tag"),
+        };
+        // An empty prefix would select, and purge, the whole cache
+        let (Selector::Url(value) | Selector::Prefix(value) | Selector::Tag(value)) = &selector;
+        if value.is_empty() {
+            return Error::e_explain(HTTPStatus(400), "url, prefix or tag must not be empty");
+        }
+        Ok(selector)
+    }
+
+    fn matches(&self, entry: &IndexEntry) -> bool {
+        match self {
+            Selector::Url(url) => entry.url == *url,
+            Selector::Prefix(prefix) => entry.url.starts_with(prefix.as_str()),
+            Selector::Tag(tag) => entry.tags.contains(tag),
+        }
+    }
+}
+
+fn stats(cache: &Cache) -> Value {
+    let usage = cache.usage();
+    let count = |statuses: &[&str]| -> u64 {
+        statuses.iter().map(|status| metrics::cache_requests(status)).sum()
+    };
+    let requests: Map<String, Value> = cache::STATUSES
+        .iter()
+        .map(|status| (status.to_string(), metrics::cache_requests(status).into()))
+        .collect();
+    // Bypassed requests never had a chance to be served from the cache
+    let looked_up = count(cache::STATUSES) - count(&["BYPASS"]);
+    let hit_ratio = match looked_up {
+        0 => Value::Null,
+        _ => (count(cache::SERVED_FROM_CACHE) as f64 / looked_up as f64).into(),
+    };
+    json!({
+        "hit_ratio": hit_ratio,
+        "requests": requests,
+        "items": usage.items,
+        "size_bytes": usage.size,
+        "max_size_bytes": usage.max_size,
+        "evicted_items": usage.evicted_items,
+        "evicted_bytes": usage.evicted_size,
+    })
+}
+
+fn entry_json(entry: &IndexEntry, meta: &CacheMeta) -> Value {
+    let mut headers = Map::new();
+    for (name, value) in meta.headers() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        match headers.get_mut(name.as_str()) {
+            Some(Value::String(values)) => *values = format!("{values}, {value}"),
+            _ => {
+                headers.insert(name.to_string(), value.into());
+            }
+        }
+    }
+    json!({
+        "url": entry.url,
+        "key": entry.key.combined(),
+        "variance": entry.key.variance(),
+        "tags": entry.tags,
+        "size_bytes": entry.size,
+        "status": meta.response_header().status.as_u16(),
+        "created": time(meta.created()),
+        "updated": time(meta.updated()),
+        "fresh_until": time(meta.fresh_until()),
+        "fresh": meta.is_fresh(SystemTime::now()),
+        "age_secs": meta.age().as_secs(),
+        "stale_while_revalidate_secs": meta.stale_while_revalidate_sec(),
+        "stale_if_error_secs": meta.stale_if_error_sec(),
+        "headers": headers,
+    })
+}
+
+fn time(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The percent-decoded value of a query string parameter.
+fn param(req: &RequestHeader, name: &str) -> Option<String> {
+    req.uri.query()?.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (decode(key) == name).then(|| decode(value))
+    })
+}
+
+fn decode(text: &str) -> String {
+    let mut decoded = vec![];
+    let mut rest = text.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                rest = &tail[2..];
+            }
+            (b'+', _) => decoded.push(b' '),
+            (b, _) => decoded.push(b),
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use pingora::cache::trace::Span;
+    use pingora::cache::Storage;
+    use pingora::http::ResponseHeader;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::cache_storage::DiskStorage;
+    use crate::config::{CacheConfig, CacheStorage};
+
+    const TOKEN: &str = "s3cret";
+
+    /// An admin API over a disk cache holding a response per `(url, tags)`
+    async fn service(name: &str, responses: &[(&str, &str)]) -> AdminService {
+        let dir = std::env::temp_dir().join(format!("pinproxy-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let path = dir.to_string_lossy().into_owned();
+        let storage: &'static _ = Box::leak(Box::new(DiskStorage::new(&path).unwrap()));
+        let span = Span::inactive().handle();
+        for (url, tags) in responses {
+            let mut header = ResponseHeader::build(200, None).unwrap();
+            header.insert_header("surrogate-key", *tags).unwrap();
+            let now = SystemTime::now();
+            let meta = CacheMeta::new(now + std::time::Duration::from_secs(60), now, 0, 0, header);
+            let mut miss = storage
+                .get_miss_handler(&cache::key(url), &meta, &span)
+                .await
+                .unwrap();
+            miss.write_body(Bytes::from_static(b"body"), true).await.unwrap();
+            miss.finish().await.unwrap();
+        }
+        // The cache finds the responses when it loads the directory
+        let cache = Cache::new(&CacheConfig {
+            storage: CacheStorage::Disk,
+            path: Some(path),
+            ..Default::default()
+        })
+        .unwrap();
+        let config = AdminConfig {
+            address: "127.0.0.1:0".to_string(),
+            token: TOKEN.to_string(),
+        };
+        AdminService::new(&config, Some(Arc::new(cache))).unwrap()
+    }
+
+    async fn call(
+        service: &AdminService,
+        method: &str,
+        uri: &str,
+        authorization: Option<&str>,
+    ) -> Response<Vec<u8>> {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = ServerSession::new_http1(Box::new(server));
+        let mut request = format!("{method} {uri} HTTP/1.1\r\nHost: admin\r\n");
+        if let Some(authorization) = authorization {
+            request.push_str(&format!("Authorization: {authorization}\r\n"));
+        }
+        request.push_str("\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        assert!(session.read_request().await.unwrap());
+        service.response(&mut session).await
+    }
+
+    /// Calls the API with the right token, returning the status and body.
+    async fn request(service: &AdminService, method: &str, uri: &str) -> (u16, Value) {
+        let bearer = format!("Bearer {TOKEN}");
+        let resp = call(service, method, uri, Some(&bearer)).await;
+        let body = serde_json::from_slice(resp.body()).unwrap();
+        (resp.status().as_u16(), body)
+    }
+
+    /// The URLs of the responses `query` selects
+    async fn urls(service: &AdminService, query: &str) -> Vec<String> {
+        let (status, body) = request(service, "GET", &format!("/cache/entries?{query}")).await;
+        assert_eq!(status, 200, "{body}");
+        let mut urls: Vec<String> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["url"].as_str().unwrap().to_string())
+            .collect();
+        urls.sort();
+        urls
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let service = service("admin-auth", &[]).await;
+        for authorization in [
+            None,
+            Some("Bearer wrong!"),
+            Some("Bearer s3cre"),
+            Some("Bearer s3crett"),
+            Some("bearer s3cret"),
+            Some("Basic czNjcmV0"),
+        ] {
+            let resp = call(&service, "GET", "/cache/stats", authorization).await;
+            assert_eq!(resp.status(), StatusCode::UNAUTHORIZED, "{authorization:?}");
+            assert_eq!(resp.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+        // Rejected before the request is looked at
+        let resp = call(&service, "POST", "/cache/purge?prefix=/", None).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let (status, body) = request(&service, "GET", "/cache/stats").await;
+        assert_eq!(status, 200);
+        assert_eq!(body["items"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_selectors() {
+        let responses = [
+            ("http://a.test/one", "red"),
+            ("http://a.test/two", "red blue"),
+            ("http://b.test/one", "blue"),
+            ("http://b.test/two a", "green"),
+        ];
+        let service = service("admin-selectors", &responses).await;
+        assert_eq!(urls(&service, "url=http://a.test/one").await, ["http://a.test/one"]);
+        assert_eq!(urls(&service, "url=http://b.test/two%20a").await, ["http://b.test/two a"]);
+        assert!(urls(&service, "url=http://a.test/").await.is_empty());
+        assert_eq!(
+            urls(&service, "prefix=http%3A%2F%2Fa.test%2F").await,
+            ["http://a.test/one", "http://a.test/two"]
+        );
+        assert_eq!(urls(&service, "tag=blue").await, ["http://a.test/two", "http://b.test/one"]);
+
+        let purge = |query: &str| format!("/cache/purge?{query}");
+        let (status, body) = request(&service, "POST", &purge("tag=red")).await;
+        assert_eq!((status, body), (200, json!({ "purged": 2 })));
+        assert_eq!(
+            urls(&service, "prefix=http").await,
+            ["http://b.test/one", "http://b.test/two a"]
+        );
+        let (status, body) = request(&service, "DELETE", &purge("url=http://b.test/one")).await;
+        assert_eq!((status, body), (200, json!({ "purged": 1 })));
+        let (status, body) = request(&service, "POST", &purge("prefix=http://a.test/")).await;
+        assert_eq!((status, body), (200, json!({ "purged": 0 })));
+        let (status, body) = request(&service, "POST", &purge("prefix=http://b.test/")).await;
+        assert_eq!((status, body), (200, json!({ "purged": 1 })));
+        assert!(urls(&service, "prefix=http").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let service = service("admin-invalid", &[("http://a.test/", "red")]).await;
+        for query in ["", "?prefix=", "?prefix", "?url=", "?tag=", "?url=x&tag=red", "?other=1"] {
+            for method in ["POST", "DELETE"] {
+                let uri = format!("/cache/purge{query}");
+                let (status, body) = request(&service, method, &uri).await;
+                assert_eq!(status, 400, "{method} {query}: {body}");
+            }
+            let (status, _) = request(&service, "GET", &format!("/cache/entries{query}")).await;
+            assert_eq!(status, 400, "{query}");
+        }
+        assert_eq!(urls(&service, "prefix=http").await, ["http://a.test/"]);
+
+        let (status, _) = request(&service, "GET", "/cache/purge?tag=red").await;
+        assert_eq!(status, 405);
+        let (status, _) = request(&service, "POST", "/cache/stats").await;
+        assert_eq!(status, 405);
+        let (status, _) = request(&service, "GET", "/cache/other").await;
+        assert_eq!(status, 404);
+    }
+}
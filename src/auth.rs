@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use argon2::password_hash::PasswordHash;
+use argon2::{Argon2, PasswordVerifier};
+use base64::Engine;
+use http::header::{PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use log::warn;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+
+use crate::config::{AuthConfig, CONFIG_ERROR};
+
+/// Verified credentials remembered, so the slow hash only runs once per user
+const VERIFIED_CACHE_SIZE: usize = 1024;
+
+/// A password hash from the htpasswd file
+#[derive(Clone)]
+enum PasswordHashKind {
+    Bcrypt(String),
+    /// A PHC string, `$argon2id$v=19$...`
+    Argon2(String),
+}
+
+impl PasswordHashKind {
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHashKind::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            PasswordHashKind::Argon2(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+/// `Proxy-Authorization: Basic` authentication against an htpasswd file.
+///
+/// Only bcrypt (`$2y$`, `htpasswd -B`) and argon2 hashes are accepted, the
+/// older htpasswd schemes are too weak to offer.
+pub struct ProxyAuth {
+    realm: String,
+    users: Arc<HashMap<String, PasswordHashKind>>,
+    /// Verified for unknown users, so they take as long to reject as known
+    /// ones and user names cannot be told apart by timing
+    dummy: Option<PasswordHashKind>,
+    /// `Proxy-Authorization` values that were verified, and their user
+    verified: Mutex<HashMap<String, String>>,
+}
+
+impl ProxyAuth {
+    pub fn new(config: &AuthConfig) -> Result<Self> {
+        let path = &config.htpasswd;
+        let content = std::fs::read_to_string(path)
+            .or_err_with(FileReadError, || format!("reading htpasswd file {path}"))?;
+        let mut users = HashMap::new();
+        let mut dummy = None;
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line.split_once(':').or_err_with(CONFIG_ERROR, || {
+                format!("malformed line in htpasswd file {path}")
+            })?;
+            let hash = if ["$2a$", "$2b$", "$2x$", "$2y$"]
+                .iter()
+                .any(|prefix| hash.starts_with(prefix))
+            {
+                PasswordHashKind::Bcrypt(hash.to_string())
+            } else if hash.starts_with("$argon2") && PasswordHash::new(hash).is_ok() {
+                PasswordHashKind::Argon2(hash.to_string())
+            } else {
+                return Error::e_explain(
+                    CONFIG_ERROR,
+                    format!("user {user} in {path} needs a bcrypt or argon2 hash"),
+                );
+            };
+            // Any hash of the file costs what verifying the others does
+            dummy.get_or_insert_with(|| hash.clone());
+            users.insert(user.to_string(), hash);
+        }
+        Ok(ProxyAuth {
+            realm: config.realm.clone(),
+            users: Arc::new(users),
+            dummy,
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Authenticates the request, returning the user name.
+    ///
+    /// `None` means the request has no valid credentials and has to be
+    /// challenged.
+    pub async fn authenticate(&self, req: &RequestHeader, request_id: &str) -> Option<String> {
+        let credentials = req.headers.get(PROXY_AUTHORIZATION)?.to_str().ok()?;
+        if let Some(user) = self.verified.lock().unwrap().get(credentials) {
+            return Some(user.clone());
+        }
+
+        let (scheme, encoded) = credentials.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        let (user, password) = (user.to_string(), password.to_string());
+
+        // Hashes are deliberately slow, keep them off the proxy threads
+        let users = self.users.clone();
+        let dummy = self.dummy.clone();
+        let name = user.clone();
+        let valid = tokio::task::spawn_blocking(move || match users.get(&name) {
+            Some(hash) => hash.verify(&password),
+            None => {
+                // Whatever the password, an unknown user is rejected
+                if let Some(hash) = dummy {
+                    hash.verify(&password);
+                }
+                false
+            }
+        })
+        .await
+        .unwrap_or(false);
+        if !valid {
+            warn!(target: "audit", "[{request_id}] Failed proxy authentication for user {user:?}");
+            return None;
+        }
+
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= VERIFIED_CACHE_SIZE {
+            verified.clear();
+        }
+        verified.insert(credentials.to_string(), user.clone());
+        Some(user)
+    }
+
+    /// Responds `407` with a `Basic` challenge.
+    pub async fn challenge(&self, session: &mut Session) -> Result<()> {
+        let mut resp = ResponseHeader::build(407, Some(2))?;
+        resp.insert_header(
+            PROXY_AUTHENTICATE,
+            format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm),
+        )?;
+        resp.insert_header(http::header::CONTENT_LENGTH, "0")?;
+        session.set_keepalive(None);
+        session.write_response_header(Box::new(resp), true).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use argon2::password_hash::{PasswordHasher, SaltString};
+    use argon2::{Algorithm, Params, Version};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn bcrypt_hash(password: &str) -> String {
+        bcrypt::hash(password, 4).unwrap()
+    }
+
+    fn argon2_hash(password: &str) -> String {
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        argon2.hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    fn htpasswd(name: &str, content: &str) -> Result<ProxyAuth> {
+        let path = std::env::temp_dir().join(format!("pinproxy-{}-{name}", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        let auth = ProxyAuth::new(&AuthConfig {
+            htpasswd: path.to_string_lossy().into_owned(),
+            realm: "test".to_string(),
+        });
+        std::fs::remove_file(&path).ok();
+        auth
+    }
+
+    fn request(credentials: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"http://example.com/", None).unwrap();
+        if let Some(credentials) = credentials {
+            req.insert_header(PROXY_AUTHORIZATION, credentials).unwrap();
+        }
+        req
+    }
+
+    fn basic(user: &str, password: &str) -> String {
+        let pair = format!("{user}:{password}");
+        format!("Basic {}", base64::engine::general_purpose::STANDARD.encode(pair))
+    }
+
+    #[tokio::test]
+    async fn test_htpasswd_formats() {
+        // htpasswd -B writes $2y$, the bcrypt crate $2b$
+        let bcrypt_2y = bcrypt_hash("hunter2").replacen("$2b$", "$2y$", 1);
+        let content = format!(
+            "# users\n\nalice:{}\n  bob:{}\ncarol:{bcrypt_2y}\n",
+            bcrypt_hash("secret"),
+            argon2_hash("s3cret:with:colons"),
+        );
+        let auth = htpasswd("formats", &content).unwrap();
+        for (user, password, valid) in [
+            ("alice", "secret", true),
+            ("alice", "Secret", false),
+            ("bob", "s3cret:with:colons", true),
+            ("bob", "s3cret", false),
+            ("carol", "hunter2", true),
+            // Unknown users are rejected, even with the password of the hash
+            // verified in their place
+            ("dave", "secret", false),
+            ("", "secret", false),
+        ] {
+            let req = request(Some(&basic(user, password)));
+            let expected = valid.then(|| user.to_string());
+            assert_eq!(auth.authenticate(&req, "-").await, expected, "{user}:{password}");
+        }
+
+        for credentials in [None, Some("Bearer abc"), Some("Basic !!!"), Some("Basic YWxpY2U=")] {
+            assert_eq!(auth.authenticate(&request(credentials), "-").await, None);
+        }
+    }
+
+    #[test]
+    fn test_htpasswd_rejected() {
+        for line in [
+            "alice:{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=",
+            "alice:$apr1$salt$hash",
+            "alice:plaintext",
+            "alice:$argon2id$v=19$m=1024,t=1,p=1$!!!$!!!",
+            "no colon",
+        ] {
+            let result = htpasswd("rejected", line);
+            assert!(result.is_err(), "{line:?} should be rejected");
+            assert_eq!(result.err().unwrap().etype(), &CONFIG_ERROR);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verified_cache() {
+        let mut auth = htpasswd("cache", &format!("alice:{}", bcrypt_hash("secret"))).unwrap();
+        let valid = basic("alice", "secret");
+        let invalid = basic("alice", "wrong");
+        assert_eq!(auth.authenticate(&request(Some(&valid)), "-").await.as_deref(), Some("alice"));
+        assert_eq!(auth.authenticate(&request(Some(&invalid)), "-").await, None);
+        assert_eq!(auth.verified.lock().unwrap().len(), 1);
+
+        // Verified credentials are not checked against the users again
+        auth.users = Arc::new(HashMap::new());
+        assert_eq!(auth.authenticate(&request(Some(&valid)), "-").await.as_deref(), Some("alice"));
+        assert_eq!(auth.authenticate(&request(Some(&invalid)), "-").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_challenge() {
+        let auth = htpasswd("challenge", &format!("alice:{}", bcrypt_hash("secret"))).unwrap();
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = Session::new_h1(Box::new(server));
+        client
+            .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(session.read_request().await.unwrap());
+
+        auth.challenge(&mut session).await.unwrap();
+        drop(session);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        let response = response.to_ascii_lowercase();
+        assert!(response.starts_with("http/1.1 407 "), "{response}");
+        let challenge = "proxy-authenticate: basic realm=\"test\", charset=\"utf-8\"\r\n";
+        assert!(response.contains(challenge), "{response}");
+        assert!(response.contains("content-length: 0\r\n"));
+        assert!(response.contains("connection: close\r\n"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use idna::AsciiDenyList;
+use pingora::prelude::*;
+
+/// The host part of an authority
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Host {
+    /// A domain name, lowercased and converted to its ASCII (punycode) form
+    Domain(String),
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Domain(name) => f.write_str(name),
+            Host::Ipv4(ip) => write!(f, "{ip}"),
+            Host::Ipv6(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+/// A validated `host[:port]` authority as found in request URIs, `Host`
+/// headers and `CONNECT` targets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Authority {
+    pub host: Host,
+    /// The port, if one was given explicitly
+    pub port: Option<u16>,
+}
+
+impl Authority {
+    /// Parses an authority, rejecting anything that does not unambiguously
+    /// name a host.
+    ///
+    /// IPv6 literals are accepted bracketed (`[::1]:8080`) or bare without a
+    /// port (`::1`). Userinfo is rejected, it has no meaning to a proxy.
+    pub fn parse(input: &str) -> Result<Self> {
+        let invalid = |reason: &str| {
+            Error::e_explain(
+                HTTPStatus(400),
+                format!("invalid authority {input:?}: {reason}"),
+            )
+        };
+
+        if input.is_empty() {
+            return invalid("empty");
+        }
+        if input.contains('@') {
+            return invalid("userinfo is not allowed");
+        }
+
+        let (host, port) = if let Some(rest) = input.strip_prefix('[') {
+            let Some((literal, after)) = rest.split_once(']') else {
+                return invalid("unterminated IPv6 literal");
+            };
+            let Ok(ip) = literal.parse::<Ipv6Addr>() else {
+                return invalid("bad IPv6 literal");
+            };
+            if !after.is_empty() && !after.starts_with(':') {
+                return invalid("unexpected data after IPv6 literal");
+            }
+            (Host::Ipv6(ip), after.strip_prefix(':'))
+        } else if let Ok(ip) = input.parse::<Ipv6Addr>() {
+            (Host::Ipv6(ip), None)
+        } else {
+            let (host, port) = match input.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (input, None),
+            };
+            if host.is_empty() {
+                return invalid("empty host");
+            }
+            let host = match host.parse::<Ipv4Addr>() {
+                Ok(ip) => Host::Ipv4(ip),
+                Err(_) => match idna::domain_to_ascii_cow(host.as_bytes(), AsciiDenyList::URL) {
+                    Ok(name) if !name.is_empty() => Host::Domain(name.into_owned()),
+                    _ => return invalid("bad host name"),
+                },
+            };
+            (host, port)
+        };
+
+        match port.map(parse_port).unwrap_or(Some(None)) {
+            Some(port) => Ok(Authority { host, port }),
+            None => invalid("port must be between 1 and 65535"),
+        }
+    }
+
+    /// The explicit port, or the default port of the scheme.
+    pub fn port_or_default(&self, tls: bool) -> u16 {
+        self.port.unwrap_or(if tls { 443 } else { 80 })
+    }
+}
+
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            Host::Ipv6(ip) => write!(f, "[{ip}]")?,
+            host => write!(f, "{host}")?,
+        }
+        if let Some(port) = self.port {
+            write!(f, ":{port}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the port after a `:`, `None` if it is invalid.
+///
+/// An empty port means the scheme default, as allowed by RFC 3986 section
+/// 3.2.3.
+fn parse_port(port: &str) -> Option<Option<u16>> {
+    if port.is_empty() {
+        return Some(None);
+    }
+    if !port.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => None,
+        Ok(port) => Some(Some(port)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> (String, Option<u16>) {
+        let authority = Authority::parse(input).unwrap();
+        (authority.host.to_string(), authority.port)
+    }
+
+    #[test]
+    fn test_domain() {
+        assert_eq!(parse("example.com"), ("example.com".into(), None));
+        assert_eq!(parse("example.com:8080"), ("example.com".into(), Some(8080)));
+        assert_eq!(parse("Example.COM"), ("example.com".into(), None));
+        assert_eq!(parse("my_service:80"), ("my_service".into(), Some(80)));
+    }
+
+    #[test]
+    fn test_idna() {
+        assert_eq!(parse("bücher.de"), ("xn--bcher-kva.de".into(), None));
+        assert_eq!(parse("xn--bcher-kva.de:443"), ("xn--bcher-kva.de".into(), Some(443)));
+    }
+
+    #[test]
+    fn test_ipv4() {
+        let authority = Authority::parse("127.0.0.1:9000").unwrap();
+        assert_eq!(authority.host, Host::Ipv4(Ipv4Addr::LOCALHOST));
+        assert_eq!(authority.port, Some(9000));
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let authority = Authority::parse("[::1]:8080").unwrap();
+        assert_eq!(authority.host, Host::Ipv6(Ipv6Addr::LOCALHOST));
+        assert_eq!(authority.port, Some(8080));
+        assert_eq!(authority.to_string(), "[::1]:8080");
+
+        assert_eq!(parse("[2001:db8::1]"), ("2001:db8::1".into(), None));
+        assert_eq!(parse("2001:db8::1"), ("2001:db8::1".into(), None));
+        assert_eq!(parse("::1"), ("::1".into(), None));
+    }
+
+    #[test]
+    fn test_ports() {
+        assert_eq!(parse("example.com:"), ("example.com".into(), None));
+        assert_eq!(parse("[::1]:"), ("::1".into(), None));
+        assert_eq!(parse("example.com:65535").1, Some(65535));
+        assert_eq!(parse("example.com:1").1, Some(1));
+
+        let authority = Authority::parse("example.com").unwrap();
+        assert_eq!(authority.port_or_default(false), 80);
+        assert_eq!(authority.port_or_default(true), 443);
+        let authority = Authority::parse("example.com:8443").unwrap();
+        assert_eq!(authority.port_or_default(false), 8443);
+    }
+
+    #[test]
+    fn test_invalid() {
+        for input in [
+            "",
+            ":80",
+            "example.com:0",
+            "example.com:65536",
+            "example.com:http",
+            "example.com:+80",
+            "example.com:80:80",
+            "[::1",
+            "[::1]8080",
+            "[example.com]:80",
+            "[::1]:99999",
+            "user@example.com",
+            "exa mple.com",
+            "example.com/path",
+        ] {
+            let result = Authority::parse(input);
+            assert!(result.is_err(), "{input:?} should be rejected");
+            assert_eq!(result.unwrap_err().etype(), &HTTPStatus(400));
+        }
+    }
+}
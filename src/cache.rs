@@ -0,0 +1,300 @@
+use std::time::{Duration, SystemTime};
+
+use http::header::{HeaderName, AUTHORIZATION, DATE, LAST_MODIFIED, PRAGMA, UPGRADE, VARY};
+use log::info;
+use pingora::cache::cache_control::{CacheControl, InterpretCacheControl};
+use pingora::cache::eviction::{lru, EvictionManager};
+use pingora::cache::filters::{calculate_serve_stale_durations, request_cacheable, resp_cacheable};
+use pingora::cache::key::HashBinary;
+use pingora::cache::lock::{CacheKeyLockImpl, CacheLock};
+use pingora::cache::trace::Span;
+use pingora::cache::{
+    CacheKey, CacheMeta, CacheMetaDefaults, CachePhase, NoCacheReason, PurgeType, RespCacheable,
+    Storage, VarianceBuilder,
+};
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+
+use crate::cache_storage::{CacheIndex, DiskStorage, IndexEntry, MemoryStorage};
+use crate::config::{CacheConfig, CacheStorage, CONFIG_ERROR};
+
+/// Shards of the LRU eviction manager
+const EVICTION_SHARDS: usize = 16;
+
+/// How long requests for a response that is being fetched wait for it,
+/// instead of all going upstream
+const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Cap on the heuristic freshness of responses without explicit expiration
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 3600);
+
+/// Statuses cacheable by default, which get heuristic freshness
+/// (RFC 9110 section 15.1)
+const HEURISTICALLY_CACHEABLE: &[u16] =
+    &[200, 203, 204, 206, 300, 301, 308, 404, 405, 410, 414, 501];
+
+/// Every cache status a response can have
+pub const STATUSES: &[&str] = &["HIT", "STALE", "REVALIDATED", "EXPIRED", "MISS", "BYPASS"];
+
+/// The cache statuses of responses served from the cache
+pub const SERVED_FROM_CACHE: &[&str] = &["HIT", "STALE", "REVALIDATED"];
+
+/// Freshness only comes from the responses themselves, or heuristically
+/// from `Last-Modified`
+fn no_default_freshness(_status: http::StatusCode) -> Option<Duration> {
+    None
+}
+
+/// The response cache.
+///
+/// pingora-cache needs its storage, eviction manager and lock to live for
+/// the rest of the process, so they are created once at startup and never
+/// reloaded.
+pub struct Cache {
+    storage: &'static (dyn Storage + Sync),
+    index: &'static CacheIndex,
+    eviction: &'static lru::Manager<EVICTION_SHARDS>,
+    lock: &'static CacheKeyLockImpl,
+    defaults: CacheMetaDefaults,
+    max_size: usize,
+    max_object_size: usize,
+    status_header: String,
+    routes: Vec<String>,
+}
+
+impl Cache {
+    pub fn new(config: &CacheConfig) -> Result<Self> {
+        HeaderName::from_bytes(config.status_header.as_bytes()).or_err_with(CONFIG_ERROR, || {
+            format!("invalid cache status_header {:?}", config.status_header)
+        })?;
+        let max_size = usize::try_from(config.max_size_mb * 1024 * 1024).unwrap_or(usize::MAX);
+        let eviction: &'static _ =
+            Box::leak(Box::new(lru::Manager::with_capacity(max_size, 1024)));
+        let (storage, index): (&'static (dyn Storage + Sync), _) = match config.storage {
+            CacheStorage::Memory => {
+                let storage: &'static _ = Box::leak(Box::new(MemoryStorage::default()));
+                (storage, &storage.index)
+            }
+            CacheStorage::Disk => {
+                let path = config
+                    .path
+                    .as_deref()
+                    .or_err(CONFIG_ERROR, "disk cache storage needs a path")?;
+                let storage = DiskStorage::new(path)?;
+                let kept = storage.load(eviction)?;
+                info!("Loaded {kept} cached responses from {path}");
+                let storage: &'static _ = Box::leak(Box::new(storage));
+                (storage, &storage.index)
+            }
+        };
+        Ok(Cache {
+            storage,
+            index,
+            eviction,
+            lock: Box::leak(CacheLock::new_boxed(CACHE_LOCK_TIMEOUT)),
+            defaults: CacheMetaDefaults::new(
+                no_default_freshness,
+                config.stale_while_revalidate_secs,
+                config.stale_if_error_secs,
+            ),
+            max_size,
+            max_object_size: usize::try_from(config.max_object_size_mb * 1024 * 1024)
+                .unwrap_or(usize::MAX),
+            status_header: config.status_header.clone(),
+            routes: config.routes.clone(),
+        })
+    }
+
+    /// Whether requests matching `route` are cached
+    pub fn applies(&self, route: Option<&str>) -> bool {
+        self.routes.is_empty() || route.is_some_and(|route| self.routes.iter().any(|r| r == route))
+    }
+
+    /// Enables the cache for a request, unless its method, `no-store` or an
+    /// upgrade rules it out.
+    pub fn enable(&self, session: &mut Session) {
+        let req = session.req_header();
+        let no_store = CacheControl::from_req_headers(req).is_some_and(|cc| cc.no_store());
+        if !request_cacheable(req) || no_store || req.headers.contains_key(UPGRADE) {
+            return;
+        }
+        session
+            .cache
+            .enable(self.storage, Some(self.eviction), None, Some(self.lock), None);
+        session.cache.set_max_file_size_bytes(self.max_object_size);
+    }
+
+    /// Decides whether and for how long a response may be stored.
+    pub fn cacheable(&self, req: &RequestHeader, resp: &ResponseHeader) -> RespCacheable {
+        // A response varying on everything matches no later request
+        let vary_any = vary(&resp.headers).any(|name| name == "*");
+        if vary_any {
+            return RespCacheable::Uncacheable(NoCacheReason::OriginNotCache);
+        }
+        let cache_control = CacheControl::from_resp_headers(resp);
+        let authorization = req.headers.contains_key(AUTHORIZATION);
+        let cacheable =
+            resp_cacheable(cache_control.as_ref(), resp.clone(), authorization, &self.defaults);
+
+        // Without explicit freshness a response may still be stored for a
+        // while, as far as the origin allows caching at all
+        let forbidden = authorization
+            || cache_control
+                .as_ref()
+                .is_some_and(|cc| cc.no_store() || cc.private());
+        let fresh = match (&cacheable, forbidden) {
+            (RespCacheable::Uncacheable(_), false) => heuristic_freshness(resp),
+            _ => None,
+        };
+        let Some(fresh) = fresh else {
+            return cacheable;
+        };
+        let (stale_while_revalidate, stale_if_error) =
+            calculate_serve_stale_durations(cache_control.as_ref(), &self.defaults);
+        let mut header = resp.clone();
+        if let Some(cache_control) = &cache_control {
+            cache_control.strip_private_headers(&mut header);
+        }
+        let now = SystemTime::now();
+        RespCacheable::Cacheable(CacheMeta::new(
+            now + fresh,
+            now,
+            stale_while_revalidate,
+            stale_if_error,
+            header,
+        ))
+    }
+
+    /// Removes the stored responses `filter` accepts, returning how many
+    /// there were.
+    pub async fn purge(&self, filter: impl Fn(&IndexEntry) -> bool) -> Result<usize> {
+        let span = Span::inactive().handle();
+        let entries = self.index.find(filter);
+        for entry in &entries {
+            self.eviction.remove(&entry.key);
+            self.storage
+                .purge(&entry.key, PurgeType::Invalidation, &span)
+                .await?;
+        }
+        Ok(entries.len())
+    }
+
+    /// The stored responses `filter` accepts, with their metadata.
+    pub async fn entries(
+        &self,
+        filter: impl Fn(&IndexEntry) -> bool,
+    ) -> Result<Vec<(IndexEntry, CacheMeta)>> {
+        let span = Span::inactive().handle();
+        let mut found = vec![];
+        for entry in self.index.find(filter) {
+            let mut key = key(&entry.url);
+            if let Some(variance) = &entry.key.variance {
+                key.set_variance_key(**variance);
+            }
+            // Gone if it was purged or evicted in the meantime
+            if let Some((meta, _)) = self.storage.lookup(&key, &span).await? {
+                found.push((entry, meta));
+            }
+        }
+        Ok(found)
+    }
+
+    pub fn usage(&self) -> CacheUsage {
+        CacheUsage {
+            items: self.eviction.total_items(),
+            size: self.eviction.total_size(),
+            max_size: self.max_size,
+            evicted_items: self.eviction.evicted_items(),
+            evicted_size: self.eviction.evicted_size(),
+        }
+    }
+
+    /// Sets the cache status header of a response.
+    pub fn insert_status(&self, resp: &mut ResponseHeader, status: &'static str) -> Result<()> {
+        resp.insert_header(self.status_header.clone(), status)
+    }
+}
+
+/// How much is stored, in bytes and responses
+pub struct CacheUsage {
+    pub items: usize,
+    pub size: usize,
+    pub max_size: usize,
+    /// Evicted to stay under `max_size` since startup
+    pub evicted_items: usize,
+    pub evicted_size: usize,
+}
+
+/// The key responses for `url` are stored under, before `Vary` is applied.
+pub fn key(url: &str) -> CacheKey {
+    CacheKey::new("", url, "")
+}
+
+/// The header names listed in `Vary` headers, lowercase.
+fn vary(headers: &http::HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(VARY)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+}
+
+/// The variance key of a request for a stored response, from the request
+/// headers the response's `Vary` names.
+pub fn variance(meta: &CacheMeta, req: &RequestHeader) -> Option<HashBinary> {
+    let names: Vec<String> = vary(meta.headers()).collect();
+    let mut variance = VarianceBuilder::new();
+    for name in &names {
+        let value = req.headers.get(name.as_str()).map_or(&b""[..], |v| v.as_bytes());
+        variance.add_value(name, value);
+    }
+    variance.finalize()
+}
+
+/// Whether the client asked for stored responses to be revalidated, with
+/// `Cache-Control: no-cache` or `max-age=0`, or `Pragma: no-cache`.
+pub fn revalidation_requested(req: &RequestHeader) -> bool {
+    match CacheControl::from_req_headers(req) {
+        Some(cc) => cc.no_cache() || cc.max_age().ok().flatten() == Some(0),
+        None => req
+            .headers
+            .get(PRAGMA)
+            .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache")),
+    }
+}
+
+/// The cache status of a response, `None` if the cache was not looked up
+/// (yet).
+pub fn status(phase: CachePhase) -> Option<&'static str> {
+    match phase {
+        CachePhase::Hit => Some("HIT"),
+        CachePhase::Stale | CachePhase::StaleUpdating => Some("STALE"),
+        CachePhase::Expired => Some("EXPIRED"),
+        CachePhase::Revalidated | CachePhase::RevalidatedNoCache(_) => Some("REVALIDATED"),
+        CachePhase::Miss => Some("MISS"),
+        // The request itself could not be served from the cache
+        CachePhase::Disabled(NoCacheReason::NeverEnabled) | CachePhase::Bypass => Some("BYPASS"),
+        // Looked up but not stored, e.g. because the response was uncacheable
+        CachePhase::Disabled(_) => Some("MISS"),
+        CachePhase::Uninit | CachePhase::CacheKey => None,
+    }
+}
+
+/// A tenth of the time since the response was last modified, as RFC 9111
+/// section 4.2.2 suggests for responses without explicit expiration.
+fn heuristic_freshness(resp: &ResponseHeader) -> Option<Duration> {
+    if !HEURISTICALLY_CACHEABLE.contains(&resp.status.as_u16()) {
+        return None;
+    }
+    let modified = header_date(resp, LAST_MODIFIED)?;
+    let date = header_date(resp, DATE).unwrap_or_else(SystemTime::now);
+    let age = date.duration_since(modified).ok()?;
+    Some((age / 10).min(MAX_HEURISTIC_FRESHNESS))
+}
+
+fn header_date(resp: &ResponseHeader, name: HeaderName) -> Option<SystemTime> {
+    let value = resp.headers.get(name)?.to_str().ok()?;
+    value.parse::<httpdate::HttpDate>().ok().map(SystemTime::from)
+}
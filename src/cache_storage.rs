@@ -0,0 +1,795 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::warn;
+use pingora::cache::eviction::EvictionManager;
+use pingora::cache::key::{CacheHashKey, CompactCacheKey, HashBinary};
+use pingora::cache::storage::{HandleHit, HandleMiss, MissFinishType};
+use pingora::cache::trace::SpanHandle;
+use pingora::cache::{CacheKey, CacheMeta, HitHandler, MissHandler, PurgeType, Storage};
+use pingora::http::HMap;
+use pingora::prelude::*;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// What is known about a stored response without reading it
+#[derive(Debug, Clone)]
+pub struct IndexEntry {
+    pub key: CompactCacheKey,
+    /// The URL the response was stored for
+    pub url: String,
+    /// `Surrogate-Key` and `Cache-Tag` values of the response
+    pub tags: Vec<String>,
+    pub size: usize,
+}
+
+impl IndexEntry {
+    fn new(key: &CacheKey, meta: &CacheMeta) -> Self {
+        IndexEntry {
+            key: key.to_compact(),
+            url: String::from_utf8_lossy(key.primary_key()).into_owned(),
+            tags: tags(meta.headers()),
+            size: 0,
+        }
+    }
+}
+
+/// The tags a response can be purged by, from the space-separated
+/// `Surrogate-Key` and comma-separated `Cache-Tag` headers
+fn tags(headers: &HMap) -> Vec<String> {
+    let values = |name: &str, separator: fn(char) -> bool| -> Vec<String> {
+        headers
+            .get_all(name)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(separator))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    };
+    let mut tags = values("surrogate-key", char::is_whitespace);
+    tags.extend(values("cache-tag", |c| c == ','));
+    tags
+}
+
+/// The responses a storage holds, by the hash of their key
+#[derive(Default)]
+pub struct CacheIndex {
+    entries: RwLock<HashMap<String, IndexEntry>>,
+}
+
+impl CacheIndex {
+    fn insert(&self, entry: IndexEntry) {
+        let hash = entry.key.combined();
+        self.entries.write().unwrap().insert(hash, entry);
+    }
+
+    fn remove(&self, key: &CompactCacheKey) {
+        self.entries.write().unwrap().remove(&key.combined());
+    }
+
+    /// The entries `filter` accepts.
+    pub fn find(&self, filter: impl Fn(&IndexEntry) -> bool) -> Vec<IndexEntry> {
+        let entries = self.entries.read().unwrap();
+        entries.values().filter(|entry| filter(entry)).cloned().collect()
+    }
+}
+
+/// Cached responses kept in memory, lost on restart
+#[derive(Default)]
+pub struct MemoryStorage {
+    pub index: CacheIndex,
+    entries: RwLock<HashMap<String, Arc<MemoryEntry>>>,
+}
+
+struct MemoryEntry {
+    meta: (Vec<u8>, Vec<u8>),
+    body: Bytes,
+}
+
+impl MemoryEntry {
+    /// What the entry counts against the size limit
+    fn size(&self) -> usize {
+        self.meta.0.len() + self.meta.1.len() + self.body.len()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let Some(entry) = self.entries.read().unwrap().get(&key.combined()).cloned() else {
+            return Ok(None);
+        };
+        let meta = CacheMeta::deserialize(&entry.meta.0, &entry.meta.1)?;
+        let hit = MemoryHit {
+            size: entry.size(),
+            body: Some(entry.body.clone()),
+        };
+        Ok(Some((meta, Box::new(hit))))
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        Ok(Box::new(MemoryMiss {
+            storage: self,
+            key: key.combined(),
+            meta: meta.serialize()?,
+            body: vec![],
+            entry: IndexEntry::new(key, meta),
+        }))
+    }
+
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        self.index.remove(key);
+        Ok(self.entries.write().unwrap().remove(&key.combined()).is_some())
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        let meta = meta.serialize()?;
+        let mut entries = self.entries.write().unwrap();
+        let Some(entry) = entries.get_mut(&key.combined()) else {
+            return Ok(false);
+        };
+        let body = entry.body.clone();
+        *entry = Arc::new(MemoryEntry { meta, body });
+        Ok(true)
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+struct MemoryHit {
+    body: Option<Bytes>,
+    size: usize,
+}
+
+#[async_trait]
+impl HandleHit for MemoryHit {
+    async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        Ok(self.body.take())
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        _storage: &'static (dyn Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_eviction_weight(&self) -> usize {
+        self.size
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut (dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// A response being stored, which becomes visible once complete
+struct MemoryMiss {
+    storage: &'static MemoryStorage,
+    key: String,
+    meta: (Vec<u8>, Vec<u8>),
+    body: Vec<u8>,
+    entry: IndexEntry,
+}
+
+#[async_trait]
+impl HandleMiss for MemoryMiss {
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        self.body.extend_from_slice(&data);
+        Ok(())
+    }
+
+    async fn finish(self: Box<Self>) -> Result<MissFinishType> {
+        let entry = MemoryEntry {
+            meta: self.meta,
+            body: self.body.into(),
+        };
+        let size = entry.size();
+        self.storage
+            .entries
+            .write()
+            .unwrap()
+            .insert(self.key, Arc::new(entry));
+        self.storage.index.insert(IndexEntry { size, ..self.entry });
+        Ok(MissFinishType::Created(size))
+    }
+}
+
+/// Start of every cache file, followed by the rest of its [`EntryHeader`] and
+/// the body
+const MAGIC: &[u8; 4] = b"PPC2";
+
+/// Size of the body chunks read from cache files
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Cached responses kept on disk, one file per response named after its key.
+///
+/// Files are written under a temporary name and renamed into place once
+/// complete, so readers only ever see whole responses.
+pub struct DiskStorage {
+    pub index: CacheIndex,
+    path: PathBuf,
+    /// Tells temporary files of concurrent writes apart
+    temp_files: AtomicU64,
+}
+
+impl DiskStorage {
+    pub fn new(path: &str) -> Result<Self> {
+        fs::create_dir_all(path)
+            .or_err_with(InternalError, || format!("creating cache directory {path}"))?;
+        Ok(DiskStorage {
+            index: CacheIndex::default(),
+            path: PathBuf::from(path),
+            temp_files: AtomicU64::new(0),
+        })
+    }
+
+    fn entry_path(&self, hash: &str) -> PathBuf {
+        self.path.join(&hash[..2]).join(hash)
+    }
+
+    /// Hands the responses stored by an earlier run to the eviction manager,
+    /// removing what does not fit and any unfinished or unreadable files.
+    ///
+    /// Returns the number of responses kept.
+    pub fn load(&self, eviction: &dyn EvictionManager) -> Result<usize> {
+        let context = || format!("reading cache directory {}", self.path.display());
+        let mut kept = 0;
+        for dir in fs::read_dir(&self.path).or_err_with(InternalError, context)? {
+            let dir = dir.or_err_with(InternalError, context)?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(&dir).or_err_with(InternalError, context)? {
+                let path = file.or_err_with(InternalError, context)?.path();
+                let Some((entry, fresh_until)) = load_entry(&path) else {
+                    remove(&path);
+                    continue;
+                };
+                kept += 1;
+                let evicted = eviction.admit(entry.key.clone(), entry.size, fresh_until);
+                self.index.insert(entry);
+                for evicted in evicted {
+                    remove(&self.entry_path(&evicted.combined()));
+                    self.index.remove(&evicted);
+                    kept -= 1;
+                }
+            }
+        }
+        Ok(kept)
+    }
+}
+
+/// Reads what the index and the eviction manager need to know about a cache
+/// file, `None` for files that are not complete cache entries.
+fn load_entry(path: &Path) -> Option<(IndexEntry, SystemTime)> {
+    if path.extension().is_some_and(|ext| ext == "tmp") {
+        return None;
+    }
+    let read = || -> io::Result<_> {
+        let (file, header) = EntryHeader::open(path)?;
+        Ok((header, file.metadata()?.len()))
+    };
+    match read() {
+        Ok((header, size)) => {
+            let meta = CacheMeta::deserialize(&header.meta.0, &header.meta.1).ok()?;
+            let entry = IndexEntry {
+                key: header.key,
+                url: header.url,
+                tags: tags(meta.headers()),
+                size: size as usize,
+            };
+            Some((entry, meta.fresh_until()))
+        }
+        Err(e) => {
+            warn!("Discarding cache file {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            warn!("Failed to remove cache file {}: {e}", path.display());
+        }
+    }
+}
+
+/// What a cache file holds in front of the body
+struct EntryHeader {
+    key: CompactCacheKey,
+    url: String,
+    meta: (Vec<u8>, Vec<u8>),
+}
+
+impl EntryHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&self.key.primary);
+        match &self.key.variance {
+            Some(variance) => {
+                buf.push(1);
+                buf.extend_from_slice(variance.as_ref());
+            }
+            None => buf.push(0),
+        }
+        buf.extend_from_slice(&(self.url.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&(self.meta.0.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&(self.meta.1.len() as u32).to_be_bytes());
+        buf.extend_from_slice(self.url.as_bytes());
+        buf.extend_from_slice(&self.meta.0);
+        buf.extend_from_slice(&self.meta.1);
+        buf
+    }
+
+    /// Opens a cache file and reads its header, leaving the file at the
+    /// start of the body.
+    fn open(path: &Path) -> io::Result<(fs::File, Self)> {
+        let mut file = fs::File::open(path)?;
+        let mut magic = [0; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a cache file"));
+        }
+        let mut primary = HashBinary::default();
+        file.read_exact(&mut primary)?;
+        let mut flag = [0; 1];
+        file.read_exact(&mut flag)?;
+        let variance = if flag[0] == 1 {
+            let mut variance = HashBinary::default();
+            file.read_exact(&mut variance)?;
+            Some(Box::new(variance))
+        } else {
+            None
+        };
+        let mut read_field = || -> io::Result<Vec<u8>> {
+            let mut length = [0; 4];
+            file.read_exact(&mut length)?;
+            Ok(vec![0; u32::from_be_bytes(length) as usize])
+        };
+        let (mut url, mut internal, mut header) = (read_field()?, read_field()?, read_field()?);
+        file.read_exact(&mut url)?;
+        file.read_exact(&mut internal)?;
+        file.read_exact(&mut header)?;
+        let header = EntryHeader {
+            key: CompactCacheKey {
+                primary,
+                variance,
+                user_tag: "".into(),
+            },
+            url: String::from_utf8_lossy(&url).into_owned(),
+            meta: (internal, header),
+        };
+        Ok((file, header))
+    }
+}
+
+#[async_trait]
+impl Storage for DiskStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let path = self.entry_path(&key.combined());
+        let opened = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || EntryHeader::open(&path))
+                .await
+                .or_err(InternalError, "reading cache file")?
+        };
+        let (file, header) = match opened {
+            Ok(opened) => opened,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                // Looked up again, a broken file would keep the response
+                // from ever being cached
+                warn!("Discarding cache file {}: {e}", path.display());
+                remove(&path);
+                return Ok(None);
+            }
+        };
+        let meta = CacheMeta::deserialize(&header.meta.0, &header.meta.1)?;
+        let size = file
+            .metadata()
+            .or_err_with(InternalError, || format!("reading cache file {}", path.display()))?
+            .len();
+        let hit = DiskHit {
+            file: tokio::fs::File::from_std(file),
+            size: size as usize,
+        };
+        Ok(Some((meta, Box::new(hit))))
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let path = self.entry_path(&key.combined());
+        let temp = path.with_extension(format!(
+            "{}.tmp",
+            self.temp_files.fetch_add(1, Ordering::Relaxed)
+        ));
+        let context = || format!("writing cache file {}", temp.display());
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .or_err_with(InternalError, context)?;
+        }
+        let entry = IndexEntry::new(key, meta);
+        let header = EntryHeader {
+            key: entry.key.clone(),
+            url: entry.url.clone(),
+            meta: meta.serialize()?,
+        }
+        .encode();
+        let mut file = tokio::fs::File::create(&temp)
+            .await
+            .or_err_with(InternalError, context)?;
+        if let Err(e) = file.write_all(&header).await {
+            remove(&temp);
+            return Err(e).or_err_with(InternalError, context);
+        }
+        Ok(Box::new(DiskMiss {
+            storage: self,
+            file: Some(file),
+            size: header.len(),
+            temp,
+            path,
+            entry,
+        }))
+    }
+
+    async fn purge(
+        &'static self,
+        key: &CompactCacheKey,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        self.index.remove(key);
+        let path = self.entry_path(&key.combined());
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e)
+                .or_err_with(InternalError, || format!("removing cache file {}", path.display())),
+        }
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        _trace: &SpanHandle,
+    ) -> Result<bool> {
+        // The header is in front of the body, so the file is copied with the
+        // new one and swapped in like a new response
+        let path = self.entry_path(&key.combined());
+        let temp = path.with_extension(format!(
+            "{}.tmp",
+            self.temp_files.fetch_add(1, Ordering::Relaxed)
+        ));
+        let meta = meta.serialize()?;
+        let rewrite = {
+            let (path, temp) = (path.clone(), temp.clone());
+            move || -> io::Result<()> {
+                let (mut old, header) = EntryHeader::open(&path)?;
+                let header = EntryHeader { meta, ..header };
+                let mut new = fs::File::create(&temp)?;
+                new.write_all(&header.encode())?;
+                io::copy(&mut old, &mut new)?;
+                fs::rename(&temp, &path)
+            }
+        };
+        let updated = tokio::task::spawn_blocking(rewrite)
+            .await
+            .or_err(InternalError, "updating cache file")?;
+        match updated {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                remove(&temp);
+                if e.kind() == io::ErrorKind::NotFound {
+                    return Ok(false);
+                }
+                let context = || format!("updating cache file {}", path.display());
+                Err(e).or_err_with(InternalError, context)
+            }
+        }
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+struct DiskHit {
+    file: tokio::fs::File,
+    size: usize,
+}
+
+#[async_trait]
+impl HandleHit for DiskHit {
+    async fn read_body(&mut self) -> Result<Option<Bytes>> {
+        let mut buf = vec![0; READ_CHUNK];
+        let read = self
+            .file
+            .read(&mut buf)
+            .await
+            .or_err(InternalError, "reading cache file")?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buf.truncate(read);
+        Ok(Some(buf.into()))
+    }
+
+    async fn finish(
+        self: Box<Self>,
+        _storage: &'static (dyn Storage + Sync),
+        _key: &CacheKey,
+        _trace: &SpanHandle,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_eviction_weight(&self) -> usize {
+        self.size
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync) {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut (dyn Any + Send + Sync) {
+        self
+    }
+}
+
+/// A response being written to a temporary file, removed unless finished
+struct DiskMiss {
+    storage: &'static DiskStorage,
+    /// `None` once finished
+    file: Option<tokio::fs::File>,
+    size: usize,
+    temp: PathBuf,
+    path: PathBuf,
+    entry: IndexEntry,
+}
+
+#[async_trait]
+impl HandleMiss for DiskMiss {
+    async fn write_body(&mut self, data: Bytes, _eof: bool) -> Result<()> {
+        let file = self.file.as_mut().or_err(InternalError, "cache file already finished")?;
+        file.write_all(&data)
+            .await
+            .or_err_with(InternalError, || format!("writing cache file {}", self.temp.display()))?;
+        self.size += data.len();
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<MissFinishType> {
+        let context = || format!("writing cache file {}", self.path.display());
+        let mut file = self.file.take().or_err(InternalError, "cache file already finished")?;
+        file.flush().await.or_err_with(InternalError, context)?;
+        drop(file);
+        let renamed = tokio::fs::rename(&self.temp, &self.path).await;
+        if renamed.is_err() {
+            remove(&self.temp);
+        }
+        renamed.or_err_with(InternalError, context)?;
+        let entry = IndexEntry {
+            size: self.size,
+            ..self.entry.clone()
+        };
+        self.storage.index.insert(entry);
+        Ok(MissFinishType::Created(self.size))
+    }
+}
+
+impl Drop for DiskMiss {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            remove(&self.temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pingora::cache::eviction::lru;
+    use pingora::cache::trace::Span;
+    use pingora::http::ResponseHeader;
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("pinproxy-{}-{name}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir.to_string_lossy().into_owned()
+    }
+
+    fn open(path: &str) -> &'static DiskStorage {
+        Box::leak(Box::new(DiskStorage::new(path).unwrap()))
+    }
+
+    fn key(url: &str) -> CacheKey {
+        CacheKey::new("", url, "")
+    }
+
+    fn meta(tags: &str) -> CacheMeta {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header.insert_header("surrogate-key", tags).unwrap();
+        header.insert_header("cache-tag", "all, shared").unwrap();
+        let now = SystemTime::now();
+        CacheMeta::new(now + Duration::from_secs(60), now, 0, 0, header)
+    }
+
+    async fn store(storage: &'static dyn Storage, url: &str, tags: &str, body: &[u8]) {
+        let span = Span::inactive().handle();
+        let mut miss = storage
+            .get_miss_handler(&key(url), &meta(tags), &span)
+            .await
+            .unwrap();
+        miss.write_body(Bytes::copy_from_slice(body), true).await.unwrap();
+        miss.finish().await.unwrap();
+    }
+
+    /// The body and `Surrogate-Key` of a stored response
+    async fn fetch(storage: &'static dyn Storage, url: &str) -> Option<(Vec<u8>, String)> {
+        let span = Span::inactive().handle();
+        let (meta, mut hit) = storage.lookup(&key(url), &span).await.unwrap()?;
+        let mut body = vec![];
+        while let Some(chunk) = hit.read_body().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+        let tags = meta.headers().get("surrogate-key").unwrap();
+        Some((body, tags.to_str().unwrap().to_string()))
+    }
+
+    fn urls(index: &CacheIndex, filter: impl Fn(&IndexEntry) -> bool) -> Vec<String> {
+        let mut urls: Vec<String> = index.find(filter).into_iter().map(|e| e.url).collect();
+        urls.sort();
+        urls
+    }
+
+    fn files(path: &str) -> usize {
+        fs::read_dir(path)
+            .unwrap()
+            .map(|dir| fs::read_dir(dir.unwrap().path()).unwrap().count())
+            .sum()
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut header = ResponseHeader::build(200, None).unwrap();
+        header.append_header("surrogate-key", " a  b\tc ").unwrap();
+        header.append_header("surrogate-key", "d").unwrap();
+        header.append_header("cache-tag", "e, f g,,").unwrap();
+        assert_eq!(tags(&header.headers), ["a", "b", "c", "d", "e", "f g"]);
+    }
+
+    #[tokio::test]
+    async fn test_memory_round_trip() {
+        let storage: &'static _ = Box::leak(Box::new(MemoryStorage::default()));
+        store(storage, "/a", "x", b"hello").await;
+        assert_eq!(fetch(storage, "/a").await, Some((b"hello".to_vec(), "x".to_string())));
+        assert_eq!(fetch(storage, "/b").await, None);
+        assert_eq!(urls(&storage.index, |e| e.tags.contains(&"x".into())), ["/a"]);
+
+        let span = Span::inactive().handle();
+        let compact = key("/a").to_compact();
+        let purged = storage.purge(&compact, PurgeType::Invalidation, &span).await;
+        assert!(purged.unwrap());
+        assert_eq!(fetch(storage, "/a").await, None);
+        assert!(storage.index.find(|_| true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_disk_round_trip() {
+        let path = temp_dir("cache-round-trip");
+        let storage = open(&path);
+        store(storage, "/a", "red blue", b"first").await;
+        store(storage, "/b", "blue", &vec![7; 3 * READ_CHUNK + 5]).await;
+        store(storage, "/c", "green", b"third").await;
+        assert_eq!(
+            fetch(storage, "/a").await,
+            Some((b"first".to_vec(), "red blue".to_string()))
+        );
+
+        let span = Span::inactive().handle();
+        let updated = storage.update_meta(&key("/a"), &meta("red"), &span).await;
+        assert!(updated.unwrap());
+        let compact = key("/c").to_compact();
+        let purged = storage.purge(&compact, PurgeType::Invalidation, &span).await;
+        assert!(purged.unwrap());
+        drop(span);
+
+        // Another run finds the responses and their index entries again
+        let reopened = open(&path);
+        let eviction = lru::Manager::<1>::with_capacity(usize::MAX, 16);
+        assert_eq!(reopened.load(&eviction).unwrap(), 2);
+        assert_eq!(eviction.total_items(), 2);
+        assert_eq!(
+            fetch(reopened, "/a").await,
+            Some((b"first".to_vec(), "red".to_string()))
+        );
+        let (body, _) = fetch(reopened, "/b").await.unwrap();
+        assert_eq!(body, vec![7; 3 * READ_CHUNK + 5]);
+        assert_eq!(fetch(reopened, "/c").await, None);
+
+        assert_eq!(urls(&reopened.index, |_| true), ["/a", "/b"]);
+        assert_eq!(urls(&reopened.index, |e| e.tags.contains(&"blue".into())), ["/b"]);
+        assert_eq!(urls(&reopened.index, |e| e.tags.contains(&"shared".into())), ["/a", "/b"]);
+        let entry = reopened.index.find(|e| e.url == "/b").remove(0);
+        assert_eq!(entry.key, key("/b").to_compact());
+        assert!(entry.size > 3 * READ_CHUNK + 5);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_disk_load_evicts() {
+        let path = temp_dir("cache-evict");
+        let storage = open(&path);
+        for url in ["/a", "/b", "/c", "/d"] {
+            store(storage, url, "t", &[0; 1000]).await;
+        }
+        // Leftovers of an interrupted write and files that are not entries
+        let dir = fs::read_dir(&path).unwrap().next().unwrap().unwrap().path();
+        fs::write(dir.join("unfinished.0.tmp"), b"PPC2").unwrap();
+        fs::write(dir.join("garbage"), b"not a cache file").unwrap();
+        assert_eq!(files(&path), 6);
+
+        // Only two responses fit the size limit of this run
+        let reopened = open(&path);
+        let eviction = lru::Manager::<1>::with_capacity(2 * 1000 + 1000, 16);
+        assert_eq!(reopened.load(&eviction).unwrap(), 2);
+        assert_eq!(files(&path), 2);
+        let kept = urls(&reopened.index, |_| true);
+        assert_eq!(kept.len(), 2);
+        for url in ["/a", "/b", "/c", "/d"] {
+            let hit = fetch(reopened, url).await.is_some();
+            assert_eq!(hit, kept.contains(&url.to_string()), "{url}");
+        }
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
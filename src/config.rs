@@ -0,0 +1,1108 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use pingora::prelude::*;
+use serde::Deserialize;
+
+/// Errors in the configuration file
+pub const CONFIG_ERROR: ErrorType = ErrorType::Custom("ConfigError");
+
+/// The pinproxy configuration file.
+///
+/// Written in TOML, or YAML when the file name ends in `.yaml`/`.yml`:
+///
+/// ```toml
+/// [[listeners]]
+/// address = "0.0.0.0:8080"
+///
+/// [upstreams.billing]
+/// backends = [{ address = "10.0.0.5:8080" }, { address = "10.0.0.6:8080" }]
+/// algorithm = "least_connections"
+/// health_check = { type = "http", path = "/healthz" }
+///
+/// [[routes]]
+/// host = "api.example.com"
+/// path_prefix = "/billing"
+/// upstream = "billing"
+///
+/// [unmatched]
+/// action = "reject"
+/// status = 404
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Addresses to accept connections on, replacing `--port`
+    pub listeners: Vec<ListenerConfig>,
+    /// Named upstreams that routes forward to
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    /// Routes, tried in order, the first match wins
+    pub routes: Vec<RouteConfig>,
+    /// What to do with requests no route matches
+    pub unmatched: UnmatchedConfig,
+    /// Prometheus metrics listener
+    pub metrics: Option<MetricsConfig>,
+    /// Admin API listener
+    pub admin: Option<AdminConfig>,
+    pub access_log: AccessLogConfig,
+    pub acl: AclConfig,
+    /// Proxy authentication, off unless configured
+    pub auth: Option<AuthConfig>,
+    /// Rate limits, a request has to pass all of them
+    pub rate_limits: Vec<RateLimitConfig>,
+    pub proxy_headers: ProxyHeadersConfig,
+    pub request_id: RequestIdConfig,
+    pub upgrades: UpgradesConfig,
+    /// Upstream timeouts of forwarded requests, and the defaults of
+    /// upstreams and routes
+    pub timeouts: TimeoutsConfig,
+    /// Responses sent when a request cannot be proxied
+    pub error_pages: ErrorPagesConfig,
+    /// Resolution of the hosts of forwarded requests and tunnels
+    pub dns: DnsConfig,
+    /// Response cache, off unless configured
+    pub cache: Option<CacheConfig>,
+    /// OpenTelemetry tracing, off unless configured
+    pub tracing: Option<TracingConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// `ip:port` to bind to
+    pub address: String,
+    /// Certificate chain (PEM), makes this a TLS listener
+    pub tls_cert: Option<String>,
+    /// Private key (PEM) of `tls_cert`
+    pub tls_key: Option<String>,
+    /// Offer HTTP/2 over ALPN, on TLS listeners
+    #[serde(default = "default_http2")]
+    pub http2: bool,
+    /// Accept HTTP/2 with prior knowledge (h2c) alongside HTTP/1.1, on
+    /// plaintext listeners
+    #[serde(default)]
+    pub h2c: bool,
+}
+
+fn default_http2() -> bool {
+    true
+}
+
+/// How a listener takes connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listening<'a> {
+    /// Plaintext HTTP/1.1
+    Plain,
+    /// Plaintext HTTP/1.1, and HTTP/2 with prior knowledge
+    H2c,
+    /// TLS, offering HTTP/2 over ALPN with `http2`
+    Tls {
+        cert: &'a str,
+        key: &'a str,
+        http2: bool,
+    },
+}
+
+impl ListenerConfig {
+    /// How this listener takes connections, or an error if its settings
+    /// conflict.
+    pub fn listening(&self) -> Result<Listening<'_>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), Some(_)) if self.h2c => Error::e_explain(
+                CONFIG_ERROR,
+                format!("listener {}: h2c is for plaintext listeners", self.address),
+            ),
+            (Some(cert), Some(key)) => Ok(Listening::Tls {
+                cert,
+                key,
+                http2: self.http2,
+            }),
+            (None, None) if self.h2c => Ok(Listening::H2c),
+            (None, None) => Ok(Listening::Plain),
+            _ => Error::e_explain(
+                CONFIG_ERROR,
+                format!("listener {} needs both tls_cert and tls_key", self.address),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `ip:port` serving the metrics over plain HTTP
+    pub address: String,
+}
+
+/// HTTP API to inspect and purge the cache
+///
+/// ```toml
+/// [admin]
+/// address = "127.0.0.1:9092"
+/// token = "change-me"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// `ip:port` serving the API over plain HTTP
+    pub address: String,
+    /// Bearer token every request must present in `Authorization`
+    pub token: String,
+}
+
+/// Where and how requests are logged, one line per request
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    /// The line format of `custom`, with nginx-style variables such as
+    /// `$remote_addr`, `$status` or `$http_user_agent`
+    pub template: Option<String>,
+    pub output: AccessLogOutput,
+    /// File to write to for the `file` output
+    pub path: Option<String>,
+    /// Size at which the file is rotated, 0 never rotates
+    pub max_size_mb: u64,
+    /// Rotated files kept next to the current one
+    pub max_files: usize,
+    /// Socket path, or `host:port` for UDP, of the `syslog` output
+    pub syslog_address: String,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            format: AccessLogFormat::Combined,
+            template: None,
+            output: AccessLogOutput::Stdout,
+            path: None,
+            max_size_mb: 100,
+            max_files: 5,
+            syslog_address: "/dev/log".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    /// Common Log Format
+    Common,
+    /// Combined Log Format, CLF plus referer and user agent
+    Combined,
+    /// One JSON object per line
+    Json,
+    /// `template`
+    Custom,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogOutput {
+    Stdout,
+    File,
+    Syslog,
+}
+
+/// Access control for clients and destinations
+///
+/// ```toml
+/// [acl]
+/// default = "deny"
+///
+/// [[acl.rules]]
+/// action = "allow"
+/// clients = ["10.0.0.0/8"]
+/// ports = [80, 443]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// Action for requests no rule matches
+    pub default: AclAction,
+    /// Deny forwarding to private, loopback and link-local addresses
+    pub block_private: bool,
+    /// Tried in order, the first match decides
+    pub rules: Vec<AclRuleConfig>,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            default: AclAction::Allow,
+            block_private: true,
+            rules: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AclAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// A rule matching requests on all of the criteria that are set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclRuleConfig {
+    /// Name used in the audit log, defaults to the rule's position
+    pub name: Option<String>,
+    pub action: AclAction,
+    /// Client networks, e.g. `10.0.0.0/8` or a single address
+    pub clients: Vec<String>,
+    /// Destination host names, `*.example.com` matches any subdomain
+    pub hosts: Vec<String>,
+    pub ports: Vec<u16>,
+    /// Networks all addresses the destination resolves to must be in;
+    /// an allow rule with destinations lifts `block_private` for them
+    pub destinations: Vec<String>,
+}
+
+/// `Proxy-Authorization: Basic` authentication of every request, forwarded,
+/// tunneled or routed, except for routes that opt out with `auth = false`
+///
+/// ```toml
+/// [auth]
+/// htpasswd = "/etc/pinproxy/htpasswd"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// htpasswd file with bcrypt (`htpasswd -B`) or argon2 hashes, read again
+    /// on every reload
+    pub htpasswd: String,
+    /// Realm of the `Proxy-Authenticate` challenge
+    #[serde(default = "default_realm")]
+    pub realm: String,
+}
+
+fn default_realm() -> String {
+    "pinproxy".to_string()
+}
+
+/// A token bucket refilled with `rate` requests per second, holding up to
+/// `burst`, for every value of `key`
+///
+/// ```toml
+/// [[rate_limits]]
+/// key = { header = "X-CI-Job" }
+/// rate = 10
+/// burst = 50
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Name used in logs and metrics, defaults to the limit's position
+    pub name: Option<String>,
+    /// What requests are counted by
+    #[serde(default)]
+    pub key: RateLimitKey,
+    /// Requests per second
+    pub rate: f64,
+    /// Requests allowed at once, defaults to one second's worth
+    pub burst: Option<u32>,
+    /// Names of the routes limited, all requests when empty
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+/// `"client_ip"`, `"user"`, `"route"` or `{ header = "name" }`
+///
+/// Requests without an authenticated user or the header are counted by
+/// client IP. Limits by user are checked after authentication, the others
+/// before it, so failed attempts count against both.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+    #[default]
+    ClientIp,
+    User,
+    Route,
+    Header(String),
+}
+
+/// `Via`, `Forwarded` and `X-Forwarded-*` headers added to requests
+///
+/// ```toml
+/// [proxy_headers]
+/// forwarded = true
+/// trusted_proxies = ["10.0.0.0/8"]
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyHeadersConfig {
+    /// Add `Via` to requests and responses
+    pub via: bool,
+    /// Name of the proxy in `Via`
+    pub pseudonym: String,
+    /// Add RFC 7239 `Forwarded`
+    pub forwarded: bool,
+    /// Add `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`
+    pub x_forwarded: bool,
+    /// Clients whose `Forwarded` and `X-Forwarded-*` values are kept and
+    /// appended to, the values of other clients are replaced
+    pub trusted_proxies: Vec<String>,
+}
+
+impl Default for ProxyHeadersConfig {
+    fn default() -> Self {
+        ProxyHeadersConfig {
+            via: true,
+            pseudonym: "pinproxy".to_string(),
+            forwarded: false,
+            x_forwarded: true,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+/// The ID correlating a request across the access log, the proxy's log and
+/// the upstream's logs
+///
+/// ```toml
+/// [request_id]
+/// header = "X-Correlation-Id"
+/// trust = "all"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestIdConfig {
+    /// Header carrying the ID
+    pub header: String,
+    /// Whose incoming IDs are kept, everyone else gets a new UUIDv7
+    pub trust: RequestIdTrust,
+    /// Send the ID upstream
+    pub forward: bool,
+    /// Return the ID to the client
+    pub echo: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        RequestIdConfig {
+            header: "X-Request-Id".to_string(),
+            trust: RequestIdTrust::TrustedProxies,
+            forward: true,
+            echo: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestIdTrust {
+    /// Every client
+    All,
+    /// Clients in `proxy_headers.trusted_proxies`
+    TrustedProxies,
+    /// No client, IDs are always generated
+    None,
+}
+
+/// Protocol upgrades such as WebSocket: after a `101` response the connection
+/// is relayed in both directions until either side closes it
+///
+/// ```toml
+/// [upgrades]
+/// protocols = ["websocket"]
+/// idle_timeout_secs = 300
+/// max_message_size = 1048576
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpgradesConfig {
+    /// `Upgrade` protocols passed upstream, any but `h2c` if empty; requests
+    /// for others are forwarded without the upgrade
+    pub protocols: Vec<String>,
+    /// Close upgraded connections after this long without traffic in either
+    /// direction
+    pub idle_timeout_secs: Option<u64>,
+    /// Largest WebSocket frame payload, in bytes
+    pub max_frame_size: Option<u64>,
+    /// Largest WebSocket message, all of its frames together, in bytes
+    pub max_message_size: Option<u64>,
+}
+
+/// The responses the proxy sends itself when a request fails, instead of
+/// empty ones
+///
+/// ```toml
+/// [[error_pages.pages]]
+/// statuses = [502, 503, 504]
+/// content_type = "application/json"
+/// template = '{"status": $status, "error": "$error_code", "request_id": "$request_id"}'
+///
+/// [[error_pages.pages]]
+/// content_type = "text/html"
+/// file = "/etc/pinproxy/error.html"
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorPagesConfig {
+    /// Header carrying the machine-readable error code, such as
+    /// `connect_refused`; none if empty
+    pub code_header: String,
+    /// Of the pages for the status, the first whose content type the client
+    /// accepts is sent, or else the first one
+    pub pages: Vec<ErrorPageConfig>,
+}
+
+impl Default for ErrorPagesConfig {
+    fn default() -> Self {
+        ErrorPagesConfig {
+            code_header: "X-Proxy-Error".to_string(),
+            pages: vec![],
+        }
+    }
+}
+
+/// An error response body, a template of `$status`, `$reason`, `$error_code`,
+/// `$request_id`, `$request_method`, `$request_uri`, `$host`, `$route` and
+/// `$http_*` request headers
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorPageConfig {
+    /// Statuses the page is for, any if empty
+    pub statuses: Vec<u16>,
+    /// Values are escaped for HTML or JSON content types
+    pub content_type: String,
+    pub template: Option<String>,
+    /// File holding the template, read at startup and on reload
+    pub file: Option<String>,
+}
+
+/// The resolver of the hosts forwarded requests and tunnels go to, which
+/// caches answers and connects to the addresses of a host as in RFC 8305
+/// ("Happy Eyeballs")
+///
+/// Reloading the configuration clears the cache and re-reads `hosts_file`.
+///
+/// ```toml
+/// [dns]
+/// nameservers = ["10.0.0.2", "10.0.0.3:5353"]
+/// hosts = { "intranet.example.com" = ["10.1.2.3"] }
+/// negative_ttl_secs = 10
+/// ```
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DnsConfig {
+    /// `ip` or `ip:port`, asked in order; those of `/etc/resolv.conf` if
+    /// empty
+    pub nameservers: Vec<String>,
+    /// Addresses of host names, taking precedence over `hosts_file` and the
+    /// nameservers
+    pub hosts: BTreeMap<String, Vec<IpAddr>>,
+    /// Read at startup and on reload, taking precedence over the
+    /// nameservers; none if empty
+    pub hosts_file: String,
+    /// Bounds on how long answers are cached, whatever their TTL
+    pub min_ttl_secs: u64,
+    pub max_ttl_secs: u64,
+    /// How long failed lookups are cached
+    pub negative_ttl_secs: u64,
+    /// Host names cached at most
+    pub cache_size: usize,
+    /// Of each query to a nameserver
+    pub timeout_ms: u64,
+    /// Queries sent to each nameserver before giving up on it
+    pub attempts: usize,
+    /// How long a connection attempt to one address of a host gets before
+    /// the next address is tried alongside it
+    pub happy_eyeballs_delay_ms: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        DnsConfig {
+            nameservers: vec![],
+            hosts: BTreeMap::new(),
+            hosts_file: "/etc/hosts".to_string(),
+            min_ttl_secs: 1,
+            max_ttl_secs: 300,
+            negative_ttl_secs: 5,
+            cache_size: 10_000,
+            timeout_ms: 2000,
+            attempts: 2,
+            happy_eyeballs_delay_ms: 250,
+        }
+    }
+}
+
+/// Caching of responses as RFC 9111 allows a shared cache to, set up once at
+/// startup
+///
+/// ```toml
+/// [cache]
+/// storage = "disk"
+/// path = "/var/cache/pinproxy"
+/// max_size_mb = 1024
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub storage: CacheStorage,
+    /// Directory of the `disk` storage, kept across restarts
+    pub path: Option<String>,
+    /// Size of all cached responses, beyond which roughly the least
+    /// recently used ones are evicted
+    pub max_size_mb: u64,
+    /// Responses larger than this are not cached
+    pub max_object_size_mb: u64,
+    /// How long a stale response may be served while it is revalidated,
+    /// unless the response says otherwise with `stale-while-revalidate`
+    pub stale_while_revalidate_secs: u32,
+    /// How long a stale response may be served when the upstream fails,
+    /// unless the response says otherwise with `stale-if-error`
+    pub stale_if_error_secs: u32,
+    /// Response header telling whether the response came from the cache,
+    /// e.g. `HIT` or `MISS`
+    pub status_header: String,
+    /// Names of the routes cached, all requests when empty
+    pub routes: Vec<String>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            storage: CacheStorage::Memory,
+            path: None,
+            max_size_mb: 256,
+            max_object_size_mb: 16,
+            stale_while_revalidate_secs: 0,
+            stale_if_error_secs: 60,
+            status_header: "X-Cache-Status".to_string(),
+            routes: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStorage {
+    /// Lost on restart
+    #[default]
+    Memory,
+    /// One file per response under `path`
+    Disk,
+}
+
+/// Spans of requests exported to an OpenTelemetry collector over OTLP, set
+/// up once at startup
+///
+/// ```toml
+/// [tracing]
+/// endpoint = "http://otel-collector:4317"
+/// sample_ratio = 0.1
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingConfig {
+    pub protocol: OtlpProtocol,
+    /// Collector URL, by default `http://localhost:4317` for `grpc` and
+    /// `http://localhost:4318/v1/traces` for `http`, which needs the full
+    /// path
+    pub endpoint: Option<String>,
+    /// `service.name` of the spans
+    pub service_name: String,
+    /// Share of the traces starting at the proxy that are recorded, from 0
+    /// to 1; traces continued from a `traceparent` follow its decision
+    pub sample_ratio: f64,
+    /// Time limit of each export to the collector
+    pub timeout_secs: u64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            protocol: OtlpProtocol::Grpc,
+            endpoint: None,
+            service_name: "pinproxy".to_string(),
+            sample_ratio: 1.0,
+            timeout_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OtlpProtocol {
+    Grpc,
+    /// Protobuf over HTTP
+    Http,
+}
+
+/// A pool of backend servers
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    pub backends: Vec<BackendConfig>,
+    /// How requests are spread over the backends
+    #[serde(default)]
+    pub algorithm: Algorithm,
+    /// What `consistent_hash` hashes on
+    #[serde(default)]
+    pub hash_key: HashKeyConfig,
+    /// Connect to the backends over TLS
+    #[serde(default)]
+    pub tls: bool,
+    /// SNI and certificate name, defaults to the host of each backend
+    pub sni: Option<String>,
+    /// Active health checking, off unless configured
+    pub health_check: Option<HealthCheckConfig>,
+    /// Ejection of backends that fail while proxying
+    #[serde(default)]
+    pub passive_health: PassiveHealthConfig,
+    /// Requests in flight to the whole upstream, further ones get a `503`;
+    /// 0 is unlimited
+    #[serde(default)]
+    pub max_concurrent_requests: usize,
+    /// The HTTP version spoken to the backends
+    #[serde(default)]
+    pub http_version: UpstreamHttpVersion,
+    /// Falling back to the top-level `timeouts`
+    #[serde(default)]
+    pub timeouts: TimeoutsConfig,
+    /// Retries of failed requests, off unless `max_attempts` is raised
+    #[serde(default)]
+    pub retry: RetryConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendConfig {
+    /// `host:port` of the backend, a host name is resolved with the `dns`
+    /// resolver whenever its answer expires and every address it resolves
+    /// to becomes a backend
+    pub address: String,
+    /// Share of traffic relative to the other backends, for `weighted`
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+/// The HTTP version spoken to the backends of an upstream.
+///
+/// gRPC backends need `http2`, and so do their clients.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpstreamHttpVersion {
+    #[default]
+    Http1,
+    /// HTTP/2 only: negotiated over ALPN with TLS backends, with prior
+    /// knowledge (h2c) otherwise
+    Http2,
+    /// HTTP/2 if the backend picks it over ALPN, HTTP/1.1 otherwise; TLS
+    /// backends only
+    Auto,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    /// Each backend in turn, ignoring weights
+    #[default]
+    RoundRobin,
+    /// Round robin in proportion to the backend weights
+    Weighted,
+    /// The backend with the fewest requests in flight, relative to its weight
+    LeastConnections,
+    /// Ketama hashing of `hash_key`, so the same key keeps the same backend
+    ConsistentHash,
+}
+
+/// `"client_ip"`, `{ header = "name" }` or `{ cookie = "name" }`
+///
+/// Requests without the header or cookie are hashed on the client IP.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKeyConfig {
+    #[default]
+    ClientIp,
+    Header(String),
+    Cookie(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    #[serde(rename = "type")]
+    pub kind: HealthCheckKind,
+    /// Path requested by `http` checks, which expect a `200`
+    pub path: String,
+    /// `Host` header of `http` checks, defaults to the upstream SNI or the
+    /// host of the first backend
+    pub host: Option<String>,
+    pub interval_secs: u64,
+    pub timeout_ms: u64,
+    /// Consecutive successes that bring an unhealthy backend back
+    pub healthy_threshold: usize,
+    /// Consecutive failures that take a backend out
+    pub unhealthy_threshold: usize,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            kind: HealthCheckKind::Tcp,
+            path: "/".to_string(),
+            host: None,
+            interval_secs: 5,
+            timeout_ms: 1000,
+            healthy_threshold: 1,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthCheckKind {
+    /// A TCP connect, plus a TLS handshake for TLS upstreams
+    #[default]
+    Tcp,
+    /// A `GET` of `path`
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveHealthConfig {
+    /// Consecutive proxy errors that eject a backend, 0 disables ejection
+    pub max_failures: usize,
+    /// How long an ejected backend is kept out of rotation
+    pub ejection_secs: u64,
+}
+
+impl Default for PassiveHealthConfig {
+    fn default() -> Self {
+        PassiveHealthConfig {
+            max_failures: 5,
+            ejection_secs: 30,
+        }
+    }
+}
+
+/// Timeouts of the exchange with an upstream, in milliseconds
+///
+/// Unset ones fall back to those of the upstream, then to the top-level
+/// ones, then to the defaults: 10s to connect and to shake hands, 60s per
+/// read and write, no total limit.
+///
+/// ```toml
+/// [upstreams.billing]
+/// backends = [{ address = "10.0.0.5:8080" }]
+/// timeouts = { connect_ms = 2000, read_ms = 30000, total_ms = 60000 }
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// Establishing the TCP connection
+    pub connect_ms: Option<u64>,
+    /// The TLS handshake, once connected
+    pub tls_handshake_ms: Option<u64>,
+    /// Each wait for the upstream to send something, the response header
+    /// included
+    pub read_ms: Option<u64>,
+    /// Each wait for the upstream to take the request
+    pub write_ms: Option<u64>,
+    /// The whole exchange, from the first connection attempt to the end of
+    /// the response and across retries
+    pub total_ms: Option<u64>,
+    /// How long an unused connection is kept for reuse
+    pub idle_ms: Option<u64>,
+}
+
+/// Retries of requests that failed, each to a backend picked anew
+///
+/// ```toml
+/// [upstreams.billing]
+/// backends = [{ address = "10.0.0.5:8080" }, { address = "10.0.0.6:8080" }]
+/// retry = { max_attempts = 3, retry_on = ["connect_failure", "503"] }
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Tries per request, the first one included; 1 never retries
+    pub max_attempts: usize,
+    /// Methods retried once the request was sent, connection failures are
+    /// retried whatever the method
+    pub methods: Vec<String>,
+    /// The failures that are retried
+    pub retry_on: Vec<RetryOn>,
+    /// Upper bound of the random wait before the first retry, doubled for
+    /// each further one
+    pub backoff_base_ms: u64,
+    /// Cap of the wait before a retry
+    pub backoff_max_ms: u64,
+    /// Retries allowed as a share of the requests to the upstream, so a
+    /// failing upstream is not buried in retries
+    pub budget_ratio: f64,
+    /// Retries per second allowed whatever the number of requests
+    pub budget_min_per_sec: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 1,
+            methods: ["GET", "HEAD", "OPTIONS", "PUT", "DELETE"]
+                .map(String::from)
+                .to_vec(),
+            retry_on: vec![
+                RetryOn::ConnectFailure,
+                RetryOn::Reset,
+                RetryOn::BadGateway,
+                RetryOn::ServiceUnavailable,
+            ],
+            backoff_base_ms: 25,
+            backoff_max_ms: 1000,
+            budget_ratio: 0.2,
+            budget_min_per_sec: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// The connection or TLS handshake failed or timed out, nothing was sent
+    ConnectFailure,
+    /// The upstream closed or reset the connection before responding
+    Reset,
+    /// A `502` response
+    #[serde(rename = "502")]
+    BadGateway,
+    /// A `503` response
+    #[serde(rename = "503")]
+    ServiceUnavailable,
+}
+
+/// A route, matching requests on all of the criteria that are set
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Name used in logs, defaults to the route's position
+    pub name: Option<String>,
+    /// Host name, `*.example.com` matches any subdomain
+    pub host: Option<String>,
+    /// Regular expression the whole host name must match
+    pub host_regex: Option<String>,
+    pub path_prefix: Option<String>,
+    /// Regular expression the path must match
+    pub path_regex: Option<String>,
+    /// Allowed methods, any method if empty
+    pub methods: Vec<String>,
+    /// Headers that must be present, values are regular expressions that
+    /// must match the whole header value
+    pub headers: BTreeMap<String, String>,
+    /// Name of the upstream in `upstreams`
+    pub upstream: String,
+    /// Challenge requests for proxy credentials if `auth` is configured,
+    /// the default; `false` serves the route to everyone
+    pub auth: Option<bool>,
+    /// How the request URI is rewritten for the upstream
+    pub rewrite: Option<RewriteConfig>,
+    /// Rules applied in order to requests sent to the upstream
+    pub request_headers: Vec<HeaderRuleConfig>,
+    /// Rules applied in order to responses sent to the client
+    pub response_headers: Vec<HeaderRuleConfig>,
+    /// Falling back to those of the upstream
+    pub timeouts: TimeoutsConfig,
+    /// Tried before the top-level `error_pages`
+    pub error_pages: Vec<ErrorPageConfig>,
+}
+
+/// A rewrite of the request URI, applied in the order of the fields
+///
+/// ```toml
+/// [[routes]]
+/// path_prefix = "/api/billing/"
+/// upstream = "billing"
+/// rewrite = { strip_prefix = "/api/billing", query = { remove = ["debug"] } }
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RewriteConfig {
+    /// Removed from the start of the path when it matches whole path segments
+    pub strip_prefix: Option<String>,
+    /// Regular expression replaced in the path
+    pub regex: Option<String>,
+    /// Replacement of `regex`, which can refer to captures as `$1`
+    pub replacement: Option<String>,
+    /// Put in front of the path
+    pub add_prefix: Option<String>,
+    pub query: QueryRewriteConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueryRewriteConfig {
+    /// Drop the whole query string from the request
+    pub clear: bool,
+    /// Parameters removed
+    pub remove: Vec<String>,
+    /// Parameters set, replacing any existing values
+    pub set: BTreeMap<String, String>,
+}
+
+/// A header rewrite rule
+///
+/// ```toml
+/// request_headers = [
+///     { action = "set", name = "X-Client-Ip", value = "$remote_addr" },
+///     { action = "remove", name = "Cookie" },
+/// ]
+/// response_headers = [
+///     { action = "rewrite", name = "Location", regex = "^http://", value = "https://" },
+/// ]
+/// ```
+///
+/// Values may use the variables `remote_addr`, `remote_user`, `host`,
+/// `request_method`, `request_uri`, `route`, `upstream`, `upstream_addr` and
+/// `http_<header>` of the client request, as `$name` or `${name}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRuleConfig {
+    pub action: HeaderAction,
+    pub name: String,
+    /// The value for `add` and `set`, the replacement for `rewrite`, which
+    /// can refer to captures as `$1`
+    pub value: Option<String>,
+    /// What `rewrite` replaces in every value of the header
+    pub regex: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderAction {
+    /// Adds a value, keeping the existing ones
+    Add,
+    /// Replaces all values
+    Set,
+    Remove,
+    Rewrite,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnmatchedConfig {
+    pub action: UnmatchedAction,
+    /// Response status for `reject`
+    pub status: Option<u16>,
+    /// Upstream for `upstream`
+    pub upstream: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnmatchedAction {
+    /// Forward to the request's own target, as a forward proxy
+    #[default]
+    Forward,
+    /// Respond with `status` (default 404)
+    Reject,
+    /// Send to a fixed upstream
+    Upstream,
+}
+
+impl Config {
+    /// Reads and parses the configuration file at `path`.
+    pub fn load(path: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .or_err_with(FileReadError, || format!("reading config file {path}"))?;
+        let yaml = matches!(
+            Path::new(path).extension().and_then(|e| e.to_str()),
+            Some("yaml" | "yml")
+        );
+        let config: Config = if yaml {
+            serde_yaml::from_str(&content)
+                .or_err_with(CONFIG_ERROR, || format!("parsing config file {path}"))?
+        } else {
+            toml::from_str(&content)
+                .or_err_with(CONFIG_ERROR, || format!("parsing config file {path}"))?
+        };
+        config
+            .validate()
+            .map_err(|e| e.more_context(format!("config file {path}")))?;
+        Ok(config)
+    }
+
+    /// Checks what parsing cannot: that the settings of each listener fit
+    /// together.
+    pub fn validate(&self) -> Result<()> {
+        for listener in &self.listeners {
+            listener.listening()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn listeners(config: &str) -> Config {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn test_listening() {
+        let config = listeners(
+            r#"
+            [[listeners]]
+            address = "0.0.0.0:8080"
+
+            [[listeners]]
+            address = "0.0.0.0:8081"
+            h2c = true
+
+            [[listeners]]
+            address = "0.0.0.0:8443"
+            tls_cert = "cert.pem"
+            tls_key = "key.pem"
+
+            [[listeners]]
+            address = "0.0.0.0:8444"
+            tls_cert = "cert.pem"
+            tls_key = "key.pem"
+            http2 = false
+            "#,
+        );
+        config.validate().unwrap();
+        let listening: Vec<_> = config.listeners.iter().map(|l| l.listening().unwrap()).collect();
+        let tls = |http2| Listening::Tls {
+            cert: "cert.pem",
+            key: "key.pem",
+            http2,
+        };
+        assert_eq!(listening, [Listening::Plain, Listening::H2c, tls(true), tls(false)]);
+    }
+
+    #[test]
+    fn test_invalid_listeners() {
+        let invalid = [
+            (
+                "address = \"0.0.0.0:8443\"\ntls_cert = \"c.pem\"\ntls_key = \"k.pem\"\nh2c = true",
+                "h2c is for plaintext listeners",
+            ),
+            ("address = \"0.0.0.0:8443\"\ntls_cert = \"c.pem\"", "needs both"),
+            ("address = \"0.0.0.0:8443\"\ntls_key = \"k.pem\"", "needs both"),
+        ];
+        for (listener, message) in invalid {
+            let config = listeners(&format!("[[listeners]]\n{listener}"));
+            let e = config.validate().unwrap_err();
+            assert_eq!(e.etype(), &CONFIG_ERROR);
+            assert!(e.to_string().contains(message), "{listener}: {e}");
+            assert!(e.to_string().contains("0.0.0.0:8443"), "{e}");
+        }
+        // Without listeners, those from the command line are used
+        assert!(listeners("").validate().is_ok());
+    }
+}
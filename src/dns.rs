@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use futures::stream::{FuturesUnordered, StreamExt};
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, ResolveHosts, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::proto::ProtoErrorKind;
+use hickory_resolver::{ResolveError, TokioResolver};
+use pingora::connectors::L4Connect;
+use pingora::prelude::*;
+use pingora::protocols::l4::socket::SocketAddr as PeerAddr;
+use pingora::protocols::l4::stream::Stream;
+use tokio::net::TcpStream;
+
+use crate::authority::Host;
+use crate::config::{DnsConfig, CONFIG_ERROR};
+use crate::metrics;
+
+/// Errors resolving the destination of a forwarded request
+pub const DNS_ERROR: ErrorType = ErrorType::Custom("DnsError");
+
+const DNS_PORT: u16 = 53;
+
+/// Resolves the hosts forwarded requests and tunnels go to: from the
+/// configured hosts and the hosts file, or else by asking the nameservers,
+/// whose answers are cached for their TTL and failures for a while.
+pub struct Resolver {
+    /// Lowercase names without a trailing dot
+    hosts: HashMap<String, Vec<IpAddr>>,
+    nameservers: TokioResolver,
+    cache: Mutex<HashMap<String, CacheEntry>>,
+    cache_size: usize,
+    min_ttl: Duration,
+    max_ttl: Duration,
+    negative_ttl: Duration,
+    happy_eyeballs_delay: Duration,
+}
+
+/// The addresses of a name, or why there are none
+type Answer = std::result::Result<Vec<IpAddr>, Failure>;
+
+struct CacheEntry {
+    answer: Answer,
+    expires: Instant,
+}
+
+/// Why a host name could not be resolved
+#[derive(Debug, Clone)]
+struct Failure {
+    /// The label of the failure metrics
+    reason: &'static str,
+    message: String,
+}
+
+impl Resolver {
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        if config.min_ttl_secs > config.max_ttl_secs {
+            return Error::e_explain(CONFIG_ERROR, "dns min_ttl_secs is above max_ttl_secs");
+        }
+        if config.timeout_ms == 0 || config.attempts == 0 {
+            return Error::e_explain(CONFIG_ERROR, "dns timeout_ms and attempts must be positive");
+        }
+
+        let mut hosts = HashMap::new();
+        if !config.hosts_file.is_empty() {
+            hosts = read_hosts_file(&config.hosts_file)?;
+        }
+        for (name, addrs) in &config.hosts {
+            hosts.insert(normalize(name), addrs.clone());
+        }
+
+        let (resolver_config, mut options) = if config.nameservers.is_empty() {
+            hickory_resolver::system_conf::read_system_conf()
+                .or_err(CONFIG_ERROR, "reading the nameservers of /etc/resolv.conf")?
+        } else {
+            let mut nameservers = vec![];
+            for nameserver in &config.nameservers {
+                let addr = nameserver
+                    .parse::<SocketAddr>()
+                    .or_else(|_| nameserver.parse().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+                    .or_err_with(CONFIG_ERROR, || format!("invalid nameserver {nameserver:?}"))?;
+                // TCP only for answers too large for UDP
+                nameservers.push(NameServerConfig::new(addr, Protocol::Udp));
+                nameservers.push(NameServerConfig::new(addr, Protocol::Tcp));
+            }
+            let resolver_config = ResolverConfig::from_parts(None, vec![], nameservers);
+            (resolver_config, ResolverOpts::default())
+        };
+        options.timeout = Duration::from_millis(config.timeout_ms);
+        options.attempts = config.attempts;
+        options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        // Hosts and caching are done here
+        options.use_hosts_file = ResolveHosts::Never;
+        options.cache_size = 0;
+        let nameservers =
+            TokioResolver::builder_with_config(resolver_config, TokioConnectionProvider::default())
+                .with_options(options)
+                .build();
+
+        Ok(Resolver {
+            hosts,
+            nameservers,
+            cache: Mutex::new(HashMap::new()),
+            cache_size: config.cache_size,
+            min_ttl: Duration::from_secs(config.min_ttl_secs),
+            max_ttl: Duration::from_secs(config.max_ttl_secs),
+            negative_ttl: Duration::from_secs(config.negative_ttl_secs),
+            happy_eyeballs_delay: Duration::from_millis(config.happy_eyeballs_delay_ms),
+        })
+    }
+
+    /// Resolves the addresses of a destination, in the order they are to be
+    /// connected to.
+    pub async fn resolve(&self, host: &Host, port: u16) -> Result<Vec<SocketAddr>> {
+        let name = match host {
+            Host::Ipv4(ip) => return Ok(vec![SocketAddr::new((*ip).into(), port)]),
+            Host::Ipv6(ip) => return Ok(vec![SocketAddr::new((*ip).into(), port)]),
+            Host::Domain(name) => normalize(name),
+        };
+        let start = Instant::now();
+        let (source, answer) = self.lookup(&name).await;
+        metrics::dns_lookup(source, start.elapsed());
+        match answer {
+            Ok(addrs) => Ok(addrs.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()),
+            Err(failure) => {
+                metrics::dns_failure(failure.reason);
+                Error::e_explain(DNS_ERROR, format!("resolving {name}: {}", failure.message))
+            }
+        }
+    }
+
+    /// The addresses of a name and where they came from
+    async fn lookup(&self, name: &str) -> (&'static str, Answer) {
+        if let Some(addrs) = self.hosts.get(name) {
+            return ("hosts", Ok(addrs.clone()));
+        }
+        if let Some(entry) = self.cache.lock().unwrap().get(name) {
+            if entry.expires > Instant::now() {
+                return ("cache", entry.answer.clone());
+            }
+        }
+
+        let (answer, ttl) = match self.nameservers.lookup_ip(name).await {
+            Ok(lookup) => {
+                let addrs = happy_eyeballs_order(lookup.iter());
+                let ttl = lookup.valid_until().saturating_duration_since(Instant::now());
+                (Ok(addrs), ttl.clamp(self.min_ttl, self.max_ttl))
+            }
+            Err(e) => (Err(failure(&e)), self.negative_ttl),
+        };
+        let answer = match answer {
+            Ok(addrs) if addrs.is_empty() => Err(Failure {
+                reason: "not_found",
+                message: "no addresses".to_string(),
+            }),
+            answer => answer,
+        };
+        if !ttl.is_zero() && self.cache_size > 0 {
+            self.store(name, &answer, ttl);
+        }
+        ("nameserver", answer)
+    }
+
+    fn store(&self, name: &str, answer: &Answer, ttl: Duration) {
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.cache_size {
+            cache.retain(|_, entry| entry.expires > now);
+        }
+        if cache.len() >= self.cache_size {
+            // Still full of live entries, so make room at random
+            if let Some(evicted) = cache.keys().next().cloned() {
+                cache.remove(&evicted);
+            }
+        }
+        let entry = CacheEntry {
+            answer: answer.clone(),
+            expires: now + ttl,
+        };
+        cache.insert(name.to_string(), entry);
+    }
+
+    /// Connects to the first of the addresses of a host that accepts, racing
+    /// them as in RFC 8305.
+    pub async fn connect(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        happy_eyeballs(addrs, self.happy_eyeballs_delay).await
+    }
+
+    /// Has pingora connect to the addresses of a host as [`Resolver::connect`]
+    /// does, each try of all of them bounded by `timeout`.
+    pub fn connector(
+        &self,
+        addrs: &[SocketAddr],
+        timeout: Option<Duration>,
+    ) -> Arc<dyn L4Connect + Send + Sync> {
+        Arc::new(HappyEyeballs {
+            addrs: addrs.to_vec(),
+            delay: self.happy_eyeballs_delay,
+            timeout,
+        })
+    }
+}
+
+/// Names are looked up lowercase and without a trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn failure(e: &ResolveError) -> Failure {
+    let reason = if e.is_nx_domain() || e.is_no_records_found() {
+        "not_found"
+    } else if e.proto().is_some_and(|e| matches!(e.kind(), ProtoErrorKind::Timeout)) {
+        "timeout"
+    } else {
+        "error"
+    };
+    Failure {
+        reason,
+        message: e.to_string(),
+    }
+}
+
+/// Alternates between IPv6 and IPv4 addresses, IPv6 first, so that a broken
+/// address family costs at most one connection attempt (RFC 8305 section 4).
+fn happy_eyeballs_order(addrs: impl Iterator<Item = IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) = addrs.partition(IpAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Reads a hosts file (`/etc/hosts`), which is fine not to exist.
+fn read_hosts_file(path: &str) -> Result<HashMap<String, Vec<IpAddr>>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Error::e_because(FileReadError, format!("reading hosts file {path}"), e),
+    };
+    let mut hosts: HashMap<String, Vec<IpAddr>> = HashMap::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        // Lines of anything but an address, such as zone-scoped ones, are skipped
+        let Some(Ok(ip)) = fields.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        for name in fields {
+            let addrs = hosts.entry(normalize(name)).or_default();
+            if !addrs.contains(&ip) {
+                addrs.push(ip);
+            }
+        }
+    }
+    Ok(hosts)
+}
+
+/// Connects to the first of `addrs` that accepts, starting an attempt on the
+/// next address whenever the last one has neither succeeded within `delay`
+/// nor failed (RFC 8305 section 5).
+async fn happy_eyeballs(addrs: &[SocketAddr], delay: Duration) -> io::Result<TcpStream> {
+    let mut next = addrs.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        match next.next() {
+            Some(addr) => attempts.push(TcpStream::connect(*addr)),
+            None if attempts.is_empty() => {
+                return Err(last_error.unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::AddrNotAvailable, "no addresses to connect to")
+                }));
+            }
+            None => {}
+        }
+        let more = next.len() > 0;
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            },
+            _ = tokio::time::sleep(delay), if more => {}
+        }
+    }
+}
+
+/// A pingora connector racing the addresses of a host
+#[derive(Debug)]
+struct HappyEyeballs {
+    addrs: Vec<SocketAddr>,
+    delay: Duration,
+    timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl L4Connect for HappyEyeballs {
+    async fn connect(&self, _addr: &PeerAddr) -> Result<Stream> {
+        let connect = happy_eyeballs(&self.addrs, self.delay);
+        let result = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+            None => connect.await,
+        };
+        let stream = result.map_err(|e| {
+            let etype = match e.kind() {
+                io::ErrorKind::ConnectionRefused => ConnectRefused,
+                io::ErrorKind::TimedOut => ConnectTimedout,
+                _ => ConnectError,
+            };
+            let addrs: Vec<String> = self.addrs.iter().map(SocketAddr::to_string).collect();
+            Error::because(etype, format!("connecting to any of {}", addrs.join(", ")), e)
+        })?;
+        Ok(stream.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::{A, AAAA};
+    use hickory_resolver::proto::rr::{RData, Record, RecordType};
+    use tokio::net::{TcpListener, UdpSocket};
+
+    use super::*;
+
+    /// A nameserver answering from a fixed zone, and NXDOMAIN for any other
+    /// name
+    struct StubDns {
+        addr: SocketAddr,
+        queries: Arc<AtomicUsize>,
+    }
+
+    impl StubDns {
+        async fn start(zone: &[(&str, &str)], ttl: u32) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let zone: Vec<(String, IpAddr)> = zone
+                .iter()
+                .map(|(name, ip)| (format!("{name}."), ip.parse().unwrap()))
+                .collect();
+            let queries = Arc::new(AtomicUsize::new(0));
+            let counter = queries.clone();
+            tokio::spawn(async move {
+                let mut buf = [0; 512];
+                while let Ok((len, client)) = socket.recv_from(&mut buf).await {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let request = Message::from_vec(&buf[..len]).unwrap();
+                    let query = request.queries()[0].clone();
+                    let name = query.name().to_lowercase().to_string();
+                    let mut response = Message::new();
+                    response
+                        .set_id(request.id())
+                        .set_message_type(MessageType::Response)
+                        .set_recursion_available(true);
+                    if !zone.iter().any(|(known, _)| *known == name) {
+                        response.set_response_code(ResponseCode::NXDomain);
+                    }
+                    for (_, ip) in zone.iter().filter(|(known, _)| *known == name) {
+                        let rdata = match (ip, query.query_type()) {
+                            (IpAddr::V4(ip), RecordType::A) => RData::A(A(*ip)),
+                            (IpAddr::V6(ip), RecordType::AAAA) => RData::AAAA(AAAA(*ip)),
+                            _ => continue,
+                        };
+                        response.add_answer(Record::from_rdata(query.name().clone(), ttl, rdata));
+                    }
+                    response.add_query(query);
+                    let _ = socket.send_to(&response.to_vec().unwrap(), client).await;
+                }
+            });
+            StubDns { addr, queries }
+        }
+
+        fn queries(&self) -> usize {
+            self.queries.load(Ordering::SeqCst)
+        }
+
+        fn resolver(&self, config: DnsConfig) -> Resolver {
+            Resolver::new(&DnsConfig {
+                nameservers: vec![self.addr.to_string()],
+                ..config
+            })
+            .unwrap()
+        }
+    }
+
+    fn config() -> DnsConfig {
+        DnsConfig {
+            hosts_file: String::new(),
+            ..Default::default()
+        }
+    }
+
+    fn domain(name: &str) -> Host {
+        Host::Domain(name.to_string())
+    }
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_resolve_and_cache() {
+        let zone = [
+            ("www.example.test", "192.0.2.1"),
+            ("www.example.test", "192.0.2.2"),
+            ("www.example.test", "2001:db8::1"),
+        ];
+        let stub = StubDns::start(&zone, 60).await;
+        let resolver = stub.resolver(config());
+
+        let expected = addrs(&["[2001:db8::1]:80", "192.0.2.1:80", "192.0.2.2:80"]);
+        let resolved = resolver.resolve(&domain("www.example.test"), 80).await.unwrap();
+        assert_eq!(resolved, expected);
+        let queries = stub.queries();
+        assert!(queries > 0);
+
+        let resolved = resolver.resolve(&domain("WWW.example.test."), 80).await.unwrap();
+        assert_eq!(resolved, expected);
+        assert_eq!(stub.queries(), queries);
+    }
+
+    #[tokio::test]
+    async fn test_max_ttl() {
+        let stub = StubDns::start(&[("www.example.test", "192.0.2.1")], 60).await;
+        let resolver = stub.resolver(DnsConfig {
+            min_ttl_secs: 0,
+            max_ttl_secs: 0,
+            ..config()
+        });
+
+        resolver.resolve(&domain("www.example.test"), 80).await.unwrap();
+        let queries = stub.queries();
+        resolver.resolve(&domain("www.example.test"), 80).await.unwrap();
+        assert_eq!(stub.queries(), queries * 2);
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let stub = StubDns::start(&[], 60).await;
+        let resolver = stub.resolver(config());
+
+        let e = resolver.resolve(&domain("missing.example.test"), 80).await.unwrap_err();
+        assert_eq!(e.etype(), &DNS_ERROR);
+        let queries = stub.queries();
+        assert!(queries > 0);
+
+        let e = resolver.resolve(&domain("missing.example.test"), 80).await.unwrap_err();
+        assert_eq!(e.etype(), &DNS_ERROR);
+        assert_eq!(stub.queries(), queries);
+    }
+
+    #[tokio::test]
+    async fn test_hosts() {
+        let stub = StubDns::start(&[("www.example.test", "192.0.2.1")], 60).await;
+        let hosts_file =
+            std::env::temp_dir().join(format!("pinproxy-hosts-{}", std::process::id()));
+        std::fs::write(
+            &hosts_file,
+            "# comment\n127.0.0.1 localhost\n10.0.0.1 db.internal DB2.internal # primary\n\
+             10.0.0.2 www.example.test\nfe80::1%eth0 scoped\n",
+        )
+        .unwrap();
+        let mut config = DnsConfig {
+            hosts_file: hosts_file.to_str().unwrap().to_string(),
+            ..config()
+        };
+        config.hosts.insert("DB.internal".to_string(), vec!["10.9.9.9".parse().unwrap()]);
+        let resolver = stub.resolver(config);
+        std::fs::remove_file(&hosts_file).unwrap();
+
+        let resolved = resolver.resolve(&domain("db.internal"), 5432).await.unwrap();
+        assert_eq!(resolved, addrs(&["10.9.9.9:5432"]));
+        let resolved = resolver.resolve(&domain("db2.internal"), 5432).await.unwrap();
+        assert_eq!(resolved, addrs(&["10.0.0.1:5432"]));
+        let resolved = resolver.resolve(&domain("www.example.test"), 5432).await.unwrap();
+        assert_eq!(resolved, addrs(&["10.0.0.2:5432"]));
+        assert_eq!(stub.queries(), 0);
+        // Not in the hosts file, so asked for
+        assert!(resolver.resolve(&domain("scoped"), 5432).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ip_literals() {
+        let stub = StubDns::start(&[], 60).await;
+        let resolver = stub.resolver(config());
+        let host = Host::Ipv6("2001:db8::1".parse().unwrap());
+        let resolved = resolver.resolve(&host, 443).await.unwrap();
+        assert_eq!(resolved, addrs(&["[2001:db8::1]:443"]));
+        assert_eq!(stub.queries(), 0);
+    }
+
+    #[test]
+    fn test_happy_eyeballs_order() {
+        let ips = ["192.0.2.1", "192.0.2.2", "192.0.2.3", "2001:db8::1", "2001:db8::2"];
+        let ordered = happy_eyeballs_order(ips.iter().map(|ip| ip.parse().unwrap()));
+        let expected = ["2001:db8::1", "192.0.2.1", "2001:db8::2", "192.0.2.2", "192.0.2.3"];
+        let expected: Vec<IpAddr> = expected.iter().map(|ip| ip.parse().unwrap()).collect();
+        assert_eq!(ordered, expected);
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let listening = listener.local_addr().unwrap();
+        // A port nothing listens on anymore
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let delay = Duration::from_secs(10);
+        let start = Instant::now();
+        let stream = happy_eyeballs(&[refused, listening], delay).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listening);
+        // A failed attempt moves on to the next address right away
+        assert!(start.elapsed() < delay);
+
+        let e = happy_eyeballs(&[refused, refused], delay).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
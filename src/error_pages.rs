@@ -0,0 +1,494 @@
+use bytes::Bytes;
+use http::header::{HeaderName, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use http::StatusCode;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use serde_json::Value;
+
+use crate::config::{ErrorPageConfig, ErrorPagesConfig, CONFIG_ERROR};
+use crate::dns::DNS_ERROR;
+use crate::template::{header, Segment, Template};
+use crate::timeouts::TOTAL_TIMEOUT;
+use crate::upstream::NO_HEALTHY_BACKEND;
+
+/// A value of the error page templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    Status,
+    Reason,
+    ErrorCode,
+    RequestId,
+    RequestMethod,
+    RequestUri,
+    Host,
+    Route,
+}
+
+const VARIABLES: &[(&str, Variable)] = &[
+    ("status", Variable::Status),
+    ("reason", Variable::Reason),
+    ("error_code", Variable::ErrorCode),
+    ("request_id", Variable::RequestId),
+    ("request_method", Variable::RequestMethod),
+    ("request_uri", Variable::RequestUri),
+    ("host", Variable::Host),
+    ("route", Variable::Route),
+];
+
+/// How values are escaped in a body of some content type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    Html,
+    Json,
+    None,
+}
+
+impl Escape {
+    fn for_content_type(content_type: &str) -> Self {
+        let subtype = content_type.split_once('/').map_or("", |(_, subtype)| subtype);
+        match subtype {
+            "json" => Escape::Json,
+            _ if subtype.ends_with("+json") => Escape::Json,
+            "html" | "xml" => Escape::Html,
+            _ if subtype.ends_with("+xml") => Escape::Html,
+            _ => Escape::None,
+        }
+    }
+
+    fn apply(self, value: &str, body: &mut String) {
+        match self {
+            Escape::Html => {
+                for c in value.chars() {
+                    match c {
+                        '&' => body.push_str("&amp;"),
+                        '<' => body.push_str("&lt;"),
+                        '>' => body.push_str("&gt;"),
+                        '"' => body.push_str("&quot;"),
+                        '\'' => body.push_str("&#39;"),
+                        c => body.push(c),
+                    }
+                }
+            }
+            Escape::Json => {
+                let quoted = Value::from(value).to_string();
+                body.push_str(&quoted[1..quoted.len() - 1]);
+            }
+            Escape::None => body.push_str(value),
+        }
+    }
+}
+
+/// A templated body for error responses of some statuses
+pub struct ErrorPage {
+    /// Any if empty
+    statuses: Vec<u16>,
+    content_type: String,
+    escape: Escape,
+    template: Template<Variable>,
+}
+
+impl ErrorPage {
+    fn new(config: &ErrorPageConfig) -> Result<Self> {
+        if let Some(status) = config.statuses.iter().find(|s| !(400..600).contains(*s)) {
+            return Error::e_explain(
+                CONFIG_ERROR,
+                format!("error page status {status} is not an error status"),
+            );
+        }
+        let content_type = config.content_type.trim().to_ascii_lowercase();
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        if !essence.contains('/') {
+            return Error::e_explain(
+                CONFIG_ERROR,
+                format!("invalid error page content_type {:?}", config.content_type),
+            );
+        }
+        let template = match (&config.template, &config.file) {
+            (Some(template), None) => template.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .or_err_with(FileReadError, || format!("reading error page {path}"))?,
+            _ => {
+                return Error::e_explain(
+                    CONFIG_ERROR,
+                    "an error page needs either a template or a file",
+                )
+            }
+        };
+        Ok(ErrorPage {
+            statuses: config.statuses.clone(),
+            escape: Escape::for_content_type(essence),
+            content_type,
+            template: Template::parse(&template, VARIABLES)
+                .map_err(|e| e.more_context("error page template"))?,
+        })
+    }
+
+    /// Compiles the error pages of a route or of the whole proxy.
+    pub fn all(configs: &[ErrorPageConfig]) -> Result<Vec<Self>> {
+        configs.iter().map(ErrorPage::new).collect()
+    }
+
+    fn applies(&self, status: u16) -> bool {
+        self.statuses.is_empty() || self.statuses.contains(&status)
+    }
+
+    fn render(&self, error: &ErrorResponse) -> String {
+        let mut body = String::new();
+        for segment in self.template.segments() {
+            let value = match segment {
+                Segment::Literal(literal) | Segment::Numbered(literal) => {
+                    body.push_str(literal);
+                    continue;
+                }
+                Segment::Variable(variable) => error.value(*variable),
+                Segment::Header(name) => header(error.req, name),
+            };
+            self.escape.apply(value.as_deref().unwrap_or_default(), &mut body);
+        }
+        body
+    }
+}
+
+/// What an error response is rendered from
+pub struct ErrorResponse<'a> {
+    pub status: u16,
+    /// Machine-readable, such as `connect_refused`
+    pub code: &'a str,
+    pub request_id: &'a str,
+    pub req: &'a RequestHeader,
+    pub route: Option<&'a str>,
+}
+
+impl ErrorResponse<'_> {
+    fn value(&self, variable: Variable) -> Option<String> {
+        match variable {
+            Variable::Status => Some(self.status.to_string()),
+            Variable::Reason => StatusCode::from_u16(self.status)
+                .ok()
+                .and_then(|s| s.canonical_reason())
+                .map(str::to_string),
+            Variable::ErrorCode => Some(self.code.to_string()),
+            Variable::RequestId => Some(self.request_id.to_string()),
+            Variable::RequestMethod => Some(self.req.method.to_string()),
+            Variable::RequestUri => Some(String::from_utf8_lossy(self.req.raw_path()).into_owned()),
+            Variable::Host => {
+                header(self.req, "host").or_else(|| self.req.uri.host().map(str::to_string))
+            }
+            Variable::Route => self.route.map(str::to_string),
+        }
+    }
+}
+
+/// The responses sent when a request cannot be proxied
+pub struct ErrorPages {
+    code_header: Option<String>,
+    pages: Vec<ErrorPage>,
+}
+
+impl ErrorPages {
+    pub fn new(config: &ErrorPagesConfig) -> Result<Self> {
+        let code_header = Some(config.code_header.clone()).filter(|name| !name.is_empty());
+        if let Some(name) = &code_header {
+            HeaderName::from_bytes(name.as_bytes()).or_err_with(CONFIG_ERROR, || {
+                format!("invalid error_pages code_header {name:?}")
+            })?;
+        }
+        Ok(ErrorPages {
+            code_header,
+            pages: ErrorPage::all(&config.pages)?,
+        })
+    }
+
+    /// The error response to a request, with the page of `route_pages` or of
+    /// these pages that fits the status and the client's `Accept` header, or
+    /// an empty body if none does.
+    pub fn response(
+        &self,
+        route_pages: &[ErrorPage],
+        error: &ErrorResponse,
+    ) -> Result<(ResponseHeader, Bytes)> {
+        let mut pages = route_pages
+            .iter()
+            .chain(&self.pages)
+            .filter(|page| page.applies(error.status));
+        let first = pages.clone().next();
+        let page = pages
+            .find(|page| accepts(error.req, &page.content_type))
+            .or(first);
+
+        let mut resp = ResponseHeader::build(error.status, Some(5))?;
+        resp.insert_header(CACHE_CONTROL, "private, no-store")?;
+        if let Some(name) = &self.code_header {
+            resp.insert_header(name.clone(), error.code)?;
+        }
+        let body = match page {
+            Some(page) => {
+                resp.insert_header(CONTENT_TYPE, page.content_type.as_str())?;
+                Bytes::from(page.render(error))
+            }
+            None => Bytes::new(),
+        };
+        resp.insert_header(CONTENT_LENGTH, body.len().to_string())?;
+        Ok((resp, body))
+    }
+}
+
+/// Whether the `Accept` header of a request allows a content type, as it
+/// does without one. The most specific range decides, so
+/// `text/html;q=0, */*` refuses HTML.
+fn accepts(req: &RequestHeader, content_type: &str) -> bool {
+    let Some(accept) = header(req, "accept") else {
+        return true;
+    };
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let kind = essence.split('/').next().unwrap_or_default();
+    // Specificity of the best matching range, and whether it allows the type
+    let mut best: Option<(u8, bool)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let media = params.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let specificity = if media == essence {
+            2
+        } else if media.strip_suffix("/*").is_some_and(|k| k == kind) {
+            1
+        } else if media == "*/*" {
+            0
+        } else {
+            continue;
+        };
+        let refused = params.any(|param| {
+            let q = param.trim().strip_prefix("q=");
+            q.and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+        });
+        if best.is_none_or(|(best, _)| specificity > best) {
+            best = Some((specificity, !refused));
+        }
+    }
+    best.is_some_and(|(_, allowed)| allowed)
+}
+
+/// The status a failed request is answered with, 0 if the client is gone,
+/// and the machine-readable code of the error.
+pub fn classify(e: &Error) -> (u16, String) {
+    let (status, code) = match e.etype() {
+        HTTPStatus(status) => return (*status, status_code(*status)),
+        &DNS_ERROR => (502, "dns_error"),
+        &NO_HEALTHY_BACKEND => (503, "no_healthy_backend"),
+        &TOTAL_TIMEOUT => (504, "total_timeout"),
+        ConnectTimedout => (504, "connect_timeout"),
+        ConnectRefused => (502, "connect_refused"),
+        ConnectNoRoute | ConnectError | BindError | SocketError | ConnectProxyFailure => {
+            (502, "connect_error")
+        }
+        TLSHandshakeTimedout => (504, "tls_timeout"),
+        TLSWantX509Lookup | TLSHandshakeFailure | InvalidCert | HandshakeError => {
+            (502, "tls_error")
+        }
+        etype => match (e.esource(), etype) {
+            (ErrorSource::Upstream, ReadTimedout | WriteTimedout) => (504, "upstream_timeout"),
+            (ErrorSource::Upstream, ReadError | WriteError | ConnectionClosed) => {
+                (502, "upstream_closed")
+            }
+            (ErrorSource::Upstream, InvalidHTTPHeader | H1Error | H2Error | InvalidH2) => {
+                (502, "invalid_response")
+            }
+            (ErrorSource::Upstream, _) => (502, "upstream_error"),
+            (ErrorSource::Downstream, ReadError | WriteError | ConnectionClosed) => {
+                (0, "client_closed")
+            }
+            (ErrorSource::Downstream, ReadTimedout | WriteTimedout) => (408, "client_timeout"),
+            (ErrorSource::Downstream, _) => (400, "bad_request"),
+            (ErrorSource::Internal | ErrorSource::Unset, _) => (500, "internal_error"),
+        },
+    };
+    (status, code.to_string())
+}
+
+/// The error code of a status, its reason phrase in snake case.
+fn status_code(status: u16) -> String {
+    let reason = StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("error");
+    reason
+        .chars()
+        .filter_map(|c| match c {
+            ' ' | '-' => Some('_'),
+            c if c.is_ascii_alphanumeric() => Some(c.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ErrorPageConfig;
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/a?b=c&d='e'", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn page(statuses: &[u16], content_type: &str, template: &str) -> ErrorPageConfig {
+        ErrorPageConfig {
+            statuses: statuses.to_vec(),
+            content_type: content_type.to_string(),
+            template: Some(template.to_string()),
+            file: None,
+        }
+    }
+
+    fn render(pages: &ErrorPages, req: &RequestHeader, status: u16) -> (ResponseHeader, String) {
+        let error = ErrorResponse {
+            status,
+            code: "connect_refused",
+            request_id: "id-1",
+            req,
+            route: Some("api"),
+        };
+        let (resp, body) = pages.response(&[], &error).unwrap();
+        (resp, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_classify() {
+        let cases = [
+            (Error::new(HTTPStatus(404)), 404, "not_found"),
+            (Error::new(HTTPStatus(429)), 429, "too_many_requests"),
+            (Error::new(HTTPStatus(599)), 599, "error"),
+            (Error::new(DNS_ERROR), 502, "dns_error"),
+            (Error::new(NO_HEALTHY_BACKEND), 503, "no_healthy_backend"),
+            (Error::new(TOTAL_TIMEOUT), 504, "total_timeout"),
+            (Error::new(ConnectTimedout), 504, "connect_timeout"),
+            (Error::new(ConnectRefused), 502, "connect_refused"),
+            (Error::new(ConnectNoRoute), 502, "connect_error"),
+            (Error::new(TLSHandshakeTimedout), 504, "tls_timeout"),
+            (Error::new(InvalidCert), 502, "tls_error"),
+            (Error::new_up(ReadTimedout), 504, "upstream_timeout"),
+            (Error::new_up(ConnectionClosed), 502, "upstream_closed"),
+            (Error::new_up(InvalidHTTPHeader), 502, "invalid_response"),
+            (Error::new_up(InternalError), 502, "upstream_error"),
+            (Error::new_down(ConnectionClosed), 0, "client_closed"),
+            (Error::new_down(ReadTimedout), 408, "client_timeout"),
+            (Error::new_down(InvalidHTTPHeader), 400, "bad_request"),
+            (Error::new_in(ReadError), 500, "internal_error"),
+            (Error::new(ReadError), 500, "internal_error"),
+        ];
+        for (e, status, code) in cases {
+            assert_eq!(classify(&e), (status, code.to_string()), "{e}");
+        }
+    }
+
+    #[test]
+    fn test_accepts() {
+        let accepts = |accept: &str, content_type| {
+            super::accepts(&request(&[("accept", accept)]), content_type)
+        };
+        assert!(super::accepts(&request(&[]), "text/html"));
+        assert!(accepts("text/html", "text/html; charset=utf-8"));
+        assert!(accepts("TEXT/HTML", "text/html"));
+        assert!(accepts("application/json, text/html;q=0.9", "text/html"));
+        assert!(!accepts("application/json", "text/html"));
+        // Wildcards
+        assert!(accepts("*/*", "application/json"));
+        assert!(accepts("text/*", "text/plain"));
+        assert!(!accepts("text/*", "application/json"));
+        // q=0 refuses, and the most specific range wins
+        assert!(!accepts("text/html;q=0", "text/html"));
+        assert!(!accepts("text/html; q=0.0, */*", "text/html"));
+        assert!(!accepts("text/*;q=0, */*", "text/html"));
+        assert!(accepts("text/*;q=0, text/html", "text/html"));
+        assert!(accepts("*/*;q=0, text/html;q=0.5", "text/html"));
+        assert!(!accepts("*/*;q=0", "text/html"));
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let pages = ErrorPages::new(&ErrorPagesConfig {
+            pages: vec![
+                page(&[404], "text/plain", "not here"),
+                page(&[], "text/html", "<p>$status $reason</p>"),
+                page(&[], "application/json", r#"{"code":"$error_code"}"#),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (resp, body) = render(&pages, &request(&[("accept", "application/json")]), 502);
+        assert_eq!(body, r#"{"code":"connect_refused"}"#);
+        assert_eq!(resp.headers["content-type"], "application/json");
+        assert_eq!(resp.headers["content-length"], body.len().to_string().as_str());
+        assert_eq!(resp.headers["x-proxy-error"], "connect_refused");
+        assert_eq!(resp.headers["cache-control"], "private, no-store");
+
+        // The first page for the status without a matching Accept...
+        let (_, body) = render(&pages, &request(&[("accept", "image/png")]), 502);
+        assert_eq!(body, "<p>502 Bad Gateway</p>");
+        // ...or without one
+        let (_, body) = render(&pages, &request(&[]), 404);
+        assert_eq!(body, "not here");
+        let (_, body) = render(&pages, &request(&[("accept", "text/plain;q=0, */*")]), 404);
+        assert_eq!(body, "<p>404 Not Found</p>");
+
+        // No page, no body
+        let pages = ErrorPages::new(&ErrorPagesConfig {
+            code_header: String::new(),
+            pages: vec![page(&[404], "text/plain", "not here")],
+        })
+        .unwrap();
+        let (resp, body) = render(&pages, &request(&[]), 502);
+        assert_eq!(body, "");
+        assert!(resp.headers.get("content-type").is_none());
+        assert!(resp.headers.get("x-proxy-error").is_none());
+    }
+
+    #[test]
+    fn test_escaping() {
+        let template = "$request_uri $http_x_name $route";
+        let pages = ErrorPages::new(&ErrorPagesConfig {
+            pages: vec![
+                page(&[], "text/html; charset=utf-8", template),
+                page(&[], "application/problem+json", template),
+                page(&[], "text/plain", template),
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        let name = ("x-name", r#"<b title="x">y</b>"#);
+
+        let (_, body) = render(&pages, &request(&[("accept", "text/html"), name]), 502);
+        assert_eq!(
+            body,
+            "/a?b=c&amp;d=&#39;e&#39; &lt;b title=&quot;x&quot;&gt;y&lt;/b&gt; api"
+        );
+        let accept = ("accept", "application/problem+json");
+        let (_, body) = render(&pages, &request(&[accept, name]), 502);
+        assert_eq!(body, r#"/a?b=c&d='e' <b title=\"x\">y</b> api"#);
+        let (_, body) = render(&pages, &request(&[("accept", "text/plain"), name]), 502);
+        assert_eq!(body, r#"/a?b=c&d='e' <b title="x">y</b> api"#);
+    }
+
+    #[test]
+    fn test_invalid_pages() {
+        let invalid = [
+            page(&[200], "text/html", ""),
+            page(&[], "html", ""),
+            ErrorPageConfig {
+                content_type: "text/html".to_string(),
+                ..Default::default()
+            },
+        ];
+        for config in invalid {
+            assert!(ErrorPage::new(&config).is_err(), "{config:?}");
+        }
+        let config = ErrorPagesConfig {
+            code_header: "bad header".to_string(),
+            pages: vec![],
+        };
+        assert!(ErrorPages::new(&config).is_err());
+    }
+}
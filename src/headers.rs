@@ -0,0 +1,793 @@
+use std::net::IpAddr;
+
+use http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use http::{StatusCode, Version};
+use ipnet::IpNet;
+use log::warn;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use regex::Regex;
+
+use crate::acl::parse_nets;
+use crate::authority::Authority;
+use crate::config::{HeaderAction, HeaderRuleConfig, ProxyHeadersConfig, CONFIG_ERROR};
+use crate::template::{self, Segment, Template};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+/// Headers that only concern a single connection, RFC 9110 section 7.6.1,
+/// plus the proxy credentials which are meant for this proxy only
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+    "proxy-authorization",
+    "proxy-authenticate",
+];
+
+/// Headers that frame the message, which `Connection` must not remove
+const FRAMING: &[&str] = &["host", "content-length", "transfer-encoding"];
+
+/// Returns the hop-by-hop headers of a message: the fixed ones and those
+/// listed in `Connection`.
+fn hop_by_hop(headers: &HeaderMap) -> Vec<HeaderName> {
+    let listed = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .filter(|name| !FRAMING.contains(&name.as_str()));
+    HOP_BY_HOP
+        .iter()
+        .map(|name| HeaderName::from_static(name))
+        .chain(listed)
+        .collect()
+}
+
+/// Where a request came from, as reported to the upstream
+pub struct Origin<'a> {
+    pub client: Option<IpAddr>,
+    /// Whether the client asked for `https`
+    pub tls: bool,
+    /// The host the client asked for
+    pub host: &'a str,
+}
+
+/// Hop-by-hop header stripping and the headers identifying the proxy and the
+/// client.
+pub struct ProxyHeaders {
+    via: Option<String>,
+    forwarded: bool,
+    x_forwarded: bool,
+    trusted_proxies: Vec<IpNet>,
+}
+
+impl ProxyHeaders {
+    pub fn new(config: &ProxyHeadersConfig) -> Result<Self> {
+        Ok(ProxyHeaders {
+            via: config.via.then(|| config.pseudonym.clone()),
+            forwarded: config.forwarded,
+            x_forwarded: config.x_forwarded,
+            trusted_proxies: parse_nets(&config.trusted_proxies)
+                .map_err(|e| e.more_context("proxy_headers trusted_proxies"))?,
+        })
+    }
+
+    /// Prepares a request to be sent upstream.
+    pub fn upstream_request(&self, req: &mut RequestHeader, origin: &Origin) -> Result<()> {
+        let trailers = accepts_trailers(req);
+        for name in hop_by_hop(&req.headers) {
+            req.remove_header(&name);
+        }
+        if trailers {
+            req.insert_header(header::TE, "trailers")?;
+        }
+        if let Some(pseudonym) = &self.via {
+            let value = via(req.version, pseudonym);
+            let value = appended(&req.headers, header::VIA.as_str(), &value);
+            req.insert_header(header::VIA, value)?;
+        }
+
+        let trusted = origin
+            .client
+            .is_some_and(|ip| self.trusted_proxies.iter().any(|net| net.contains(&ip)));
+        let proto = if origin.tls { "https" } else { "http" };
+        if self.forwarded {
+            let client = origin.client.map_or("unknown".to_string(), |ip| match ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("\"[{ip}]\""),
+            });
+            let element = format!("for={client};host={};proto={proto}", quote(origin.host));
+            if !trusted {
+                req.remove_header(&header::FORWARDED);
+            }
+            let value = appended(&req.headers, header::FORWARDED.as_str(), &element);
+            req.insert_header(header::FORWARDED, value)?;
+        }
+        if self.x_forwarded {
+            let client = origin.client.map_or("unknown".to_string(), |ip| ip.to_string());
+            if !trusted {
+                req.remove_header(X_FORWARDED_FOR);
+                req.remove_header(X_FORWARDED_PROTO);
+                req.remove_header(X_FORWARDED_HOST);
+            }
+            let value = appended(&req.headers, X_FORWARDED_FOR, &client);
+            req.insert_header(X_FORWARDED_FOR, value)?;
+            // The first proxy saw what the client asked for
+            if !req.headers.contains_key(X_FORWARDED_PROTO) {
+                req.insert_header(X_FORWARDED_PROTO, proto)?;
+            }
+            if !req.headers.contains_key(X_FORWARDED_HOST) {
+                req.insert_header(X_FORWARDED_HOST, origin.host)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepares an upstream response to be sent to the client.
+    pub fn response(&self, resp: &mut ResponseHeader) -> Result<()> {
+        // The client only switches protocols with the upgrade headers intact
+        let switching = resp.status == StatusCode::SWITCHING_PROTOCOLS;
+        for name in hop_by_hop(&resp.headers) {
+            if !(switching && name == header::UPGRADE) {
+                resp.remove_header(&name);
+            }
+        }
+        if switching {
+            resp.insert_header(header::CONNECTION, "upgrade")?;
+        }
+        if let Some(pseudonym) = &self.via {
+            let value = via(resp.version, pseudonym);
+            let value = appended(&resp.headers, header::VIA.as_str(), &value);
+            resp.insert_header(header::VIA, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the client accepts trailers and gets them passed on, which gRPC
+/// servers insist on: response trailers only reach HTTP/2 clients.
+fn accepts_trailers(req: &RequestHeader) -> bool {
+    req.version == Version::HTTP_2
+        && req
+            .headers
+            .get_all(header::TE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|coding| coding.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Returns the value of a list-valued header with `value` appended, repeated
+/// fields combined into one.
+fn appended(headers: &HeaderMap, name: &str, value: &str) -> String {
+    let existing: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    if existing.is_empty() {
+        value.to_string()
+    } else {
+        format!("{}, {value}", existing.join(", "))
+    }
+}
+
+/// A `Via` entry for a message received with `version`
+fn via(version: Version, pseudonym: &str) -> String {
+    let protocol = match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    };
+    format!("{protocol} {pseudonym}")
+}
+
+/// Quotes a `Forwarded` value unless it is a token.
+fn quote(value: &str) -> String {
+    let token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// A value of header rule templates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Variable {
+    RemoteAddr,
+    RemoteUser,
+    RequestId,
+    Host,
+    RequestMethod,
+    RequestUri,
+    Route,
+    Upstream,
+    UpstreamAddr,
+}
+
+const VARIABLES: &[(&str, Variable)] = &[
+    ("remote_addr", Variable::RemoteAddr),
+    ("remote_user", Variable::RemoteUser),
+    ("request_id", Variable::RequestId),
+    ("host", Variable::Host),
+    ("request_method", Variable::RequestMethod),
+    ("request_uri", Variable::RequestUri),
+    ("route", Variable::Route),
+    ("upstream", Variable::Upstream),
+    ("upstream_addr", Variable::UpstreamAddr),
+];
+
+/// What header rule templates are rendered with
+pub struct RuleContext<'a> {
+    pub client: Option<IpAddr>,
+    pub user: Option<&'a str>,
+    pub request_id: &'a str,
+    /// The request as received from the client
+    pub req: &'a RequestHeader,
+    /// Where the request is addressed to
+    pub authority: Option<&'a Authority>,
+    pub route: &'a str,
+    pub upstream: &'a str,
+    pub upstream_addr: Option<&'a str>,
+}
+
+impl RuleContext<'_> {
+    /// Renders a template, as a regex replacement if `replacement` is set:
+    /// then only numbered variables refer to captures.
+    fn render(&self, template: &Template<Variable>, replacement: bool) -> String {
+        let escape = |text: &str| {
+            if replacement {
+                text.replace('$', "$$")
+            } else {
+                text.to_string()
+            }
+        };
+        let mut value = String::new();
+        for segment in template.segments() {
+            let variable = match segment {
+                Segment::Literal(literal) => {
+                    value.push_str(&escape(literal));
+                    continue;
+                }
+                Segment::Numbered(reference) => {
+                    value.push_str(reference);
+                    continue;
+                }
+                Segment::Variable(variable) => match variable {
+                    Variable::RemoteAddr => self.client.map(|ip| ip.to_string()),
+                    Variable::RemoteUser => self.user.map(str::to_string),
+                    Variable::RequestId => Some(self.request_id.to_string()),
+                    Variable::Host => self.authority.map(|a| a.to_string()),
+                    Variable::RequestMethod => Some(self.req.method.to_string()),
+                    Variable::RequestUri => {
+                        Some(String::from_utf8_lossy(self.req.raw_path()).into_owned())
+                    }
+                    Variable::Route => Some(self.route.to_string()),
+                    Variable::Upstream => Some(self.upstream.to_string()),
+                    Variable::UpstreamAddr => self.upstream_addr.map(str::to_string),
+                },
+                Segment::Header(name) => template::header(self.req, name),
+            };
+            value.push_str(&escape(variable.as_deref().unwrap_or_default()));
+        }
+        value
+    }
+}
+
+/// The headers of a request or a response
+trait Headers {
+    fn map(&self) -> &HeaderMap;
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()>;
+    fn remove(&mut self, name: &HeaderName);
+}
+
+impl Headers for RequestHeader {
+    fn map(&self) -> &HeaderMap {
+        &self.headers
+    }
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.insert_header(name, value)
+    }
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+impl Headers for ResponseHeader {
+    fn map(&self) -> &HeaderMap {
+        &self.headers
+    }
+    fn insert(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.insert_header(name, value)
+    }
+    fn append(&mut self, name: HeaderName, value: HeaderValue) -> Result<()> {
+        self.append_header(name, value).map(|_| ())
+    }
+    fn remove(&mut self, name: &HeaderName) {
+        self.remove_header(name);
+    }
+}
+
+enum RuleAction {
+    Add(Template<Variable>),
+    Set(Template<Variable>),
+    Remove,
+    Rewrite(Regex, Template<Variable>),
+}
+
+struct HeaderRule {
+    name: HeaderName,
+    action: RuleAction,
+}
+
+impl HeaderRule {
+    fn new(config: &HeaderRuleConfig) -> Result<Self> {
+        let name = HeaderName::from_bytes(config.name.as_bytes())
+            .or_err_with(CONFIG_ERROR, || format!("invalid header name {:?}", config.name))?;
+        let value = || {
+            let value = config.value.as_deref().or_err_with(CONFIG_ERROR, || {
+                format!("header rule for {} needs a value", config.name)
+            })?;
+            Template::parse(value, VARIABLES)
+        };
+        let action = match config.action {
+            HeaderAction::Add => RuleAction::Add(value()?),
+            HeaderAction::Set => RuleAction::Set(value()?),
+            HeaderAction::Remove => RuleAction::Remove,
+            HeaderAction::Rewrite => {
+                let regex = config.regex.as_deref().or_err_with(CONFIG_ERROR, || {
+                    format!("header rule for {} needs a regex", config.name)
+                })?;
+                let regex = Regex::new(regex).or_err_with(CONFIG_ERROR, || {
+                    format!("invalid regex {regex:?} in header rule for {}", config.name)
+                })?;
+                RuleAction::Rewrite(regex, value()?)
+            }
+        };
+        Ok(HeaderRule { name, action })
+    }
+
+    fn apply(&self, headers: &mut impl Headers, context: &RuleContext) -> Result<()> {
+        let value = |template| {
+            HeaderValue::from_str(&context.render(template, false))
+                .or_err_with(InvalidHTTPHeader, || format!("invalid value for {}", self.name))
+        };
+        match &self.action {
+            RuleAction::Add(template) => headers.append(self.name.clone(), value(template)?),
+            RuleAction::Set(template) => headers.insert(self.name.clone(), value(template)?),
+            RuleAction::Remove => {
+                headers.remove(&self.name);
+                Ok(())
+            }
+            RuleAction::Rewrite(regex, template) => {
+                let replacement = context.render(template, true);
+                let values: Vec<HeaderValue> = headers
+                    .map()
+                    .get_all(&self.name)
+                    .iter()
+                    .map(|original| match original.to_str() {
+                        Ok(original) => HeaderValue::from_str(
+                            &regex.replace_all(original, replacement.as_str()),
+                        )
+                        .or_err_with(InvalidHTTPHeader, || {
+                            format!("invalid rewritten value for {}", self.name)
+                        }),
+                        // Values that are not text are left alone
+                        Err(_) => Ok(original.clone()),
+                    })
+                    .collect::<Result<_>>()?;
+                headers.remove(&self.name);
+                for value in values {
+                    headers.append(self.name.clone(), value)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Header rules of a route, for either requests or responses
+pub struct HeaderRules(Vec<HeaderRule>);
+
+impl HeaderRules {
+    pub fn new(configs: &[HeaderRuleConfig]) -> Result<Self> {
+        Ok(HeaderRules(
+            configs.iter().map(HeaderRule::new).collect::<Result<_>>()?,
+        ))
+    }
+
+    /// Applies the rules to a request sent upstream.
+    pub fn apply_request(&self, req: &mut RequestHeader, context: &RuleContext) {
+        self.apply(req, context);
+    }
+
+    /// Applies the rules to a response sent to the client.
+    pub fn apply_response(&self, resp: &mut ResponseHeader, context: &RuleContext) {
+        self.apply(resp, context);
+    }
+
+    /// A failing rule is skipped, the request goes on without it.
+    fn apply(&self, headers: &mut impl Headers, context: &RuleContext) {
+        for (i, rule) in self.0.iter().enumerate() {
+            if let Err(e) = rule.apply(headers, context) {
+                warn!(
+                    "[{}] Header rule #{i} of route {} failed: {e}",
+                    context.request_id, context.route
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy_headers(forwarded: bool, trusted_proxies: &[&str]) -> ProxyHeaders {
+        ProxyHeaders::new(&ProxyHeadersConfig {
+            forwarded,
+            trusted_proxies: trusted_proxies.iter().map(|net| net.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn request(headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .map(|value| value.to_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn origin(client: &str) -> Origin<'static> {
+        Origin {
+            client: Some(client.parse().unwrap()),
+            tls: false,
+            host: "example.com:8080",
+        }
+    }
+
+    #[test]
+    fn test_hop_by_hop_request() {
+        let headers = proxy_headers(false, &[]);
+        let mut req = request(&[
+            ("connection", "keep-alive, X-Secret"),
+            ("connection", "content-length, host"),
+            ("keep-alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("proxy-authorization", "Basic YTpi"),
+            ("upgrade", "websocket"),
+            ("te", "trailers"),
+            ("trailer", "x-checksum"),
+            ("host", "example.com"),
+            ("content-length", "0"),
+            ("x-kept", "1"),
+        ]);
+        headers.upstream_request(&mut req, &origin("192.0.2.1")).unwrap();
+        for name in [
+            "connection",
+            "keep-alive",
+            "x-secret",
+            "proxy-authorization",
+            "upgrade",
+            "trailer",
+            // Only HTTP/2 clients get trailers passed on
+            "te",
+        ] {
+            assert!(!req.headers.contains_key(name), "{name}");
+        }
+        // Framing headers survive being listed in Connection
+        assert_eq!(values(&req.headers, "host"), ["example.com"]);
+        assert_eq!(values(&req.headers, "content-length"), ["0"]);
+        assert_eq!(values(&req.headers, "x-kept"), ["1"]);
+        assert_eq!(values(&req.headers, "via"), ["1.1 pinproxy"]);
+
+        let mut req = request(&[("te", "gzip, trailers")]);
+        req.set_version(Version::HTTP_2);
+        headers.upstream_request(&mut req, &origin("192.0.2.1")).unwrap();
+        assert_eq!(values(&req.headers, "te"), ["trailers"]);
+    }
+
+    #[test]
+    fn test_hop_by_hop_response() {
+        let headers = proxy_headers(false, &[]);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.append_header("connection", "x-secret").unwrap();
+        resp.append_header("x-secret", "1").unwrap();
+        resp.append_header("proxy-authenticate", "Basic").unwrap();
+        resp.append_header("upgrade", "h2c").unwrap();
+        resp.append_header("trailer", "grpc-status").unwrap();
+        resp.append_header("via", "1.1 origin").unwrap();
+        headers.response(&mut resp).unwrap();
+        for name in ["connection", "x-secret", "proxy-authenticate", "upgrade", "trailer"] {
+            assert!(!resp.headers.contains_key(name), "{name}");
+        }
+        assert_eq!(values(&resp.headers, "via"), ["1.1 origin, 1.1 pinproxy"]);
+
+        let mut resp = ResponseHeader::build(101, None).unwrap();
+        resp.append_header("connection", "Upgrade").unwrap();
+        resp.append_header("upgrade", "websocket").unwrap();
+        headers.response(&mut resp).unwrap();
+        assert_eq!(values(&resp.headers, "connection"), ["upgrade"]);
+        assert_eq!(values(&resp.headers, "upgrade"), ["websocket"]);
+    }
+
+    #[test]
+    fn test_untrusted_client() {
+        let headers = proxy_headers(true, &["10.0.0.0/8"]);
+        let mut req = request(&[
+            ("forwarded", "for=203.0.113.9"),
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "spoofed.test"),
+        ]);
+        headers.upstream_request(&mut req, &origin("192.0.2.1")).unwrap();
+        assert_eq!(
+            values(&req.headers, "forwarded"),
+            ["for=192.0.2.1;host=\"example.com:8080\";proto=http"]
+        );
+        assert_eq!(values(&req.headers, "x-forwarded-for"), ["192.0.2.1"]);
+        assert_eq!(values(&req.headers, "x-forwarded-proto"), ["http"]);
+        assert_eq!(values(&req.headers, "x-forwarded-host"), ["example.com:8080"]);
+    }
+
+    #[test]
+    fn test_trusted_client() {
+        let headers = proxy_headers(true, &["10.0.0.0/8", "2001:db8::/32"]);
+        let mut req = request(&[
+            ("forwarded", "for=203.0.113.9;proto=https"),
+            ("x-forwarded-for", "203.0.113.9"),
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.org"),
+        ]);
+        headers.upstream_request(&mut req, &origin("10.1.2.3")).unwrap();
+        assert_eq!(
+            values(&req.headers, "forwarded"),
+            ["for=203.0.113.9;proto=https, for=10.1.2.3;host=\"example.com:8080\";proto=http"]
+        );
+        assert_eq!(
+            values(&req.headers, "x-forwarded-for"),
+            ["203.0.113.9, 198.51.100.7, 10.1.2.3"]
+        );
+        // The first proxy's view of the request is kept
+        assert_eq!(values(&req.headers, "x-forwarded-proto"), ["https"]);
+        assert_eq!(values(&req.headers, "x-forwarded-host"), ["example.org"]);
+
+        // A trusted client adding nothing still gets the headers started
+        let mut req = request(&[]);
+        let origin = Origin {
+            client: Some("2001:db8::1".parse().unwrap()),
+            tls: true,
+            host: "example.com",
+        };
+        headers.upstream_request(&mut req, &origin).unwrap();
+        assert_eq!(
+            values(&req.headers, "forwarded"),
+            ["for=\"[2001:db8::1]\";host=example.com;proto=https"]
+        );
+        assert_eq!(values(&req.headers, "x-forwarded-for"), ["2001:db8::1"]);
+        assert_eq!(values(&req.headers, "x-forwarded-proto"), ["https"]);
+    }
+
+    #[test]
+    fn test_unknown_client() {
+        let headers = proxy_headers(true, &[]);
+        let mut req = request(&[("x-forwarded-for", "203.0.113.9")]);
+        let origin = Origin {
+            client: None,
+            tls: false,
+            host: "example.com",
+        };
+        headers.upstream_request(&mut req, &origin).unwrap();
+        assert_eq!(
+            values(&req.headers, "forwarded"),
+            ["for=unknown;host=example.com;proto=http"]
+        );
+        assert_eq!(values(&req.headers, "x-forwarded-for"), ["unknown"]);
+    }
+
+    fn rule(
+        action: HeaderAction,
+        name: &str,
+        value: Option<&str>,
+        regex: Option<&str>,
+    ) -> HeaderRuleConfig {
+        HeaderRuleConfig {
+            action,
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            regex: regex.map(str::to_string),
+        }
+    }
+
+    fn context<'a>(req: &'a RequestHeader, authority: &'a Authority) -> RuleContext<'a> {
+        RuleContext {
+            client: Some("192.0.2.1".parse().unwrap()),
+            user: Some("alice"),
+            request_id: "id-1",
+            req,
+            authority: Some(authority),
+            route: "api",
+            upstream: "billing",
+            upstream_addr: Some("10.0.0.5:8080"),
+        }
+    }
+
+    /// Rules adding, setting, removing and rewriting the same headers
+    fn rules() -> HeaderRules {
+        use HeaderAction::*;
+        HeaderRules::new(&[
+            rule(Add, "x-added", Some("new"), None),
+            rule(Set, "x-set", Some("new"), None),
+            rule(Remove, "x-removed", None, None),
+            rule(Rewrite, "x-rewritten", Some("$2.$1"), Some("^([a-z]+)-([0-9]+)$")),
+            rule(Set, "x-fresh", Some("set"), None),
+        ])
+        .unwrap()
+    }
+
+    #[test]
+    fn test_rules_request() {
+        let mut req = request(&[
+            ("x-added", "old"),
+            ("x-set", "old"),
+            ("x-set", "older"),
+            ("x-removed", "1"),
+            ("x-removed", "2"),
+            ("x-rewritten", "abc-123"),
+            ("x-rewritten", "no match"),
+        ]);
+        let client = request(&[]);
+        let authority = Authority::parse("example.com").unwrap();
+        rules().apply_request(&mut req, &context(&client, &authority));
+        assert_eq!(values(&req.headers, "x-added"), ["old", "new"]);
+        assert_eq!(values(&req.headers, "x-set"), ["new"]);
+        assert!(values(&req.headers, "x-removed").is_empty());
+        assert_eq!(values(&req.headers, "x-rewritten"), ["123.abc", "no match"]);
+        assert_eq!(values(&req.headers, "x-fresh"), ["set"]);
+    }
+
+    #[test]
+    fn test_rules_response() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in [
+            ("x-added", "old"),
+            ("x-set", "old"),
+            ("x-removed", "1"),
+            ("x-rewritten", "xyz-9"),
+        ] {
+            resp.append_header(name, value).unwrap();
+        }
+        let req = request(&[]);
+        let authority = Authority::parse("example.com").unwrap();
+        rules().apply_response(&mut resp, &context(&req, &authority));
+        assert_eq!(values(&resp.headers, "x-added"), ["old", "new"]);
+        assert_eq!(values(&resp.headers, "x-set"), ["new"]);
+        assert!(values(&resp.headers, "x-removed").is_empty());
+        assert_eq!(values(&resp.headers, "x-rewritten"), ["9.xyz"]);
+        assert_eq!(values(&resp.headers, "x-fresh"), ["set"]);
+
+        // Rewriting a header that is not there adds nothing
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        rules().apply_response(&mut resp, &context(&req, &authority));
+        assert!(!resp.headers.contains_key("x-rewritten"));
+    }
+
+    #[test]
+    fn test_variables() {
+        let template = "$remote_addr|$remote_user|${request_id}|$host|$request_method|\
+            $request_uri|$route|$upstream|${upstream_addr}|$http_user_agent|$http_x_missing|";
+        let rules = HeaderRules::new(&[rule(HeaderAction::Set, "x-vars", Some(template), None)])
+            .unwrap();
+        let mut client = RequestHeader::build("POST", b"/a/b?c=d", None).unwrap();
+        client.insert_header("user-agent", "curl/8").unwrap();
+        let authority = Authority::parse("example.com:8080").unwrap();
+        let mut req = request(&[]);
+        rules.apply_request(&mut req, &context(&client, &authority));
+        assert_eq!(
+            values(&req.headers, "x-vars"),
+            ["192.0.2.1|alice|id-1|example.com:8080|POST|/a/b?c=d|api|billing|10.0.0.5:8080|\
+             curl/8||"]
+        );
+
+        // Values that are not known render as nothing
+        let context = RuleContext {
+            client: None,
+            user: None,
+            authority: None,
+            upstream_addr: None,
+            ..context(&client, &authority)
+        };
+        let template = "[$remote_addr][$remote_user][$host][$upstream_addr]";
+        let rules = HeaderRules::new(&[rule(HeaderAction::Set, "x-vars", Some(template), None)])
+            .unwrap();
+        rules.apply_request(&mut req, &context);
+        assert_eq!(values(&req.headers, "x-vars"), ["[][][][]"]);
+    }
+
+    #[test]
+    fn test_escapes() {
+        use HeaderAction::*;
+        let client = request(&[("x-price", "$1")]);
+        let authority = Authority::parse("example.com").unwrap();
+        let context = RuleContext {
+            user: Some("a$1b"),
+            ..context(&client, &authority)
+        };
+        let rules = HeaderRules::new(&[
+            rule(Set, "x-literal", Some("$$remote_user costs $$5"), None),
+            rule(Set, "x-user", Some("$remote_user"), None),
+            // Variables are not captures, however they look
+            rule(Rewrite, "x-rewritten", Some("${1}:$remote_user:$http_x_price"), Some("^(.*)$")),
+            rule(Rewrite, "x-dollar", Some("$$1 and $1"), Some("([0-9]+)")),
+        ])
+        .unwrap();
+        let mut req = request(&[("x-rewritten", "value"), ("x-dollar", "42")]);
+        rules.apply_request(&mut req, &context);
+        assert_eq!(values(&req.headers, "x-literal"), ["$remote_user costs $5"]);
+        assert_eq!(values(&req.headers, "x-user"), ["a$1b"]);
+        assert_eq!(values(&req.headers, "x-rewritten"), ["value:a$1b:$1"]);
+        assert_eq!(values(&req.headers, "x-dollar"), ["$1 and 42"]);
+    }
+
+    #[test]
+    fn test_failing_rule_skipped() {
+        use HeaderAction::*;
+        let rules = HeaderRules::new(&[
+            rule(Set, "x-bad", Some("line\nbreak"), None),
+            rule(Set, "x-good", Some("ok"), None),
+        ])
+        .unwrap();
+        let client = request(&[]);
+        let authority = Authority::parse("example.com").unwrap();
+        let mut req = request(&[]);
+        rules.apply_request(&mut req, &context(&client, &authority));
+        assert!(!req.headers.contains_key("x-bad"));
+        assert_eq!(values(&req.headers, "x-good"), ["ok"]);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        use HeaderAction::*;
+        for config in [
+            rule(Add, "x-a", None, None),
+            rule(Set, "x-a", None, None),
+            rule(Rewrite, "x-a", Some("b"), None),
+            rule(Rewrite, "x-a", None, Some("a")),
+            rule(Rewrite, "x-a", Some("b"), Some("(")),
+            rule(Set, "x a", Some("b"), None),
+            rule(Set, "x-a", Some("$unknown"), None),
+            rule(Set, "x-a", Some("${remote_addr"), None),
+        ] {
+            let e = HeaderRules::new(std::slice::from_ref(&config)).err();
+            assert_eq!(e.map(|e| e.etype().clone()), Some(CONFIG_ERROR), "{config:?}");
+        }
+    }
+}
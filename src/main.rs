@@ -1,14 +1,60 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
 use clap::Parser;
 use log::info;
+use pingora::apps::HttpServerOptions;
 use pingora::prelude::*;
-use pingora::proxy::http_proxy_service;
-use pingora::http::ResponseHeader;
+use pingora::proxy::{http_proxy_service, http_proxy_service_with_name};
+use pingora::services::background::{background_service, GenBackgroundService};
+use pingora::services::listening::Service;
+
+mod access_log;
+mod acl;
+mod admin;
+mod auth;
+mod authority;
+mod cache;
+mod cache_storage;
+mod config;
+mod dns;
+mod error_pages;
+mod headers;
+mod metrics;
+mod proxy;
+mod rate_limit;
+mod reload;
+mod request_id;
+mod retry;
+mod rewrite;
+mod routing;
+mod telemetry;
+mod template;
+mod timeouts;
+mod tls;
+mod tunnel;
+mod upgrade;
+mod upstream;
+
+use access_log::AccessLog;
+use admin::AdminService;
+use cache::Cache;
+use config::{Config, ListenerConfig, Listening};
+use proxy::ProxyService;
+use reload::{ConfigReloader, Settings};
+use telemetry::Tracing;
+use tls::{CertStore, UpstreamTls};
+use upstream::HealthChecks;
 
 /// A lightweight HTTP proxy server based on Pingora
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Port to listen on
+    /// Configuration file (TOML, or YAML with a .yaml/.yml extension)
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Port to listen on, unless the configuration file has listeners
     #[arg(short, long, default_value = "8080")]
     port: u16,
 
@@ -19,89 +65,37 @@ struct Args {
     /// Enable daemon mode
     #[arg(short, long)]
     daemon: bool,
-}
 
-pub struct ProxyService;
-
-#[async_trait::async_trait]
-impl ProxyHttp for ProxyService {
-    type CTX = ();
-    fn new_ctx(&self) -> Self::CTX {}
-
-    async fn upstream_peer(
-        &self,
-        session: &mut Session,
-        _ctx: &mut Self::CTX,
-    ) -> Result<Box<HttpPeer>> {
-        // Extract the host from the request headers
-        let host = session
-            .req_header()
-            .headers
-            .get("Host")
-            .and_then(|h| h.to_str().ok())
-            .unwrap_or("example.com:80");
-
-        info!("Proxying request to: {}", host);
-
-        // Parse host and port
-        let (hostname, port) = if host.contains(':') {
-            let parts: Vec<&str> = host.split(':').collect();
-            (parts[0], parts[1].parse().unwrap_or(80))
-        } else {
-            (host, 80)
-        };
-
-        let peer = Box::new(HttpPeer::new(
-            (hostname, port),
-            false, // TLS
-            hostname.to_string(),
-        ));
-
-        Ok(peer)
-    }
+    /// Take over the listening sockets of a running instance, which hands
+    /// them over and drains its connections once sent SIGQUIT
+    #[arg(short, long)]
+    upgrade: bool,
 
-    async fn upstream_request_filter(
-        &self,
-        _session: &mut Session,
-        upstream_request: &mut RequestHeader,
-        _ctx: &mut Self::CTX,
-    ) -> Result<()> {
-        // Remove proxy-specific headers if present
-        upstream_request.remove_header("Proxy-Connection");
-        Ok(())
-    }
+    /// Certificate chain (PEM) for the TLS listener, reloaded when it changes
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<String>,
 
-    async fn response_filter(
-        &self,
-        _session: &mut Session,
-        upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
-    ) -> Result<()> {
-        // Add custom header to identify the proxy
-        upstream_response
-            .insert_header("X-Proxy-Server", "pinproxy")
-            .unwrap();
-        Ok(())
-    }
+    /// Private key (PEM) for the TLS listener
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<String>,
 
-    async fn logging(
-        &self,
-        session: &mut Session,
-        _e: Option<&pingora::Error>,
-        _ctx: &mut Self::CTX,
-    ) {
-        let req = session.req_header();
-        info!(
-            "{} {} {} - Status: {}",
-            session.client_addr().unwrap_or(&"unknown".parse().unwrap()),
-            req.method,
-            req.uri,
-            session
-                .response_written()
-                .map(|r| r.status.as_u16())
-                .unwrap_or(0)
-        );
-    }
+    /// Port of the TLS listener, served alongside the plaintext one
+    #[arg(long, default_value = "8443")]
+    tls_port: u16,
+
+    /// Address of the Prometheus metrics listener, e.g. 127.0.0.1:9091,
+    /// overriding the configuration file
+    #[arg(long)]
+    metrics_address: Option<String>,
+
+    /// PEM bundle of CA certificates used to verify TLS upstreams
+    /// instead of the system trust store
+    #[arg(long)]
+    ca_file: Option<String>,
+
+    /// Skip certificate verification of TLS upstreams (test environments only)
+    #[arg(long)]
+    insecure_upstream: bool,
 }
 
 fn main() {
@@ -113,12 +107,16 @@ fn main() {
     // Parse command line arguments
     let args = Args::parse();
 
-    info!("Starting pinproxy on port {}", args.port);
     info!("Workers: {}", args.workers);
 
+    let config = match &args.config {
+        Some(path) => Config::load(path).unwrap(),
+        None => Config::default(),
+    };
+
     // Create Pingora server
     let mut server = Server::new(Some(Opt {
-        upgrade: false,
+        upgrade: args.upgrade,
         daemon: args.daemon,
         nocapture: false,
         test: false,
@@ -128,16 +126,123 @@ fn main() {
 
     server.bootstrap();
 
-    // Create proxy service - ProxyService itself, not Arc
-    let proxy_service = ProxyService;
+    let upstream_tls = UpstreamTls::new(args.ca_file.as_deref(), args.insecure_upstream).unwrap();
+    let settings = Settings::new(&config, &upstream_tls, None).unwrap();
+    let settings = Arc::new(ArcSwap::from_pointee(settings));
+    server.add_service(background_service(
+        "upstream health checks",
+        HealthChecks::new(settings.clone()),
+    ));
+    if let Some(path) = &args.config {
+        let reloader = ConfigReloader::new(path, &config, upstream_tls.clone(), settings.clone());
+        server.add_service(background_service("config reloader", reloader));
+    }
 
-    let mut proxy_service_builder = http_proxy_service(&server.configuration, proxy_service);
-    proxy_service_builder.add_tcp(&format!("0.0.0.0:{}", args.port));
+    let access_log = Arc::new(AccessLog::new(&config.access_log).unwrap());
+    server.add_service(GenBackgroundService::new(
+        "BG access log writer".to_string(),
+        access_log.clone(),
+    ));
+    let cache = config.cache.as_ref().map(Cache::new).transpose().unwrap().map(Arc::new);
+    let tracing = config.tracing.as_ref().map(Tracing::new).transpose().unwrap();
+    if let Some(tracing) = &tracing {
+        server.add_service(background_service("trace exporter", tracing.clone()));
+    }
+    // Create proxy service - ProxyService itself, not Arc
+    let proxy_service =
+        ProxyService::new(upstream_tls, settings, access_log, cache.clone(), tracing);
+
+    let mut proxy_service_builder =
+        http_proxy_service(&server.configuration, proxy_service.clone());
+    // pingora turns h2c on for a whole service, where it would also take
+    // HTTP/1.1 over TLS for HTTP/2, so h2c listeners get a service of their own
+    let mut h2c_service_builder = None;
+
+    let listeners = if config.listeners.is_empty() {
+        cli_listeners(&args)
+    } else {
+        config.listeners
+    };
+    for listener in &listeners {
+        match listener.listening().unwrap() {
+            Listening::Tls { cert, key, http2 } => {
+                let cert_store = CertStore::new(cert, key).unwrap();
+                proxy_service_builder.add_tls_with_settings(
+                    &listener.address,
+                    None,
+                    cert_store.listener_settings(http2).unwrap(),
+                );
+                info!("TLS listener on {}", listener.address);
+                server.add_service(background_service("tls cert reloader", cert_store));
+            }
+            Listening::H2c => {
+                let builder = h2c_service_builder.get_or_insert_with(|| {
+                    let mut builder = http_proxy_service_with_name(
+                        &server.configuration,
+                        proxy_service.clone(),
+                        "Pingora HTTP Proxy Service (h2c)",
+                    );
+                    let mut options = HttpServerOptions::default();
+                    options.h2c = true;
+                    builder.app_logic_mut().unwrap().server_options = Some(options);
+                    builder
+                });
+                builder.add_tcp(&listener.address);
+                info!("Listening on {} with h2c", listener.address);
+            }
+            Listening::Plain => {
+                proxy_service_builder.add_tcp(&listener.address);
+                info!("Listening on {}", listener.address);
+            }
+        }
+    }
 
     server.add_service(proxy_service_builder);
+    if let Some(h2c_service_builder) = h2c_service_builder {
+        server.add_service(h2c_service_builder);
+    }
+
+    let metrics_address = args
+        .metrics_address
+        .or(config.metrics.map(|metrics| metrics.address));
+    if let Some(address) = metrics_address {
+        let mut metrics_service = Service::prometheus_http_service();
+        metrics_service.add_tcp(&address);
+        info!("Metrics listener on {}", address);
+        server.add_service(metrics_service);
+    }
+
+    if let Some(admin) = &config.admin {
+        let mut admin_service =
+            Service::new("admin API".to_string(), AdminService::new(admin, cache).unwrap());
+        admin_service.add_tcp(&admin.address);
+        info!("Admin listener on {}", admin.address);
+        server.add_service(admin_service);
+    }
 
     info!("Proxy server ready to accept connections");
 
     // Run the server
     server.run_forever();
 }
+
+/// The listeners given on the command line, used without a configuration file
+fn cli_listeners(args: &Args) -> Vec<ListenerConfig> {
+    let mut listeners = vec![ListenerConfig {
+        address: format!("0.0.0.0:{}", args.port),
+        tls_cert: None,
+        tls_key: None,
+        http2: true,
+        h2c: false,
+    }];
+    if args.tls_cert.is_some() {
+        listeners.push(ListenerConfig {
+            address: format!("0.0.0.0:{}", args.tls_port),
+            tls_cert: args.tls_cert.clone(),
+            tls_key: args.tls_key.clone(),
+            http2: true,
+            h2c: false,
+        });
+    }
+    listeners
+}
@@ -0,0 +1,392 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use pingora::protocols::{Digest, SocketDigest};
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, HistogramVec,
+    IntCounterVec, IntGauge,
+};
+
+/// Label for requests that matched no route
+pub const NO_ROUTE: &str = "none";
+/// Upstream label for requests forwarded to their own target
+pub const FORWARD_UPSTREAM: &str = "forward";
+/// Upstream label for CONNECT tunnels
+pub const TUNNEL_UPSTREAM: &str = "tunnel";
+/// User label for requests without proxy authentication
+pub const NO_USER: &str = "none";
+
+static REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_requests_total",
+        "Requests handled, by response status class, route, upstream and user",
+        &["status", "route", "upstream", "user"]
+    )
+    .unwrap()
+});
+
+static REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "pinproxy_request_duration_seconds",
+        "Time from receiving the request header to finishing the response",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+static CONNECT_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "pinproxy_upstream_connect_duration_seconds",
+        "Time to establish new upstream connections, including TLS",
+        &["upstream"]
+    )
+    .unwrap()
+});
+
+static TTFB: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "pinproxy_upstream_ttfb_seconds",
+        "Time from receiving the request header to the upstream response header",
+        &["upstream"]
+    )
+    .unwrap()
+});
+
+static BYTES_RECEIVED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_received_bytes_total",
+        "Body bytes received from clients",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+static BYTES_SENT: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_sent_bytes_total",
+        "Body bytes sent to clients",
+        &["route", "upstream"]
+    )
+    .unwrap()
+});
+
+static IN_FLIGHT_REQUESTS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "pinproxy_in_flight_requests",
+        "Requests and tunnels in progress, each HTTP/2 stream counting as one"
+    )
+    .unwrap()
+});
+
+/// Size below which closed connections are only swept out when gathering
+const MIN_CONNECTION_SWEEP: usize = 1024;
+
+/// The open client connections, by the address of the socket digest pingora
+/// keeps for each of them for as long as it is open
+#[derive(Default)]
+struct OpenConnections {
+    digests: HashMap<usize, Weak<SocketDigest>>,
+    /// Size at which closed connections are swept out when adding one
+    sweep_at: usize,
+}
+
+impl OpenConnections {
+    /// Forgets the closed connections, returning how many are left.
+    fn sweep(&mut self) -> usize {
+        self.digests.retain(|_, digest| digest.strong_count() > 0);
+        // Sweeping only when the size doubled keeps adding O(1) amortized
+        self.sweep_at = (self.digests.len() * 2).max(MIN_CONNECTION_SWEEP);
+        self.digests.len()
+    }
+}
+
+/// Reports the connections still open whenever metrics are gathered
+struct ConnectionGauge {
+    gauge: IntGauge,
+    open: Arc<Mutex<OpenConnections>>,
+}
+
+impl Collector for ConnectionGauge {
+    fn desc(&self) -> Vec<&Desc> {
+        self.gauge.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let open = self.open.lock().unwrap().sweep();
+        self.gauge.set(open as i64);
+        self.gauge.collect()
+    }
+}
+
+static OPEN_CONNECTIONS: LazyLock<Arc<Mutex<OpenConnections>>> = LazyLock::new(|| {
+    let open = Arc::default();
+    let gauge = IntGauge::new(
+        "pinproxy_active_connections",
+        "Open client connections, counted once whatever the requests or tunnel on them",
+    )
+    .unwrap();
+    let collector = ConnectionGauge {
+        gauge,
+        open: Arc::clone(&open),
+    };
+    prometheus::register(Box::new(collector)).unwrap();
+    open
+});
+
+static UPSTREAM_ERRORS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_upstream_errors_total",
+        "Errors connecting to or proxying from upstreams, by error type",
+        &["upstream", "error"]
+    )
+    .unwrap()
+});
+
+static UPSTREAM_RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_upstream_retries_total",
+        "Failed tries of requests to upstreams that were retried, by failure",
+        &["upstream", "failure"]
+    )
+    .unwrap()
+});
+
+static RATE_LIMITED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_rate_limited_total",
+        "Requests rejected by a rate limit",
+        &["limit"]
+    )
+    .unwrap()
+});
+
+static CACHE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_cache_requests_total",
+        "Responses to requests the cache applies to, by cache status",
+        &["status"]
+    )
+    .unwrap()
+});
+
+static DNS_LOOKUP_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "pinproxy_dns_lookup_duration_seconds",
+        "Time to resolve the hosts of forwarded requests and tunnels, by where the answer \
+         came from",
+        &["source"]
+    )
+    .unwrap()
+});
+
+static DNS_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "pinproxy_dns_failures_total",
+        "Host names that could not be resolved, by reason",
+        &["reason"]
+    )
+    .unwrap()
+});
+
+/// Measurements of a single request, counted as in flight until dropped
+pub struct RequestMetrics {
+    start: Instant,
+    connect_start: Option<Instant>,
+    first_byte: bool,
+}
+
+impl Default for RequestMetrics {
+    fn default() -> Self {
+        IN_FLIGHT_REQUESTS.inc();
+        RequestMetrics {
+            start: Instant::now(),
+            connect_start: None,
+            first_byte: false,
+        }
+    }
+}
+
+impl RequestMetrics {
+    /// Time since the request header was received
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Marks the start of getting an upstream connection.
+    pub fn connecting(&mut self) {
+        self.connect_start = Some(Instant::now());
+    }
+
+    /// Records the connect time, unless a pooled connection was reused.
+    pub fn connected(&mut self, upstream: &str, reused: bool) {
+        if let (Some(start), false) = (self.connect_start, reused) {
+            CONNECT_DURATION
+                .with_label_values(&[upstream])
+                .observe(start.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Records the time to the first upstream response header.
+    pub fn first_byte(&mut self, upstream: &str) {
+        // Retried or 1xx responses come through here more than once
+        if !std::mem::replace(&mut self.first_byte, true) {
+            TTFB.with_label_values(&[upstream])
+                .observe(self.start.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Records the outcome of the finished request.
+    ///
+    /// `status` is 0 when no response was sent.
+    pub fn finish(
+        &self,
+        route: &str,
+        upstream: &str,
+        user: &str,
+        status: u16,
+        received: u64,
+        sent: u64,
+    ) {
+        let class = match status {
+            100..=599 => ["1xx", "2xx", "3xx", "4xx", "5xx"][usize::from(status / 100 - 1)],
+            _ => "none",
+        };
+        REQUESTS
+            .with_label_values(&[class, route, upstream, user])
+            .inc();
+        REQUEST_DURATION
+            .with_label_values(&[route, upstream])
+            .observe(self.start.elapsed().as_secs_f64());
+        BYTES_RECEIVED
+            .with_label_values(&[route, upstream])
+            .inc_by(received);
+        BYTES_SENT.with_label_values(&[route, upstream]).inc_by(sent);
+    }
+}
+
+impl Drop for RequestMetrics {
+    fn drop(&mut self) {
+        IN_FLIGHT_REQUESTS.dec();
+    }
+}
+
+/// Counts the client connection of a request as open until it closes.
+///
+/// pingora has no hook for accepted or closed connections, so they are
+/// picked up with their first request, and closed once the socket digest
+/// every request on them shares is gone.
+pub fn connection(digest: Option<&Digest>) {
+    let Some(digest) = digest.and_then(|digest| digest.socket_digest.as_ref()) else {
+        return;
+    };
+    let mut open = OPEN_CONNECTIONS.lock().unwrap();
+    if open.digests.len() >= open.sweep_at {
+        open.sweep();
+    }
+    // The weak reference keeps the address from being reused while it is here
+    open.digests
+        .entry(Arc::as_ptr(digest) as usize)
+        .or_insert_with(|| Arc::downgrade(digest));
+}
+
+/// Counts an error that happened on the upstream side of a request.
+pub fn upstream_error(upstream: &str, error: &pingora::Error) {
+    UPSTREAM_ERRORS
+        .with_label_values(&[upstream, error.etype().as_str()])
+        .inc();
+}
+
+/// Counts a retry of a request to an upstream.
+pub fn upstream_retry(upstream: &str, failure: &str) {
+    UPSTREAM_RETRIES.with_label_values(&[upstream, failure]).inc();
+}
+
+/// Counts a request rejected by a rate limit.
+pub fn rate_limited(limit: &str) {
+    RATE_LIMITED.with_label_values(&[limit]).inc();
+}
+
+/// Observes a host name resolution, `source` being `cache`, `hosts` or
+/// `nameserver`.
+pub fn dns_lookup(source: &str, duration: Duration) {
+    DNS_LOOKUP_DURATION
+        .with_label_values(&[source])
+        .observe(duration.as_secs_f64());
+}
+
+/// Counts a host name that could not be resolved.
+pub fn dns_failure(reason: &str) {
+    DNS_FAILURES.with_label_values(&[reason]).inc();
+}
+
+/// Counts a response by its cache status, such as `HIT` or `MISS`.
+pub fn cache_request(status: &str) {
+    CACHE_REQUESTS.with_label_values(&[status]).inc();
+}
+
+/// Responses counted with a cache status since startup.
+pub fn cache_requests(status: &str) -> u64 {
+    CACHE_REQUESTS.with_label_values(&[status]).get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(fd: i32) -> Digest {
+        Digest {
+            socket_digest: Some(Arc::new(SocketDigest::from_raw_fd(fd))),
+            ..Default::default()
+        }
+    }
+
+    fn open_connections() -> i64 {
+        let families = prometheus::gather();
+        let family = families
+            .iter()
+            .find(|family| family.get_name() == "pinproxy_active_connections")
+            .unwrap();
+        family.get_metric()[0].get_gauge().get_value() as i64
+    }
+
+    #[test]
+    fn test_connections() {
+        let (first, second) = (digest(1000), digest(1001));
+        connection(None);
+        connection(Some(&first));
+        let before = open_connections();
+
+        // Requests on the same connection count once
+        let request = first.clone();
+        connection(Some(&request));
+        connection(Some(&first));
+        connection(Some(&Digest::default()));
+        assert_eq!(open_connections(), before);
+        connection(Some(&second));
+        assert_eq!(open_connections(), before + 1);
+
+        // Gone once pingora and every request dropped the digest
+        drop(first);
+        assert_eq!(open_connections(), before + 1);
+        drop(request);
+        assert_eq!(open_connections(), before);
+        drop(second);
+        assert_eq!(open_connections(), before - 1);
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut open = OpenConnections::default();
+        let mut digests: Vec<_> =
+            (0..10).map(|fd| Arc::new(SocketDigest::from_raw_fd(fd))).collect();
+        for digest in &digests {
+            open.digests.insert(Arc::as_ptr(digest) as usize, Arc::downgrade(digest));
+        }
+        drop(digests.split_off(4));
+        assert_eq!(open.sweep(), 4);
+        assert_eq!(open.sweep_at, MIN_CONNECTION_SWEEP);
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+
+use crate::config::{RateLimitConfig, RateLimitKey, CONFIG_ERROR};
+use crate::metrics;
+
+/// Buckets kept per limit; full buckets are dropped to make room, as they
+/// are no different from new ones
+const MAX_BUCKETS: usize = 100_000;
+
+/// Shards of the buckets of a limit, each behind a lock of its own
+const SHARDS: usize = 64;
+
+/// Buckets kept per shard
+const SHARD_CAPACITY: usize = MAX_BUCKETS / SHARDS;
+
+/// What a request is counted by
+pub struct RateLimitRequest<'a> {
+    pub session: &'a Session,
+    pub user: Option<&'a str>,
+    pub route: Option<&'a str>,
+}
+
+/// The tokens left for one key
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per key, refilled with `rate` tokens per second up to
+/// `burst`; every request takes a token.
+struct RateLimit {
+    name: String,
+    /// What the limit was built from, to carry it over reloads that keep it
+    config: RateLimitConfig,
+    key: RateLimitKey,
+    rate: f64,
+    burst: f64,
+    routes: Vec<String>,
+    /// Picks the shard of a key, seeded so clients cannot aim at one
+    hasher: RandomState,
+    shards: Vec<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimit {
+    fn new(index: usize, config: &RateLimitConfig) -> Result<Self> {
+        let name = config.name.clone().unwrap_or_else(|| format!("#{index}"));
+        if !(config.rate > 0.0 && config.rate.is_finite()) {
+            return Error::e_explain(
+                CONFIG_ERROR,
+                format!("rate limit {name}: rate must be positive"),
+            );
+        }
+        let burst = match config.burst {
+            Some(0) => {
+                return Error::e_explain(
+                    CONFIG_ERROR,
+                    format!("rate limit {name}: burst must be positive"),
+                )
+            }
+            Some(burst) => f64::from(burst),
+            None => config.rate.ceil(),
+        };
+        Ok(RateLimit {
+            name,
+            config: config.clone(),
+            key: config.key.clone(),
+            rate: config.rate,
+            burst,
+            routes: config.routes.clone(),
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        })
+    }
+
+    fn key(&self, req: &RateLimitRequest) -> String {
+        let value = match &self.key {
+            RateLimitKey::ClientIp => None,
+            RateLimitKey::User => req.user,
+            RateLimitKey::Route => Some(req.route.unwrap_or(metrics::NO_ROUTE)),
+            RateLimitKey::Header(name) => req
+                .session
+                .req_header()
+                .headers
+                .get(name.as_str())
+                .and_then(|v| v.to_str().ok()),
+        };
+        match value {
+            Some(value) => value.to_string(),
+            None => req
+                .session
+                .client_addr()
+                .and_then(|addr| addr.as_inet())
+                .map(|addr| addr.ip().to_canonical().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Takes a token for the request, or returns how long until one is
+    /// available.
+    fn acquire(&self, req: &RateLimitRequest) -> std::result::Result<(), Duration> {
+        self.take(self.key(req), Instant::now())
+    }
+
+    /// Takes a token from the bucket of `key` at `now`.
+    fn take(&self, key: String, now: Instant) -> std::result::Result<(), Duration> {
+        let shard = self.hasher.hash_one(&key) as usize % SHARDS;
+        let mut buckets = self.shards[shard].lock().unwrap();
+        if buckets.len() >= SHARD_CAPACITY && !buckets.contains_key(&key) {
+            self.make_room(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    /// Drops the full buckets of a shard at its capacity, then arbitrary
+    /// ones down to half of it, so the shard is only swept again after as
+    /// many new keys.
+    fn make_room(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        // Too many keys are active at once, some start over
+        let excess = buckets.len().saturating_sub(SHARD_CAPACITY / 2);
+        let evicted: Vec<String> = buckets.keys().take(excess).cloned().collect();
+        for key in evicted {
+            buckets.remove(&key);
+        }
+    }
+
+    /// The tokens of `bucket` at `now`.
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// The configured rate limits
+pub struct RateLimits {
+    limits: Vec<Arc<RateLimit>>,
+}
+
+impl RateLimits {
+    /// Builds the limits of `configs`, keeping those of `previous` whose name
+    /// and configuration are unchanged along with the requests they counted.
+    pub fn new(configs: &[RateLimitConfig], previous: Option<&RateLimits>) -> Result<Self> {
+        let limits = configs
+            .iter()
+            .enumerate()
+            .map(|(i, config)| {
+                let limit = RateLimit::new(i, config)?;
+                let kept = previous
+                    .and_then(|previous| previous.limits.iter().find(|l| l.name == limit.name))
+                    .filter(|kept| kept.config == *config);
+                Ok(kept.cloned().unwrap_or_else(|| Arc::new(limit)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(RateLimits { limits })
+    }
+
+    /// Checks the request against the limits that apply to it and do not
+    /// count by user.
+    ///
+    /// These go before authentication, so that credentials cannot be tried
+    /// faster than the limits allow. Returns the name of the first limit
+    /// exceeded and how long the client should wait.
+    pub fn check_client(
+        &self,
+        req: &RateLimitRequest,
+    ) -> std::result::Result<(), (&str, Duration)> {
+        self.check(req, |key| *key != RateLimitKey::User)
+    }
+
+    /// Checks the request against the limits that apply to it and count by
+    /// user, once it is authenticated.
+    ///
+    /// Requests that failed to authenticate are counted by client IP.
+    pub fn check_user(
+        &self,
+        req: &RateLimitRequest,
+    ) -> std::result::Result<(), (&str, Duration)> {
+        self.check(req, |key| *key == RateLimitKey::User)
+    }
+
+    fn check(
+        &self,
+        req: &RateLimitRequest,
+        keys: impl Fn(&RateLimitKey) -> bool,
+    ) -> std::result::Result<(), (&str, Duration)> {
+        for limit in self.limits.iter().filter(|limit| keys(&limit.key)) {
+            let applies = limit.routes.is_empty()
+                || req
+                    .route
+                    .is_some_and(|route| limit.routes.iter().any(|r| r == route));
+            if applies {
+                limit.acquire(req).map_err(|wait| {
+                    metrics::rate_limited(&limit.name);
+                    (limit.name.as_str(), wait)
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Responds with `status` and a `Retry-After` of `wait`, rounded up to whole
+/// seconds.
+pub async fn respond_retry_after(session: &mut Session, status: u16, wait: Duration) -> Result<()> {
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    let mut resp = ResponseHeader::build(status, Some(2))?;
+    resp.insert_header(RETRY_AFTER, seconds.max(1).to_string())?;
+    resp.insert_header(CONTENT_LENGTH, "0")?;
+    session.write_response_header(Box::new(resp), true).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn config(rate: f64, burst: Option<u32>) -> RateLimitConfig {
+        RateLimitConfig {
+            name: Some("test".to_string()),
+            key: RateLimitKey::ClientIp,
+            rate,
+            burst,
+            routes: vec![],
+        }
+    }
+
+    fn take(limit: &RateLimit, key: &str, now: Instant) -> std::result::Result<(), Duration> {
+        limit.take(key.to_string(), now)
+    }
+
+    #[test]
+    fn test_burst_and_rate() {
+        let limit = RateLimit::new(0, &config(2.0, Some(3))).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(take(&limit, "a", start), Ok(()));
+        }
+        assert_eq!(take(&limit, "a", start), Err(Duration::from_millis(500)));
+        // Other keys have buckets of their own
+        assert_eq!(take(&limit, "b", start), Ok(()));
+
+        // Tokens come back at the rate
+        let later = start + Duration::from_millis(250);
+        assert_eq!(take(&limit, "a", later), Err(Duration::from_millis(250)));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(take(&limit, "a", later), Ok(()));
+        assert_eq!(take(&limit, "a", later), Err(Duration::from_millis(500)));
+
+        // but never more than the burst
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert_eq!(take(&limit, "a", later), Ok(()));
+        }
+        assert!(take(&limit, "a", later).is_err());
+    }
+
+    #[test]
+    fn test_sustained_rate() {
+        let limit = RateLimit::new(0, &config(10.0, Some(1))).unwrap();
+        let start = Instant::now();
+        let allowed = (0..1000)
+            .filter(|i| take(&limit, "a", start + Duration::from_millis(i * 5)).is_ok())
+            .count();
+        // 5 seconds at 10 per second
+        assert_eq!(allowed, 50);
+    }
+
+    #[test]
+    fn test_default_burst() {
+        let limit = RateLimit::new(0, &config(2.5, None)).unwrap();
+        let now = Instant::now();
+        let allowed = (0..10).filter(|_| take(&limit, "a", now).is_ok()).count();
+        assert_eq!(allowed, 3);
+
+        let limit = RateLimit::new(0, &config(0.5, None)).unwrap();
+        assert_eq!(take(&limit, "a", now), Ok(()));
+        assert_eq!(take(&limit, "a", now), Err(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for (rate, burst) in [(0.0, None), (-1.0, None), (f64::NAN, None), (1.0, Some(0))] {
+            let result = RateLimit::new(0, &config(rate, burst));
+            assert!(result.is_err(), "rate {rate} burst {burst:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_kept_on_reload() {
+        let limits = RateLimits::new(&[config(1.0, Some(1))], None).unwrap();
+        let now = Instant::now();
+        assert_eq!(take(&limits.limits[0], "a", now), Ok(()));
+
+        let kept = RateLimits::new(&[config(1.0, Some(1))], Some(&limits)).unwrap();
+        assert!(take(&kept.limits[0], "a", now).is_err());
+        let changed = RateLimits::new(&[config(2.0, Some(1))], Some(&limits)).unwrap();
+        assert_eq!(take(&changed.limits[0], "a", now), Ok(()));
+    }
+
+    #[test]
+    fn test_make_room() {
+        let limit = RateLimit::new(0, &config(1.0, Some(2))).unwrap();
+        let now = Instant::now();
+        let bucket = |tokens| Bucket {
+            tokens,
+            updated: now,
+        };
+        let mut buckets: HashMap<String, Bucket> = (0..SHARD_CAPACITY)
+            .map(|i| (i.to_string(), bucket(if i < 10 { 0.0 } else { 2.0 })))
+            .collect();
+        limit.make_room(&mut buckets, now);
+        assert_eq!(buckets.len(), 10);
+
+        let mut buckets: HashMap<String, Bucket> =
+            (0..SHARD_CAPACITY).map(|i| (i.to_string(), bucket(0.0))).collect();
+        limit.make_room(&mut buckets, now);
+        assert_eq!(buckets.len(), SHARD_CAPACITY / 2);
+        // Buckets filled up in the meantime go first
+        let later = now + Duration::from_secs(1);
+        let mut buckets: HashMap<String, Bucket> =
+            (0..SHARD_CAPACITY).map(|i| (i.to_string(), bucket(1.0))).collect();
+        limit.make_room(&mut buckets, later);
+        assert!(buckets.is_empty());
+    }
+
+    #[test]
+    fn test_many_keys() {
+        let limit = RateLimit::new(0, &config(1.0, Some(1))).unwrap();
+        let now = Instant::now();
+        assert_eq!(take(&limit, "a", now), Ok(()));
+        for i in 0..MAX_BUCKETS + MAX_BUCKETS / 2 {
+            take(&limit, &i.to_string(), now).unwrap();
+        }
+        let buckets: Vec<usize> = limit.shards.iter().map(|s| s.lock().unwrap().len()).collect();
+        assert!(buckets.iter().all(|len| *len <= SHARD_CAPACITY), "{buckets:?}");
+        assert!(buckets.iter().sum::<usize>() > MAX_BUCKETS / 2);
+        // Keys are still limited
+        assert_eq!(take(&limit, "b", now), Ok(()));
+        assert!(take(&limit, "b", now).is_err());
+    }
+
+    async fn session() -> Session {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = Session::new_h1(Box::new(server));
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        session.read_request().await.unwrap();
+        session
+    }
+
+    #[tokio::test]
+    async fn test_client_and_user() {
+        let by_user = RateLimitConfig {
+            name: Some("user".to_string()),
+            key: RateLimitKey::User,
+            ..config(1.0, Some(2))
+        };
+        let limits = RateLimits::new(&[config(1.0, Some(3)), by_user], None).unwrap();
+        let session = session().await;
+        let request = |user| RateLimitRequest {
+            session: &session,
+            user,
+            route: None,
+        };
+
+        // Each check only takes from its own limits
+        assert!(limits.check_user(&request(Some("alice"))).is_ok());
+        assert!(limits.check_user(&request(Some("alice"))).is_ok());
+        assert_eq!(limits.check_user(&request(Some("alice"))).unwrap_err().0, "user");
+        for _ in 0..3 {
+            assert!(limits.check_client(&request(Some("alice"))).is_ok());
+        }
+        assert_eq!(limits.check_client(&request(None)).unwrap_err().0, "test");
+
+        // Requests that failed to authenticate count by client IP
+        assert!(limits.check_user(&request(Some("bob"))).is_ok());
+        assert!(limits.check_user(&request(None)).is_ok());
+        assert!(limits.check_user(&request(None)).is_ok());
+        assert_eq!(limits.check_user(&request(None)).unwrap_err().0, "user");
+    }
+
+    /// The `Retry-After` value sent for `wait`
+    async fn retry_after(wait: Duration) -> String {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = Session::new_h1(Box::new(server));
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        session.read_request().await.unwrap();
+        respond_retry_after(&mut session, 429, wait).await.unwrap();
+        drop(session);
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 429 "), "{response}");
+        let header = response.lines().find_map(|line| {
+            let line = line.to_ascii_lowercase();
+            line.strip_prefix("retry-after: ").map(str::to_string)
+        });
+        header.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        for (wait, expected) in [
+            (Duration::ZERO, "1"),
+            (Duration::from_millis(300), "1"),
+            (Duration::from_secs(1), "1"),
+            (Duration::from_millis(1200), "2"),
+            (Duration::from_secs(30), "30"),
+        ] {
+            assert_eq!(retry_after(wait).await, expected, "{wait:?}");
+        }
+    }
+}
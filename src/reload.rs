@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pingora::prelude::*;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::acl::Acl;
+use crate::auth::ProxyAuth;
+use crate::config::{
+    AccessLogConfig, AdminConfig, CacheConfig, Config, ListenerConfig, TracingConfig,
+};
+use crate::dns::Resolver;
+use crate::error_pages::ErrorPages;
+use crate::headers::ProxyHeaders;
+use crate::rate_limit::RateLimits;
+use crate::request_id::RequestIds;
+use crate::routing::RouteTable;
+use crate::timeouts::Timeouts;
+use crate::tls::UpstreamTls;
+use crate::upgrade::Upgrades;
+
+/// How often the configuration file is checked for changes
+const CONFIG_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Everything built from the configuration file that can change while
+/// running.
+///
+/// Requests load the current settings once and keep them for their whole
+/// lifetime, so a reload never affects requests already in flight.
+pub struct Settings {
+    pub routes: RouteTable,
+    pub acl: Acl,
+    pub auth: Option<ProxyAuth>,
+    pub rate_limits: RateLimits,
+    pub proxy_headers: ProxyHeaders,
+    pub request_ids: RequestIds,
+    pub upgrades: Upgrades,
+    /// Upstream timeouts of forwarded requests
+    pub timeouts: Timeouts,
+    pub error_pages: ErrorPages,
+    pub dns: Resolver,
+}
+
+impl Settings {
+    /// Builds the settings of `config`.
+    ///
+    /// Upstream pools and rate limits that are unchanged from `previous` are
+    /// kept, so a reload neither resets backend health, request accounting
+    /// and retry budgets nor the requests counted against limits.
+    pub fn new(
+        config: &Config,
+        upstream_tls: &UpstreamTls,
+        previous: Option<&Settings>,
+    ) -> Result<Self> {
+        let timeouts = Timeouts::new(&config.timeouts)?;
+        Ok(Settings {
+            routes: RouteTable::new(config, timeouts, upstream_tls, previous.map(|p| &p.routes))?,
+            acl: Acl::new(&config.acl)?,
+            auth: config.auth.as_ref().map(ProxyAuth::new).transpose()?,
+            rate_limits: RateLimits::new(&config.rate_limits, previous.map(|p| &p.rate_limits))?,
+            proxy_headers: ProxyHeaders::new(&config.proxy_headers)?,
+            request_ids: RequestIds::new(config)?,
+            upgrades: Upgrades::new(&config.upgrades)?,
+            timeouts,
+            error_pages: ErrorPages::new(&config.error_pages)?,
+            dns: Resolver::new(&config.dns)?,
+        })
+    }
+}
+
+/// The settings in service, swapped atomically on reload
+pub type SharedSettings = Arc<ArcSwap<Settings>>;
+
+/// Reloads the configuration file on `SIGHUP` or when it changes on disk.
+///
+/// Listeners, the access log, the cache, the admin API and tracing are set up
+/// once at startup: changing them takes a restart or a graceful upgrade,
+/// everything else is swapped into the running service.
+pub struct ConfigReloader {
+    path: String,
+    upstream_tls: UpstreamTls,
+    listeners: Vec<ListenerConfig>,
+    access_log: AccessLogConfig,
+    cache: Option<CacheConfig>,
+    admin: Option<AdminConfig>,
+    tracing: Option<TracingConfig>,
+    settings: SharedSettings,
+    modified: std::sync::Mutex<Option<SystemTime>>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        path: &str,
+        config: &Config,
+        upstream_tls: UpstreamTls,
+        settings: SharedSettings,
+    ) -> Self {
+        ConfigReloader {
+            path: path.to_string(),
+            upstream_tls,
+            listeners: config.listeners.clone(),
+            access_log: config.access_log.clone(),
+            cache: config.cache.clone(),
+            admin: config.admin.clone(),
+            tracing: config.tracing.clone(),
+            settings,
+            modified: std::sync::Mutex::new(last_modified(path)),
+        }
+    }
+
+    async fn reload(&self) {
+        *self.modified.lock().unwrap() = last_modified(&self.path);
+        let path = self.path.clone();
+        let upstream_tls = self.upstream_tls.clone();
+        let previous = self.settings.load_full();
+        // Reading the configuration, htpasswd and hosts files blocks
+        let loaded = tokio::task::spawn_blocking(move || {
+            let config = Config::load(&path)?;
+            Ok((Settings::new(&config, &upstream_tls, Some(&previous))?, config))
+        })
+        .await
+        .or_err(InternalError, "loading configuration")
+        .and_then(|loaded| loaded);
+        match loaded {
+            Ok((settings, config)) => {
+                if config.listeners != self.listeners
+                    || config.access_log != self.access_log
+                    || config.cache != self.cache
+                    || config.admin != self.admin
+                    || config.tracing != self.tracing
+                {
+                    warn!(
+                        "Listener, access log, cache, admin and tracing changes in {} need a \
+                         restart to apply",
+                        self.path
+                    );
+                }
+                // New pools get their backends before taking requests
+                for upstream in &settings.routes.upstreams {
+                    upstream.resolve_once(&settings.dns).await.ok();
+                }
+                self.settings.store(Arc::new(settings));
+                info!("Reloaded configuration {}", self.path);
+            }
+            Err(e) => error!("Keeping previous configuration: {e}"),
+        }
+    }
+
+    /// Reloads if the file was modified since the last check.
+    async fn reload_if_changed(&self) {
+        let modified = last_modified(&self.path);
+        if modified.is_some() && *self.modified.lock().unwrap() != modified {
+            self.reload().await;
+        }
+    }
+}
+
+fn last_modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut hangup = signal(SignalKind::hangup())
+            .map_err(|e| error!("Cannot listen for SIGHUP, reloading on file changes only: {e}"))
+            .ok();
+        let mut interval = tokio::time::interval(CONFIG_RELOAD_INTERVAL);
+        loop {
+            let hangup_received = async {
+                match &mut hangup {
+                    Some(hangup) => hangup.recv().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = hangup_received => {
+                    info!("SIGHUP received, reloading configuration");
+                    self.reload().await;
+                }
+                _ = interval.tick() => self.reload_if_changed().await,
+            }
+        }
+    }
+}
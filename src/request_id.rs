@@ -0,0 +1,177 @@
+use std::net::IpAddr;
+
+use http::header::HeaderName;
+use ipnet::IpNet;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use uuid::Uuid;
+
+use crate::acl::parse_nets;
+use crate::config::{Config, RequestIdTrust, CONFIG_ERROR};
+
+/// Longest incoming ID that is kept, longer ones are replaced
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Takes request IDs from trusted clients or generates them, and passes them
+/// on upstream and back to the client.
+pub struct RequestIds {
+    header: HeaderName,
+    trust: RequestIdTrust,
+    trusted_proxies: Vec<IpNet>,
+    forward: bool,
+    echo: bool,
+}
+
+impl RequestIds {
+    pub fn new(config: &Config) -> Result<Self> {
+        let request_id = &config.request_id;
+        let header = HeaderName::from_bytes(request_id.header.as_bytes())
+            .or_err_with(CONFIG_ERROR, || {
+                format!("invalid request_id header {:?}", request_id.header)
+            })?;
+        Ok(RequestIds {
+            header,
+            trust: request_id.trust,
+            trusted_proxies: parse_nets(&config.proxy_headers.trusted_proxies)
+                .map_err(|e| e.more_context("proxy_headers trusted_proxies"))?,
+            forward: request_id.forward,
+            echo: request_id.echo,
+        })
+    }
+
+    /// The ID of a request: the one it came with if the client is trusted,
+    /// a new UUIDv7 otherwise.
+    pub fn id(&self, req: &RequestHeader, client: Option<IpAddr>) -> String {
+        let trusted = match self.trust {
+            RequestIdTrust::All => true,
+            RequestIdTrust::TrustedProxies => {
+                client.is_some_and(|ip| self.trusted_proxies.iter().any(|net| net.contains(&ip)))
+            }
+            RequestIdTrust::None => false,
+        };
+        let incoming = req
+            .headers
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| trusted && valid(id));
+        match incoming {
+            Some(id) => id.to_string(),
+            None => Uuid::now_v7().to_string(),
+        }
+    }
+
+    /// Sets the ID of a request sent upstream.
+    pub fn upstream_request(&self, req: &mut RequestHeader, id: &str) -> Result<()> {
+        if self.forward {
+            req.insert_header(self.header.clone(), id)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the ID of a response sent to the client.
+    pub fn response(&self, resp: &mut ResponseHeader, id: &str) -> Result<()> {
+        if self.echo {
+            resp.insert_header(self.header.clone(), id)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether an incoming ID is safe to put in logs as is: short and without
+/// spaces or control characters.
+fn valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(config: &str) -> Result<RequestIds> {
+        RequestIds::new(&toml::from_str(config).unwrap())
+    }
+
+    fn request(id: Option<&str>) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        if let Some(id) = id {
+            req.insert_header("X-Request-Id", id).unwrap();
+        }
+        req
+    }
+
+    fn generated(id: &str) -> bool {
+        Uuid::parse_str(id).is_ok_and(|uuid| uuid.get_version_num() == 7)
+    }
+
+    #[test]
+    fn test_trust() {
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        let client = Some("192.0.2.1".parse().unwrap());
+        let req = request(Some("abc-123"));
+
+        let ids = ids("[proxy_headers]\ntrusted_proxies = [\"10.0.0.0/8\"]").unwrap();
+        assert_eq!(ids.id(&req, proxy), "abc-123");
+        assert!(generated(&ids.id(&req, client)));
+        assert!(generated(&ids.id(&req, None)));
+        assert!(generated(&ids.id(&request(None), proxy)));
+
+        let ids = self::ids("[request_id]\ntrust = \"all\"").unwrap();
+        assert_eq!(ids.id(&req, client), "abc-123");
+        assert_eq!(ids.id(&req, None), "abc-123");
+
+        let ids = self::ids(
+            "[request_id]\ntrust = \"none\"\n\
+             [proxy_headers]\ntrusted_proxies = [\"10.0.0.0/8\"]",
+        )
+        .unwrap();
+        assert!(generated(&ids.id(&req, proxy)));
+
+        // Generated IDs are unique
+        assert_ne!(ids.id(&req, proxy), ids.id(&req, proxy));
+    }
+
+    #[test]
+    fn test_header() {
+        let ids = ids("[request_id]\nheader = \"X-Trace\"\ntrust = \"all\"").unwrap();
+        let mut req = request(Some("ignored"));
+        assert!(generated(&ids.id(&req, None)));
+        req.insert_header("X-Trace", "kept").unwrap();
+        assert_eq!(ids.id(&req, None), "kept");
+
+        assert!(self::ids("[request_id]\nheader = \"bad header\"").is_err());
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        let ids = ids("[request_id]\ntrust = \"all\"").unwrap();
+        let longest = "a".repeat(MAX_REQUEST_ID_LEN);
+        assert_eq!(ids.id(&request(Some(&longest)), None), longest);
+
+        let overlong = "a".repeat(MAX_REQUEST_ID_LEN + 1);
+        for id in [overlong.as_str(), "", "has space", "tab\tid", "caf\u{e9}"] {
+            let mut req = request(None);
+            req.insert_header("X-Request-Id", id.as_bytes()).unwrap();
+            let replaced = ids.id(&req, None);
+            assert!(generated(&replaced), "{id:?} was kept as {replaced:?}");
+        }
+    }
+
+    #[test]
+    fn test_forward_and_echo() {
+        let mut req = request(None);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        let ids = ids("").unwrap();
+        ids.upstream_request(&mut req, "abc").unwrap();
+        ids.response(&mut resp, "abc").unwrap();
+        assert_eq!(req.headers["x-request-id"], "abc");
+        assert_eq!(resp.headers["x-request-id"], "abc");
+
+        let mut req = request(None);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        let ids = self::ids("[request_id]\nforward = false\necho = false").unwrap();
+        ids.upstream_request(&mut req, "abc").unwrap();
+        ids.response(&mut resp, "abc").unwrap();
+        assert!(req.headers.get("x-request-id").is_none());
+        assert!(resp.headers.get("x-request-id").is_none());
+    }
+}
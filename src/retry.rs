@@ -0,0 +1,298 @@
+use std::io;
+use std::time::Duration;
+
+use http::{Method, StatusCode};
+use pingora::prelude::*;
+use pingora_limits::rate::Rate;
+
+use crate::config::{RetryConfig, RetryOn, CONFIG_ERROR};
+
+/// The window the retry budget is counted over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Which failed requests to an upstream are tried again, and when.
+pub struct RetryPolicy {
+    max_attempts: usize,
+    methods: Vec<Method>,
+    retry_on: Vec<RetryOn>,
+    backoff_base: Duration,
+    backoff_max: Duration,
+    budget: RetryBudget,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &RetryConfig) -> Result<Self> {
+        if config.max_attempts == 0 {
+            return Error::e_explain(CONFIG_ERROR, "retry max_attempts must be at least 1");
+        }
+        if !(config.budget_ratio >= 0.0 && config.budget_ratio.is_finite()) {
+            return Error::e_explain(CONFIG_ERROR, "retry budget_ratio must not be negative");
+        }
+        let methods = config
+            .methods
+            .iter()
+            .map(|m| {
+                Method::from_bytes(m.to_ascii_uppercase().as_bytes())
+                    .or_err_with(CONFIG_ERROR, || format!("invalid retry method {m:?}"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(RetryPolicy {
+            max_attempts: config.max_attempts,
+            methods,
+            retry_on: config.retry_on.clone(),
+            backoff_base: Duration::from_millis(config.backoff_base_ms),
+            backoff_max: Duration::from_millis(config.backoff_max_ms),
+            budget: RetryBudget {
+                ratio: config.budget_ratio,
+                min_retries: f64::from(config.budget_min_per_sec) * BUDGET_WINDOW.as_secs_f64(),
+                requests: Rate::new(BUDGET_WINDOW),
+                retries: Rate::new(BUDGET_WINDOW),
+            },
+        })
+    }
+
+    /// Counts a request against the retry budget, once however often it is
+    /// tried.
+    pub fn request(&self) {
+        if self.max_attempts > 1 {
+            self.budget.requests.observe(&(), 1);
+        }
+    }
+
+    /// Whether a request that failed on its `attempt`th try is tried again,
+    /// which takes a retry from the budget.
+    pub fn retries(&self, attempt: usize, method: &Method, failure: RetryOn) -> bool {
+        attempt < self.max_attempts
+            && self.retry_on.contains(&failure)
+            && (failure == RetryOn::ConnectFailure || self.methods.contains(method))
+            && self.budget.withdraw()
+    }
+
+    /// How long to wait before the `retry`th retry: a random time up to the
+    /// base backoff doubled for each retry before ("full jitter").
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exponent = u32::try_from(retry.saturating_sub(1)).unwrap_or(u32::MAX).min(16);
+        let ceiling = self.backoff_base.saturating_mul(1 << exponent).min(self.backoff_max);
+        ceiling.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Retries allowed per window: a share of the requests plus a minimum
+struct RetryBudget {
+    ratio: f64,
+    min_retries: f64,
+    requests: Rate,
+    retries: Rate,
+}
+
+impl RetryBudget {
+    /// Takes a retry if the budget has one left.
+    fn withdraw(&self) -> bool {
+        let requests = self.requests.observe(&(), 0) as f64;
+        let retries = self.retries.observe(&(), 0) as f64;
+        if retries >= self.min_retries + self.ratio * requests {
+            return false;
+        }
+        self.retries.observe(&(), 1);
+        true
+    }
+}
+
+/// The label of a failure in metrics and logs
+pub fn name(failure: RetryOn) -> &'static str {
+    match failure {
+        RetryOn::ConnectFailure => "connect_failure",
+        RetryOn::Reset => "reset",
+        RetryOn::BadGateway => "502",
+        RetryOn::ServiceUnavailable => "503",
+    }
+}
+
+/// The kind of failure of an error while proxying, `None` if it is not one
+/// that retries can apply to.
+pub fn failure(e: &Error) -> Option<RetryOn> {
+    let reset = matches!(e.etype(), ConnectionClosed)
+        || e
+            .root_cause()
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::UnexpectedEof
+                )
+            });
+    reset.then_some(RetryOn::Reset)
+}
+
+/// The kind of failure a response status is, if any.
+pub fn status_failure(status: StatusCode) -> Option<RetryOn> {
+    match status {
+        StatusCode::BAD_GATEWAY => Some(RetryOn::BadGateway),
+        StatusCode::SERVICE_UNAVAILABLE => Some(RetryOn::ServiceUnavailable),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_policy(config: RetryConfig) -> RetryPolicy {
+        RetryPolicy::new(&config).unwrap()
+    }
+
+    /// A policy of 3 tries whose budget does not run out
+    fn unlimited() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 3,
+            budget_min_per_sec: 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        for config in [
+            RetryConfig {
+                max_attempts: 0,
+                ..Default::default()
+            },
+            RetryConfig {
+                budget_ratio: -0.1,
+                ..Default::default()
+            },
+            RetryConfig {
+                budget_ratio: f64::NAN,
+                ..Default::default()
+            },
+            RetryConfig {
+                methods: vec!["G ET".to_string()],
+                ..Default::default()
+            },
+        ] {
+            assert!(RetryPolicy::new(&config).is_err(), "{config:?}");
+        }
+    }
+
+    #[test]
+    fn test_backoff_bounds() {
+        let policy = new_policy(RetryConfig {
+            backoff_base_ms: 100,
+            backoff_max_ms: 1000,
+            ..unlimited()
+        });
+        let ms = Duration::from_millis;
+        for (retry, ceiling) in [
+            (0, ms(100)),
+            (1, ms(100)),
+            (2, ms(200)),
+            (3, ms(400)),
+            (4, ms(800)),
+            (5, ms(1000)),
+            (40, ms(1000)),
+            (usize::MAX, ms(1000)),
+        ] {
+            let waits: Vec<Duration> = (0..200).map(|_| policy.backoff(retry)).collect();
+            assert!(waits.iter().all(|wait| *wait <= ceiling), "{retry}");
+            // Spread over the whole range, not stuck at either end
+            assert!(waits.iter().any(|wait| *wait < ceiling / 4), "{retry}");
+            assert!(waits.iter().any(|wait| *wait > ceiling * 3 / 4), "{retry}");
+        }
+
+        let none = new_policy(RetryConfig {
+            backoff_base_ms: 0,
+            ..unlimited()
+        });
+        assert_eq!(none.backoff(3), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_methods() {
+        let policy = new_policy(unlimited());
+        for method in [Method::GET, Method::HEAD, Method::OPTIONS, Method::PUT, Method::DELETE] {
+            assert!(policy.retries(1, &method, RetryOn::Reset), "{method}");
+            assert!(policy.retries(1, &method, RetryOn::ServiceUnavailable), "{method}");
+        }
+        // Requests that may have been acted upon are not sent twice...
+        for method in [Method::POST, Method::PATCH, Method::CONNECT] {
+            for failure in [RetryOn::Reset, RetryOn::BadGateway, RetryOn::ServiceUnavailable] {
+                assert!(!policy.retries(1, &method, failure), "{method} {failure:?}");
+            }
+            // ...unless they never reached the upstream
+            assert!(policy.retries(1, &method, RetryOn::ConnectFailure), "{method}");
+        }
+
+        let post = new_policy(RetryConfig {
+            methods: vec!["post".to_string()],
+            ..unlimited()
+        });
+        assert!(post.retries(1, &Method::POST, RetryOn::Reset));
+        assert!(!post.retries(1, &Method::GET, RetryOn::Reset));
+    }
+
+    #[test]
+    fn test_failures_and_attempts() {
+        let policy = new_policy(RetryConfig {
+            retry_on: vec![RetryOn::ConnectFailure, RetryOn::ServiceUnavailable],
+            ..unlimited()
+        });
+        assert!(policy.retries(1, &Method::GET, RetryOn::ConnectFailure));
+        assert!(policy.retries(2, &Method::GET, RetryOn::ServiceUnavailable));
+        assert!(!policy.retries(1, &Method::GET, RetryOn::Reset));
+        assert!(!policy.retries(1, &Method::GET, RetryOn::BadGateway));
+        // The third try is the last
+        assert!(!policy.retries(3, &Method::GET, RetryOn::ConnectFailure));
+
+        let once = new_policy(RetryConfig::default());
+        assert!(!once.retries(1, &Method::GET, RetryOn::ConnectFailure));
+
+        assert_eq!(status_failure(StatusCode::BAD_GATEWAY), Some(RetryOn::BadGateway));
+        assert_eq!(status_failure(StatusCode::GATEWAY_TIMEOUT), None);
+        assert_eq!(failure(&Error::explain(ConnectionClosed, "")), Some(RetryOn::Reset));
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(failure(&Error::because(ReadError, "", reset)), Some(RetryOn::Reset));
+        assert_eq!(failure(&Error::explain(ReadTimedout, "")), None);
+    }
+
+    #[test]
+    fn test_budget() {
+        let policy = new_policy(RetryConfig {
+            max_attempts: 5,
+            budget_ratio: 0.5,
+            budget_min_per_sec: 0,
+            ..Default::default()
+        });
+        // Nothing to take a share of yet
+        assert!(!policy.retries(1, &Method::GET, RetryOn::Reset));
+        for _ in 0..4 {
+            policy.request();
+        }
+        assert!(policy.retries(1, &Method::GET, RetryOn::Reset));
+        assert!(policy.retries(2, &Method::GET, RetryOn::Reset));
+        // Used up, even for failures that are otherwise always retried
+        assert!(!policy.retries(1, &Method::GET, RetryOn::Reset));
+        assert!(!policy.retries(1, &Method::POST, RetryOn::ConnectFailure));
+        // Retries the policy turns down take nothing from the budget
+        policy.request();
+        policy.request();
+        assert!(!policy.retries(5, &Method::GET, RetryOn::Reset));
+        assert!(!policy.retries(1, &Method::POST, RetryOn::Reset));
+        assert!(policy.retries(1, &Method::GET, RetryOn::Reset));
+        assert!(!policy.retries(1, &Method::GET, RetryOn::Reset));
+
+        // The minimum holds without any requests
+        let minimum = new_policy(RetryConfig {
+            max_attempts: 2,
+            budget_ratio: 0.0,
+            budget_min_per_sec: 1,
+            ..Default::default()
+        });
+        let allowed = (0..20)
+            .filter(|_| minimum.retries(1, &Method::GET, RetryOn::Reset))
+            .count();
+        assert_eq!(allowed as f64, BUDGET_WINDOW.as_secs_f64());
+    }
+}
@@ -0,0 +1,234 @@
+use pingora::prelude::*;
+use regex::Regex;
+
+use crate::config::{RewriteConfig, CONFIG_ERROR};
+
+/// The request URI rewrite of a route
+pub struct UriRewrite {
+    strip_prefix: Option<String>,
+    regex: Option<(Regex, String)>,
+    add_prefix: Option<String>,
+    clear_query: bool,
+    remove_params: Vec<String>,
+    /// Parameters set, already encoded
+    set_params: Vec<(String, String)>,
+}
+
+impl UriRewrite {
+    pub fn new(config: &RewriteConfig) -> Result<Self> {
+        let regex = match (&config.regex, &config.replacement) {
+            (Some(regex), Some(replacement)) => {
+                let regex = Regex::new(regex)
+                    .or_err_with(CONFIG_ERROR, || format!("invalid rewrite regex {regex:?}"))?;
+                Some((regex, replacement.clone()))
+            }
+            (None, None) => None,
+            _ => {
+                return Error::e_explain(
+                    CONFIG_ERROR,
+                    "rewrite needs both regex and replacement",
+                )
+            }
+        };
+        if let Some(prefix) = config.add_prefix.as_ref().filter(|p| !p.starts_with('/')) {
+            return Error::e_explain(
+                CONFIG_ERROR,
+                format!("rewrite add_prefix {prefix:?} does not start with /"),
+            );
+        }
+        Ok(UriRewrite {
+            strip_prefix: config.strip_prefix.clone(),
+            regex,
+            add_prefix: config.add_prefix.clone(),
+            clear_query: config.query.clear,
+            remove_params: config.query.remove.iter().map(|name| encode(name)).collect(),
+            set_params: config
+                .query
+                .set
+                .iter()
+                .map(|(name, value)| (encode(name), encode(value)))
+                .collect(),
+        })
+    }
+
+    /// Rewrites an origin-form request-target, `/path?query`.
+    pub fn apply(&self, target: &str) -> String {
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let mut path = path.to_string();
+        if let Some(stripped) = self
+            .strip_prefix
+            .as_deref()
+            .and_then(|prefix| strip_segments(&path, prefix))
+        {
+            path = stripped.to_string();
+        }
+        if let Some((regex, replacement)) = &self.regex {
+            path = regex.replace_all(&path, replacement.as_str()).into_owned();
+        }
+        if let Some(prefix) = &self.add_prefix {
+            path = format!("{}/{}", prefix.trim_end_matches('/'), path.trim_start_matches('/'));
+        }
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+
+        let mut params: Vec<&str> = match (query, self.clear_query) {
+            (Some(query), false) => query.split('&').filter(|p| !p.is_empty()).collect(),
+            _ => vec![],
+        };
+        params.retain(|param| {
+            let name = param.split_once('=').map_or(*param, |(name, _)| name);
+            !self.remove_params.iter().any(|removed| removed == name)
+                && !self.set_params.iter().any(|(set, _)| set == name)
+        });
+        let set: Vec<String> = self
+            .set_params
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect();
+        params.extend(set.iter().map(String::as_str));
+
+        if params.is_empty() {
+            path
+        } else {
+            format!("{path}?{}", params.join("&"))
+        }
+    }
+}
+
+/// Removes `prefix` from the start of `path` if it ends there or at a `/`,
+/// so `/api` strips `/api/users` but not `/apiv2`.
+fn strip_segments<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(prefix)?;
+    (rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')).then_some(rest)
+}
+
+/// Percent-encodes a query string parameter name or value.
+fn encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for b in text.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(char::from(b));
+        } else {
+            encoded.push_str(&format!("%{b:02X}"));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn regex(regex: &str, replacement: &str) -> RewriteConfig {
+        RewriteConfig {
+            regex: Some(regex.to_string()),
+            replacement: Some(replacement.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn rewrite(config: RewriteConfig) -> UriRewrite {
+        UriRewrite::new(&config).unwrap()
+    }
+
+    #[test]
+    fn test_regex_captures() {
+        let numbered = rewrite(regex(r"^/users/(\d+)/posts/(\d+)$", "/posts/$2/by/$1"));
+        assert_eq!(numbered.apply("/users/7/posts/42"), "/posts/42/by/7");
+        let named = rewrite(regex(r"^/v(?<version>\d)/(?<rest>.*)", "/${rest}/v${version}"));
+        assert_eq!(named.apply("/v2/items/1"), "/items/1/v2");
+        // Every match is replaced, and paths not matching are left alone
+        let all = rewrite(regex("_", "-"));
+        assert_eq!(all.apply("/a_b_c"), "/a-b-c");
+        assert_eq!(numbered.apply("/other"), "/other");
+        // A replacement removing everything still leaves an absolute path
+        assert_eq!(rewrite(regex("^/old/", "")).apply("/old/page"), "/page");
+    }
+
+    #[test]
+    fn test_query_preserved() {
+        let rewrite = rewrite(RewriteConfig {
+            strip_prefix: Some("/api".to_string()),
+            ..regex(r"^/items/(\d+)$", "/item/$1")
+        });
+        assert_eq!(rewrite.apply("/api/items/5?a=1&b=2&a=3"), "/item/5?a=1&b=2&a=3");
+        assert_eq!(rewrite.apply("/api/items/5?x=%2F%3F&flag"), "/item/5?x=%2F%3F&flag");
+        // Captures are only taken from the path, not the query
+        assert_eq!(rewrite.apply("/api/items/5?id=6"), "/item/5?id=6");
+        assert_eq!(rewrite.apply("/api/items/5?"), "/item/5");
+        assert_eq!(rewrite.apply("/api/items/5"), "/item/5");
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let rewrite = rewrite(RewriteConfig {
+            strip_prefix: Some("/api/billing".to_string()),
+            add_prefix: Some("/v2/".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(rewrite.apply("/api/billing/invoices?page=2"), "/v2/invoices?page=2");
+        assert_eq!(rewrite.apply("/api/billing"), "/v2/");
+        assert_eq!(rewrite.apply("/other"), "/v2/other");
+    }
+
+    #[test]
+    fn test_strip_segments() {
+        let rewrite = rewrite(RewriteConfig {
+            strip_prefix: Some("/api".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(rewrite.apply("/api/users"), "/users");
+        assert_eq!(rewrite.apply("/api"), "/");
+        assert_eq!(rewrite.apply("/api?x=1"), "/?x=1");
+        // Only whole segments are stripped
+        assert_eq!(rewrite.apply("/apiv2"), "/apiv2");
+        assert_eq!(rewrite.apply("/apiv2/users"), "/apiv2/users");
+        assert_eq!(rewrite.apply("/ap"), "/ap");
+
+        let rewrite = self::rewrite(RewriteConfig {
+            strip_prefix: Some("/api/".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(rewrite.apply("/api/users"), "/users");
+        assert_eq!(rewrite.apply("/api/"), "/");
+        assert_eq!(rewrite.apply("/api"), "/api");
+    }
+
+    #[test]
+    fn test_query_params() {
+        let mut config = RewriteConfig::default();
+        config.query.remove = vec!["debug".to_string()];
+        config.query.set.insert("api key".to_string(), "a&b=c".to_string());
+        config.query.set.insert("v".to_string(), "2".to_string());
+        let rewrite = rewrite(config);
+        assert_eq!(
+            rewrite.apply("/?debug=1&q=x&v=1&debug&api%20key=old"),
+            "/?q=x&api%20key=a%26b%3Dc&v=2"
+        );
+        assert_eq!(rewrite.apply("/"), "/?api%20key=a%26b%3Dc&v=2");
+
+        let mut config = RewriteConfig::default();
+        config.query.clear = true;
+        assert_eq!(UriRewrite::new(&config).unwrap().apply("/p?a=1&b=2"), "/p");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(UriRewrite::new(&regex("(", "x")).is_err());
+        let config = RewriteConfig {
+            regex: Some("a".to_string()),
+            ..Default::default()
+        };
+        assert!(UriRewrite::new(&config).is_err());
+        let config = RewriteConfig {
+            add_prefix: Some("v2".to_string()),
+            ..Default::default()
+        };
+        assert!(UriRewrite::new(&config).is_err());
+    }
+}
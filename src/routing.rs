@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use http::header::HeaderName;
+use http::Method;
+use pingora::prelude::*;
+use regex::Regex;
+
+use crate::authority::{Authority, Host};
+use crate::config::{Config, RouteConfig, UnmatchedAction, CONFIG_ERROR};
+use crate::error_pages::ErrorPage;
+use crate::headers::HeaderRules;
+use crate::rewrite::UriRewrite;
+use crate::timeouts::Timeouts;
+use crate::tls::UpstreamTls;
+use crate::upstream::Upstream;
+
+/// A host name pattern, exact or `*.example.com` for any subdomain
+pub enum HostMatch {
+    Exact(Host),
+    /// `*.example.com`, stored as `.example.com`
+    Subdomain(String),
+}
+
+impl HostMatch {
+    pub fn new(pattern: &str) -> Result<Self> {
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => match Authority::parse(suffix)? {
+                Authority {
+                    host: Host::Domain(name),
+                    port: None,
+                } => Ok(HostMatch::Subdomain(format!(".{name}"))),
+                _ => Error::e_explain(CONFIG_ERROR, format!("invalid host pattern {pattern:?}")),
+            },
+            None => match Authority::parse(pattern)? {
+                Authority { host, port: None } => Ok(HostMatch::Exact(host)),
+                _ => Error::e_explain(CONFIG_ERROR, format!("host pattern {pattern:?} has a port")),
+            },
+        }
+    }
+
+    pub fn matches(&self, host: &Host) -> bool {
+        match (self, host) {
+            (HostMatch::Exact(expected), host) => expected == host,
+            (HostMatch::Subdomain(suffix), Host::Domain(name)) => name.ends_with(suffix.as_str()),
+            (HostMatch::Subdomain(_), _) => false,
+        }
+    }
+}
+
+/// A compiled route
+pub struct Route {
+    pub name: String,
+    host: Option<HostMatch>,
+    host_regex: Option<Regex>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    methods: Vec<Method>,
+    headers: Vec<(HeaderName, Regex)>,
+    pub upstream: Arc<Upstream>,
+    /// Whether requests need proxy authentication, if it is configured
+    pub auth: bool,
+    pub rewrite: Option<UriRewrite>,
+    pub request_headers: HeaderRules,
+    pub response_headers: HeaderRules,
+    pub timeouts: Timeouts,
+    /// Tried before the proxy's own error pages
+    pub error_pages: Vec<ErrorPage>,
+}
+
+impl Route {
+    fn new(
+        index: usize,
+        config: &RouteConfig,
+        upstreams: &HashMap<String, Arc<Upstream>>,
+    ) -> Result<Self> {
+        let name = config
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("#{index}"));
+        let context = |e: Box<Error>| e.more_context(format!("route {name}"));
+
+        let upstream = upstreams.get(&config.upstream).cloned().or_err_with(CONFIG_ERROR, || {
+            format!("route {name} uses unknown upstream {:?}", config.upstream)
+        })?;
+        let host = config
+            .host
+            .as_deref()
+            .map(HostMatch::new)
+            .transpose()
+            .map_err(context)?;
+        let host_regex = config
+            .host_regex
+            .as_deref()
+            .map(|re| Regex::new(&format!("^(?:{re})$")))
+            .transpose()
+            .or_err_with(CONFIG_ERROR, || format!("route {name} has an invalid host_regex"))?;
+        let path_regex = config
+            .path_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .or_err_with(CONFIG_ERROR, || format!("route {name} has an invalid path_regex"))?;
+        let methods = config
+            .methods
+            .iter()
+            .map(|m| Method::from_bytes(m.to_ascii_uppercase().as_bytes()))
+            .collect::<std::result::Result<_, _>>()
+            .or_err_with(CONFIG_ERROR, || format!("route {name} has an invalid method"))?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|(header, value)| {
+                let header = HeaderName::from_bytes(header.as_bytes())
+                    .or_err_with(CONFIG_ERROR, || format!("invalid header name {header:?}"))?;
+                let value = Regex::new(&format!("^(?:{value})$"))
+                    .or_err_with(CONFIG_ERROR, || format!("invalid header pattern {value:?}"))?;
+                Ok((header, value))
+            })
+            .collect::<Result<_>>()
+            .map_err(context)?;
+        let rewrite = config
+            .rewrite
+            .as_ref()
+            .map(UriRewrite::new)
+            .transpose()
+            .map_err(context)?;
+        let request_headers = HeaderRules::new(&config.request_headers).map_err(context)?;
+        let response_headers = HeaderRules::new(&config.response_headers).map_err(context)?;
+        let timeouts = Timeouts::new(&config.timeouts)
+            .map_err(context)?
+            .or(upstream.timeouts);
+        let error_pages = ErrorPage::all(&config.error_pages).map_err(context)?;
+
+        Ok(Route {
+            name,
+            host,
+            host_regex,
+            path_prefix: config.path_prefix.clone(),
+            path_regex,
+            methods,
+            headers,
+            upstream,
+            auth: config.auth.unwrap_or(true),
+            rewrite,
+            request_headers,
+            response_headers,
+            timeouts,
+            error_pages,
+        })
+    }
+
+    fn matches(&self, req: &RequestHeader, host: &Host, path: &str) -> bool {
+        self.host.as_ref().is_none_or(|h| h.matches(host))
+            && self
+                .host_regex
+                .as_ref()
+                .is_none_or(|re| re.is_match(&host.to_string()))
+            && self
+                .path_prefix
+                .as_ref()
+                .is_none_or(|prefix| path.starts_with(prefix.as_str()))
+            && self.path_regex.as_ref().is_none_or(|re| re.is_match(path))
+            && (self.methods.is_empty() || self.methods.contains(&req.method))
+            && self.headers.iter().all(|(name, pattern)| {
+                req.headers
+                    .get_all(name)
+                    .iter()
+                    .any(|v| v.to_str().is_ok_and(|v| pattern.is_match(v)))
+            })
+    }
+}
+
+/// What happens to requests that no route matches
+pub enum Unmatched {
+    Forward,
+    Reject(u16),
+    Upstream(Arc<Upstream>),
+}
+
+/// The routes of the configuration, compiled for matching requests
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+    pub unmatched: Unmatched,
+    /// Every upstream pool, whether routes refer to it or not
+    pub upstreams: Vec<Arc<Upstream>>,
+}
+
+impl Default for RouteTable {
+    /// No routes, every request is forwarded to its own target.
+    fn default() -> Self {
+        RouteTable {
+            routes: vec![],
+            unmatched: Unmatched::Forward,
+            upstreams: vec![],
+        }
+    }
+}
+
+impl RouteTable {
+    /// Compiles the routes and upstreams of `config`, validating references
+    /// between them.
+    ///
+    /// Upstreams of `previous` that `config` leaves unchanged are kept.
+    pub fn new(
+        config: &Config,
+        timeouts: Timeouts,
+        upstream_tls: &UpstreamTls,
+        previous: Option<&RouteTable>,
+    ) -> Result<Self> {
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|(name, upstream)| {
+                let kept = previous
+                    .and_then(|previous| previous.upstreams.iter().find(|u| &u.name == name))
+                    .filter(|kept| kept.is_built_from(upstream, timeouts));
+                let upstream = match kept {
+                    Some(kept) => kept.clone(),
+                    None => Arc::new(Upstream::new(name, upstream, timeouts, upstream_tls)?),
+                };
+                Ok((name.clone(), upstream))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        let routes = config
+            .routes
+            .iter()
+            .enumerate()
+            .map(|(i, route)| Route::new(i, route, &upstreams).map(Arc::new))
+            .collect::<Result<_>>()?;
+
+        let unmatched = match config.unmatched.action {
+            UnmatchedAction::Forward => Unmatched::Forward,
+            UnmatchedAction::Reject => {
+                let status = config.unmatched.status.unwrap_or(404);
+                if !(100..=599).contains(&status) {
+                    return Error::e_explain(
+                        CONFIG_ERROR,
+                        format!("invalid unmatched status {status}"),
+                    );
+                }
+                Unmatched::Reject(status)
+            }
+            UnmatchedAction::Upstream => {
+                let name = config.unmatched.upstream.as_deref().unwrap_or_default();
+                let upstream = upstreams.get(name).cloned().or_err_with(CONFIG_ERROR, || {
+                    format!("unmatched action uses unknown upstream {name:?}")
+                })?;
+                Unmatched::Upstream(upstream)
+            }
+        };
+
+        Ok(RouteTable {
+            routes,
+            unmatched,
+            upstreams: upstreams.into_values().collect(),
+        })
+    }
+
+    /// Returns the first route matching the request.
+    pub fn find(&self, req: &RequestHeader, host: &Host, path: &str) -> Option<Arc<Route>> {
+        self.routes
+            .iter()
+            .find(|route| route.matches(req, host, path))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UPSTREAMS: &str = r#"
+        [upstreams.a]
+        backends = [{ address = "127.0.0.1:9001" }]
+        [upstreams.b]
+        backends = [{ address = "127.0.0.1:9002" }]
+    "#;
+
+    fn table(config: &str) -> Result<RouteTable> {
+        let config: Config = toml::from_str(&format!("{UPSTREAMS}\n{config}")).unwrap();
+        let upstream_tls = UpstreamTls::new(None, false)?;
+        RouteTable::new(&config, Timeouts::default(), &upstream_tls, None)
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut req = RequestHeader::build(method, b"/", None).unwrap();
+        for (name, value) in headers {
+            req.append_header(name.to_string(), *value).unwrap();
+        }
+        req
+    }
+
+    /// The name of the route a GET of `path` on `host` goes to
+    fn route(table: &RouteTable, host: &str, path: &str) -> Option<String> {
+        find(table, &request("GET", &[]), host, path)
+    }
+
+    fn find(table: &RouteTable, req: &RequestHeader, host: &str, path: &str) -> Option<String> {
+        let host = Authority::parse(host).unwrap().host;
+        table.find(req, &host, path).map(|route| route.name.clone())
+    }
+
+    #[test]
+    fn test_host_match() {
+        let exact = HostMatch::new("Example.com").unwrap();
+        assert!(exact.matches(&Host::Domain("example.com".to_string())));
+        assert!(!exact.matches(&Host::Domain("www.example.com".to_string())));
+        let ip = HostMatch::new("127.0.0.1").unwrap();
+        assert!(ip.matches(&Authority::parse("127.0.0.1").unwrap().host));
+
+        let wildcard = HostMatch::new("*.example.com").unwrap();
+        assert!(wildcard.matches(&Host::Domain("www.example.com".to_string())));
+        assert!(wildcard.matches(&Host::Domain("a.b.example.com".to_string())));
+        assert!(!wildcard.matches(&Host::Domain("example.com".to_string())));
+        assert!(!wildcard.matches(&Host::Domain("badexample.com".to_string())));
+
+        for pattern in ["example.com:80", "*.127.0.0.1", "*.", "*.example.com:80"] {
+            assert!(HostMatch::new(pattern).is_err(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn test_hosts() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "exact"
+            host = "api.example.com"
+            upstream = "a"
+            [[routes]]
+            name = "wildcard"
+            host = "*.example.com"
+            upstream = "a"
+            [[routes]]
+            name = "regex"
+            host_regex = "(eu|us)-[0-9]+\\.example\\.net"
+            upstream = "b"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(route(&table, "api.example.com", "/").as_deref(), Some("exact"));
+        assert_eq!(route(&table, "API.example.com:8080", "/").as_deref(), Some("exact"));
+        assert_eq!(route(&table, "www.example.com", "/").as_deref(), Some("wildcard"));
+        assert_eq!(route(&table, "eu-1.example.net", "/").as_deref(), Some("regex"));
+        assert_eq!(route(&table, "us-22.example.net", "/").as_deref(), Some("regex"));
+        assert_eq!(route(&table, "example.com", "/"), None);
+        // The host regex has to match the whole host
+        assert_eq!(route(&table, "eu-1.example.net.evil.test", "/"), None);
+        assert_eq!(route(&table, "xeu-1.example.net", "/"), None);
+    }
+
+    #[test]
+    fn test_paths() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "prefix"
+            path_prefix = "/api/"
+            upstream = "a"
+            [[routes]]
+            name = "regex"
+            path_regex = "^/users/[0-9]+$"
+            upstream = "b"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(route(&table, "example.com", "/api/").as_deref(), Some("prefix"));
+        assert_eq!(route(&table, "example.com", "/api/v1/x").as_deref(), Some("prefix"));
+        assert_eq!(route(&table, "example.com", "/api"), None);
+        assert_eq!(route(&table, "example.com", "/users/42").as_deref(), Some("regex"));
+        assert_eq!(route(&table, "example.com", "/users/42/posts"), None);
+        assert_eq!(route(&table, "example.com", "/users/me"), None);
+    }
+
+    #[test]
+    fn test_methods_and_headers() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "write"
+            methods = ["post", "PUT"]
+            upstream = "a"
+            [[routes]]
+            name = "canary"
+            headers = { x-canary = "yes|true", x-region = "eu-.*" }
+            upstream = "b"
+            "#,
+        )
+        .unwrap();
+        let host = "example.com";
+        let post = request("POST", &[]);
+        assert_eq!(find(&table, &post, host, "/").as_deref(), Some("write"));
+        assert_eq!(find(&table, &request("PUT", &[]), host, "/").as_deref(), Some("write"));
+        assert_eq!(find(&table, &request("GET", &[]), host, "/"), None);
+
+        let canary = request("GET", &[("X-Canary", "true"), ("X-Region", "eu-west")]);
+        assert_eq!(find(&table, &canary, host, "/").as_deref(), Some("canary"));
+        // Every header has to match, on its whole value
+        let partial = request("GET", &[("X-Canary", "true")]);
+        assert_eq!(find(&table, &partial, host, "/"), None);
+        let substring = request("GET", &[("X-Canary", "not-true"), ("X-Region", "eu-west")]);
+        assert_eq!(find(&table, &substring, host, "/"), None);
+        // Any of several values of a header will do
+        let repeated = request(
+            "GET",
+            &[("X-Canary", "no"), ("X-Canary", "yes"), ("X-Region", "eu-1")],
+        );
+        assert_eq!(find(&table, &repeated, host, "/").as_deref(), Some("canary"));
+    }
+
+    #[test]
+    fn test_first_match() {
+        let table = table(
+            r#"
+            [[routes]]
+            path_prefix = "/api/admin"
+            upstream = "b"
+            [[routes]]
+            name = "api"
+            host = "example.com"
+            path_prefix = "/api"
+            upstream = "a"
+            [[routes]]
+            name = "catch-all"
+            upstream = "b"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(route(&table, "example.com", "/api/admin/x").as_deref(), Some("#0"));
+        assert_eq!(route(&table, "example.com", "/api/x").as_deref(), Some("api"));
+        assert_eq!(route(&table, "other.test", "/api/x").as_deref(), Some("catch-all"));
+        let found = table.find(&request("GET", &[]), &Host::Domain("x".into()), "/").unwrap();
+        assert_eq!(found.upstream.name, "b");
+    }
+
+    #[test]
+    fn test_auth() {
+        let table = table(
+            r#"
+            [[routes]]
+            name = "public"
+            path_prefix = "/public"
+            auth = false
+            upstream = "a"
+            [[routes]]
+            name = "private"
+            upstream = "a"
+            "#,
+        )
+        .unwrap();
+        let find = |path| table.find(&request("GET", &[]), &Host::Domain("x".into()), path);
+        assert!(!find("/public/x").unwrap().auth);
+        assert!(find("/x").unwrap().auth);
+    }
+
+    #[test]
+    fn test_unmatched() {
+        assert!(matches!(table("").unwrap().unmatched, Unmatched::Forward));
+        let reject = table("[unmatched]\naction = \"reject\"").unwrap();
+        assert!(matches!(reject.unmatched, Unmatched::Reject(404)));
+        let reject = table("[unmatched]\naction = \"reject\"\nstatus = 451").unwrap();
+        assert!(matches!(reject.unmatched, Unmatched::Reject(451)));
+        let upstream = table("[unmatched]\naction = \"upstream\"\nupstream = \"b\"").unwrap();
+        assert!(matches!(&upstream.unmatched, Unmatched::Upstream(u) if u.name == "b"));
+
+        for config in [
+            "[unmatched]\naction = \"reject\"\nstatus = 600",
+            "[unmatched]\naction = \"upstream\"",
+            "[unmatched]\naction = \"upstream\"\nupstream = \"c\"",
+            "[[routes]]\nupstream = \"c\"",
+            "[[routes]]\nupstream = \"a\"\npath_regex = \"(\"",
+            "[[routes]]\nupstream = \"a\"\nhost_regex = \"[\"",
+            "[[routes]]\nupstream = \"a\"\nmethods = [\"G ET\"]",
+            "[[routes]]\nupstream = \"a\"\nheaders = { \"x y\" = \"1\" }",
+        ] {
+            assert!(table(config).is_err(), "{config}");
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use http::header::USER_AGENT;
+use http::{HeaderMap, Uri};
+use log::error;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::trace::{Span, SpanKind, Status, TraceContextExt, Tracer, TracerProvider};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::{Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::runtime;
+use opentelemetry_sdk::trace::span_processor_with_async_runtime::BatchSpanProcessor;
+use opentelemetry_sdk::trace::{Sampler, SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use pingora::prelude::*;
+use pingora::protocols::Digest;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::upstreams::peer::Peer;
+
+use crate::authority::Host;
+use crate::config::{OtlpProtocol, TracingConfig, CONFIG_ERROR};
+
+/// Request spans, continuing and propagating W3C trace context and exported
+/// over OTLP.
+///
+/// The exporter is set up when the background service starts, on its
+/// runtime: that is after pingora daemonized, whose fork would leave a
+/// runtime started earlier without threads. Requests arriving before then
+/// are not traced.
+#[derive(Clone)]
+pub struct Tracing {
+    config: TracingConfig,
+    exporter: Arc<OnceLock<(SdkTracerProvider, SdkTracer)>>,
+}
+
+impl Tracing {
+    pub fn new(config: &TracingConfig) -> Result<Self> {
+        if !(0.0..=1.0).contains(&config.sample_ratio) {
+            return Error::e_explain(CONFIG_ERROR, "tracing sample_ratio must be from 0 to 1");
+        }
+        if let Some(endpoint) = &config.endpoint {
+            endpoint.parse::<Uri>().or_err_with(CONFIG_ERROR, || {
+                format!("invalid tracing endpoint {endpoint:?}")
+            })?;
+        }
+        Ok(Tracing {
+            config: config.clone(),
+            exporter: Arc::default(),
+        })
+    }
+
+    /// Sets up the exporter, on the current runtime.
+    fn export(&self) -> Result<()> {
+        let config = &self.config;
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let exporter = match config.protocol {
+            OtlpProtocol::Grpc => {
+                let mut builder = SpanExporter::builder().with_tonic().with_timeout(timeout);
+                if let Some(endpoint) = &config.endpoint {
+                    builder = builder.with_endpoint(endpoint);
+                }
+                builder.build()
+            }
+            OtlpProtocol::Http => {
+                let mut builder = SpanExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary)
+                    .with_timeout(timeout);
+                if let Some(endpoint) = &config.endpoint {
+                    builder = builder.with_endpoint(endpoint);
+                }
+                builder.build()
+            }
+        }
+        .map_err(|e| Error::because(InternalError, "setting up the trace exporter", e))?;
+
+        let sampler = Sampler::TraceIdRatioBased(config.sample_ratio);
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .build();
+        let provider = SdkTracerProvider::builder()
+            .with_span_processor(BatchSpanProcessor::builder(exporter, runtime::Tokio).build())
+            .with_sampler(Sampler::ParentBased(Box::new(sampler)))
+            .with_resource(resource)
+            .build();
+        let tracer = provider.tracer("pinproxy");
+        self.exporter
+            .set((provider, tracer))
+            .map_err(|_| Error::explain(InternalError, "trace exporter already started"))
+    }
+
+    /// Starts the span of a request, as a child of its `traceparent` if it
+    /// has one, `None` until the exporter runs.
+    pub fn start(&self, session: &Session, request_id: &str) -> Option<RequestTrace> {
+        let (_, tracer) = self.exporter.get()?;
+        let req = session.req_header();
+        let parent = TraceContextPropagator::new().extract(&HeaderExtractor(&req.headers));
+        let mut attributes = vec![
+            KeyValue::new("http.request.method", req.method.to_string()),
+            KeyValue::new("url.full", String::from_utf8_lossy(req.raw_path()).into_owned()),
+            KeyValue::new("pinproxy.request_id", request_id.to_string()),
+        ];
+        if let Some(client) = session.client_addr().and_then(|addr| addr.as_inet()) {
+            let client = client.ip().to_canonical().to_string();
+            attributes.push(KeyValue::new("client.address", client));
+        }
+        if let Some(agent) = req.headers.get(USER_AGENT).and_then(|v| v.to_str().ok()) {
+            attributes.push(KeyValue::new("user_agent.original", agent.to_string()));
+        }
+        let span = tracer
+            .span_builder(req.method.to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes(attributes)
+            .start_with_context(tracer, &parent);
+        Some(RequestTrace {
+            tracer: tracer.clone(),
+            context: parent.with_span(span),
+            connecting: None,
+            responding: None,
+        })
+    }
+}
+
+/// Runs the exporter, which exports the spans still queued when the server
+/// shuts down.
+#[async_trait]
+impl BackgroundService for Tracing {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if let Err(e) = self.export() {
+            error!("Failed to start the trace exporter: {e}");
+            return;
+        }
+        let _ = shutdown.changed().await;
+        let Some((provider, _)) = self.exporter.get() else {
+            return;
+        };
+        let provider = provider.clone();
+        // Blocks until the exporter runtime is done
+        if let Ok(Err(e)) = tokio::task::spawn_blocking(move || provider.shutdown()).await {
+            error!("Failed to export the remaining spans: {e}");
+        }
+    }
+}
+
+/// The spans of a single request
+pub struct RequestTrace {
+    tracer: SdkTracer,
+    /// Carries the request span, the parent of all others
+    context: Context,
+    /// Since when an upstream connection is being established
+    connecting: Option<SystemTime>,
+    /// Since when the response is being sent
+    responding: Option<SystemTime>,
+}
+
+impl RequestTrace {
+    /// Records a child span that already ended.
+    fn child(
+        &self,
+        name: &'static str,
+        kind: SpanKind,
+        (start, end): (SystemTime, SystemTime),
+        attributes: Vec<KeyValue>,
+        error: Option<String>,
+    ) {
+        let mut span = self
+            .tracer
+            .span_builder(name)
+            .with_kind(kind)
+            .with_start_time(start)
+            .with_attributes(attributes)
+            .start_with_context(&self.tracer, &self.context);
+        if let Some(error) = error {
+            span.set_status(Status::error(error));
+        }
+        span.end_with_timestamp(end);
+    }
+
+    /// Records a DNS lookup that started at `start`.
+    pub fn resolved(&self, host: &Host, start: SystemTime, result: &Result<Vec<SocketAddr>>) {
+        // Literal addresses need no lookup
+        if let Host::Domain(name) = host {
+            let attributes = vec![KeyValue::new("server.address", name.to_string())];
+            let error = result.as_ref().err().map(|e| e.to_string().trim().to_string());
+            let end = SystemTime::now();
+            self.child("dns lookup", SpanKind::Client, (start, end), attributes, error);
+        }
+    }
+
+    /// Marks the start of getting an upstream connection.
+    pub fn connecting(&mut self) {
+        self.connecting = Some(SystemTime::now());
+    }
+
+    /// Records the TCP connect and TLS handshake of a new upstream
+    /// connection, from the times pingora noted in its digest.
+    pub fn connected(&mut self, reused: bool, peer: &HttpPeer, digest: Option<&Digest>) {
+        let Some(start) = self.connecting.take() else {
+            return;
+        };
+        if reused {
+            return;
+        }
+        let now = SystemTime::now();
+        // One entry per layer: TCP first, then TLS
+        let established: Vec<SystemTime> = digest
+            .map(|d| d.timing_digest.iter().flatten().map(|t| t.established_ts).collect())
+            .unwrap_or_default();
+        let connected = established.first().copied().unwrap_or(now);
+        let attributes = peer_attributes(peer);
+        self.child("connect", SpanKind::Client, (start, connected), attributes.clone(), None);
+        if let Some(handshaken) = established.get(1) {
+            let times = (connected, *handshaken);
+            self.child("tls handshake", SpanKind::Client, times, attributes, None);
+        }
+    }
+
+    /// Records a failed attempt to connect upstream, so that a retry starts
+    /// a span of its own.
+    pub fn connect_failed(&mut self, peer: &HttpPeer, error: &pingora::Error) {
+        if let Some(start) = self.connecting.take() {
+            let times = (start, SystemTime::now());
+            let error = Some(error.to_string().trim().to_string());
+            self.child("connect", SpanKind::Client, times, peer_attributes(peer), error);
+        }
+    }
+
+    /// Sets `traceparent` and `tracestate` of a request sent upstream.
+    pub fn inject(&self, req: &mut RequestHeader) {
+        TraceContextPropagator::new().inject_context(&self.context, &mut HeaderInjector(req));
+    }
+
+    /// Marks the start of sending the response.
+    pub fn responding(&mut self) {
+        self.responding.get_or_insert_with(SystemTime::now);
+    }
+
+    /// Ends the request span, and the spans still open.
+    ///
+    /// `status` is 0 when no response was sent.
+    pub fn finish(&self, status: u16, bytes_sent: u64, error: Option<&pingora::Error>) {
+        let now = SystemTime::now();
+        let error_message = error.map(|e| e.to_string().trim().to_string());
+        if let Some(start) = self.connecting {
+            let error = error_message.clone().or(Some("connection failed".to_string()));
+            self.child("connect", SpanKind::Client, (start, now), vec![], error);
+        }
+        if let Some(start) = self.responding {
+            let attributes = vec![KeyValue::new("http.response.body.size", bytes_sent as i64)];
+            let error = error_message.clone();
+            self.child("response", SpanKind::Internal, (start, now), attributes, error);
+        }
+
+        let span = self.context.span();
+        if status != 0 {
+            span.set_attribute(KeyValue::new("http.response.status_code", i64::from(status)));
+        }
+        // Client errors are the client's, only server errors fail the span
+        match error_message {
+            Some(message) => span.set_status(Status::error(message)),
+            None if status >= 500 => span.set_status(Status::error("")),
+            None => {}
+        }
+        span.end_with_timestamp(now);
+    }
+}
+
+fn peer_attributes(peer: &HttpPeer) -> Vec<KeyValue> {
+    match peer.address().as_inet() {
+        Some(addr) => vec![
+            KeyValue::new("network.peer.address", addr.ip().to_string()),
+            KeyValue::new("network.peer.port", i64::from(addr.port())),
+        ],
+        None => vec![KeyValue::new("network.peer.address", peer.address().to_string())],
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut RequestHeader);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        // The propagator only produces valid names and values
+        let _ = self.0.insert_header(key.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+    use opentelemetry_proto::tonic::trace::v1::span::SpanKind as ProtoSpanKind;
+    use opentelemetry_proto::tonic::trace::v1::status::StatusCode;
+    use prost::Message;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::{mpsc, watch};
+
+    use super::*;
+    use crate::config::TracingConfig;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    /// An OTLP/HTTP collector passing on the bodies of export requests
+    async fn collector() -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, sender.clone()));
+            }
+        });
+        (endpoint, receiver)
+    }
+
+    async fn serve(mut stream: TcpStream, bodies: mpsc::UnboundedSender<Vec<u8>>) {
+        let mut buf = vec![];
+        loop {
+            let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                let mut chunk = [0; 4096];
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => buf.extend_from_slice(&chunk[..read]),
+                }
+                continue;
+            };
+            let head = String::from_utf8_lossy(&buf[..end]).to_ascii_lowercase();
+            assert!(head.starts_with("post /v1/traces "), "{head}");
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            while buf.len() < end + 4 + length {
+                let mut chunk = [0; 4096];
+                let read = stream.read(&mut chunk).await.unwrap();
+                assert_ne!(read, 0, "request body cut short");
+                buf.extend_from_slice(&chunk[..read]);
+            }
+            bodies.send(buf[end + 4..end + 4 + length].to_vec()).unwrap();
+            buf.drain(..end + 4 + length);
+            let response = b"HTTP/1.1 200 OK\r\ncontent-type: application/x-protobuf\r\n\
+                content-length: 0\r\n\r\n";
+            stream.write_all(response).await.unwrap();
+        }
+    }
+
+    async fn session(request: &str) -> Session {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = Session::new_h1(Box::new(server));
+        client.write_all(request.as_bytes()).await.unwrap();
+        assert!(session.read_request().await.unwrap());
+        session
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_otlp_export() {
+        let (endpoint, mut bodies) = collector().await;
+        let tracing = Tracing::new(&TracingConfig {
+            protocol: OtlpProtocol::Http,
+            endpoint: Some(endpoint),
+            service_name: "pinproxy-test".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let session = session(&format!(
+            "GET http://example.com/a HTTP/1.1\r\nHost: example.com\r\n\
+            traceparent: 00-{TRACE_ID}-{PARENT_ID}-01\r\n\r\n"
+        ))
+        .await;
+        // Nothing is traced before the exporter runs
+        assert!(tracing.start(&session, "id").is_none());
+
+        let (shutdown, watch) = watch::channel(false);
+        let exporter = tokio::spawn({
+            let tracing = tracing.clone();
+            async move { BackgroundService::start(&tracing, watch).await }
+        });
+        let mut trace = loop {
+            match tracing.start(&session, "id") {
+                Some(trace) => break trace,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        let peer = HttpPeer::new("127.0.0.1:9", false, String::new());
+        // A failed attempt, then a retry
+        trace.connecting();
+        let error = pingora::Error::explain(ConnectRefused, "refused");
+        trace.connect_failed(&peer, &error);
+        trace.connecting();
+        trace.connected(false, &peer, None);
+        let mut upstream = RequestHeader::build("GET", b"/a", None).unwrap();
+        trace.inject(&mut upstream);
+        trace.finish(200, 5, None);
+
+        // Shutting down exports what is queued
+        shutdown.send(true).unwrap();
+        exporter.await.unwrap();
+        let mut spans = vec![];
+        while let Ok(body) = bodies.try_recv() {
+            let request = ExportTraceServiceRequest::decode(body.as_slice()).unwrap();
+            for resource in request.resource_spans {
+                let service = resource.resource.unwrap().attributes;
+                assert!(service.iter().any(|a| a.key == "service.name"));
+                spans.extend(resource.scope_spans.into_iter().flat_map(|s| s.spans));
+            }
+        }
+        assert_eq!(spans.len(), 3, "{spans:?}");
+        let server = spans.iter().find(|s| s.name == "GET").unwrap();
+        let connects: Vec<_> = spans.iter().filter(|s| s.name == "connect").collect();
+        let [failed, connect] = connects[..] else {
+            panic!("{connects:?}");
+        };
+
+        // Each attempt has its own span, failed ones with their error
+        let status = failed.status.as_ref().unwrap();
+        assert_eq!(status.code, StatusCode::Error as i32);
+        assert!(status.message.contains("refused"), "{status:?}");
+        assert!(connect.status.as_ref().is_none_or(|s| s.code != StatusCode::Error as i32));
+        assert!(failed.end_time_unix_nano <= connect.start_time_unix_nano);
+        assert_eq!(failed.parent_span_id, server.span_id);
+
+        // The request span continues the client's trace...
+        assert_eq!(hex(&server.trace_id), TRACE_ID);
+        assert_eq!(hex(&server.parent_span_id), PARENT_ID);
+        assert_eq!(server.kind, ProtoSpanKind::Server as i32);
+        // ...is the parent of the proxy's own spans...
+        assert_eq!(hex(&connect.trace_id), TRACE_ID);
+        assert_eq!(connect.parent_span_id, server.span_id);
+        assert_eq!(connect.kind, ProtoSpanKind::Client as i32);
+        // ...and of the upstream's
+        let traceparent = upstream.headers.get("traceparent").unwrap();
+        let expected = format!("00-{TRACE_ID}-{}-01", hex(&server.span_id));
+        assert_eq!(traceparent.to_str().unwrap(), expected);
+    }
+}
@@ -0,0 +1,89 @@
+use pingora::prelude::*;
+
+use crate::config::CONFIG_ERROR;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment<V> {
+    Literal(String),
+    Variable(V),
+    /// `$http_user_agent` is the `User-Agent` request header
+    Header(String),
+    /// `$1` or `${1}` as written, for templates used as regex replacements
+    Numbered(String),
+}
+
+/// A compiled template of `$name` or `${name}` variables, out of a set of
+/// variables `V` known by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template<V>(Vec<Segment<V>>);
+
+impl<V: Copy> Template<V> {
+    /// Compiles a template, `$$` is a literal `$`.
+    ///
+    /// Numbered variables such as `$1` are not looked up, they are left to
+    /// regex replacements.
+    pub fn parse(template: &str, variables: &[(&str, V)]) -> Result<Self> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(dollar) = rest.find('$') {
+            literal.push_str(&rest[..dollar]);
+            rest = &rest[dollar + 1..];
+            if let Some(after) = rest.strip_prefix('$') {
+                literal.push('$');
+                rest = after;
+                continue;
+            }
+            let start = rest;
+            let name = if let Some(braced) = rest.strip_prefix('{') {
+                let end = braced.find('}').or_err_with(CONFIG_ERROR, || {
+                    format!("unterminated ${{ in template {template:?}")
+                })?;
+                rest = &braced[end + 1..];
+                &braced[..end]
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                let name = &rest[..end];
+                rest = &rest[end..];
+                name
+            };
+
+            let segment = if name.starts_with(|c: char| c.is_ascii_digit()) {
+                Segment::Numbered(format!("${}", &start[..start.len() - rest.len()]))
+            } else if let Some(header) = name.strip_prefix("http_") {
+                Segment::Header(header.replace('_', "-"))
+            } else {
+                let variable = variables
+                    .iter()
+                    .find(|(known, _)| *known == name)
+                    .map(|(_, variable)| *variable)
+                    .or_err_with(CONFIG_ERROR, || {
+                        format!("unknown variable ${name} in template {template:?}")
+                    })?;
+                Segment::Variable(variable)
+            };
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(segment);
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template(segments))
+    }
+
+    pub fn segments(&self) -> &[Segment<V>] {
+        &self.0
+    }
+}
+
+/// Returns a request header as a string.
+pub fn header(req: &RequestHeader, name: &str) -> Option<String> {
+    req.headers
+        .get(name)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+}
@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+
+use pingora::prelude::*;
+use pingora::upstreams::peer::PeerOptions;
+
+use crate::config::{TimeoutsConfig, CONFIG_ERROR};
+
+/// Errors of requests whose exchange with the upstream ran past its total
+/// timeout
+pub const TOTAL_TIMEOUT: ErrorType = ErrorType::Custom("TotalTimeout");
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The timeouts of the exchange with an upstream, `None` where they fall
+/// back to another layer or the default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timeouts {
+    connect: Option<Duration>,
+    tls_handshake: Option<Duration>,
+    read: Option<Duration>,
+    write: Option<Duration>,
+    total: Option<Duration>,
+    idle: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn new(config: &TimeoutsConfig) -> Result<Self> {
+        let duration = |name: &str, ms: Option<u64>| match ms {
+            Some(0) => Error::e_explain(CONFIG_ERROR, format!("timeout {name} must be positive")),
+            ms => Ok(ms.map(Duration::from_millis)),
+        };
+        Ok(Timeouts {
+            connect: duration("connect_ms", config.connect_ms)?,
+            tls_handshake: duration("tls_handshake_ms", config.tls_handshake_ms)?,
+            read: duration("read_ms", config.read_ms)?,
+            write: duration("write_ms", config.write_ms)?,
+            total: duration("total_ms", config.total_ms)?,
+            idle: duration("idle_ms", config.idle_ms)?,
+        })
+    }
+
+    /// These timeouts, with the unset ones taken from `fallback`
+    pub fn or(self, fallback: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(fallback.connect),
+            tls_handshake: self.tls_handshake.or(fallback.tls_handshake),
+            read: self.read.or(fallback.read),
+            write: self.write.or(fallback.write),
+            total: self.total.or(fallback.total),
+            idle: self.idle.or(fallback.idle),
+        }
+    }
+
+    /// How long to wait for an upstream to send something
+    pub fn read(&self) -> Duration {
+        self.read.unwrap_or(DEFAULT_READ_TIMEOUT)
+    }
+
+    /// When an exchange starting now has to be over, if ever
+    pub fn deadline(&self) -> Option<Instant> {
+        self.total.map(|total| Instant::now() + total)
+    }
+
+    /// Sets the timeouts of a connection to an upstream, none of them past
+    /// `deadline`.
+    pub fn apply(&self, options: &mut PeerOptions, deadline: Option<Instant>) {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let bounded = |timeout: Duration| remaining.map_or(timeout, |r| timeout.min(r));
+        let connect = bounded(self.connect.unwrap_or(DEFAULT_CONNECT_TIMEOUT));
+        let handshake = self.tls_handshake.unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT);
+        options.connection_timeout = Some(connect);
+        // pingora only bounds the handshake together with the connect
+        options.total_connection_timeout = Some(bounded(connect + handshake));
+        options.read_timeout = Some(bounded(self.read()));
+        options.write_timeout = Some(bounded(self.write.unwrap_or(DEFAULT_WRITE_TIMEOUT)));
+        if self.idle.is_some() {
+            options.idle_timeout = self.idle;
+        }
+    }
+}
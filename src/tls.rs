@@ -0,0 +1,189 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pingora::listeners::tls::TlsSettings;
+use pingora::listeners::TlsAccept;
+use pingora::prelude::*;
+use pingora::protocols::tls::{CaType, TlsRef};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::tls::ext::{ssl_add_chain_cert, ssl_use_certificate, ssl_use_private_key};
+use pingora::tls::pkey::{PKey, Private};
+use pingora::tls::x509::X509;
+use pingora::upstreams::peer::PeerOptions;
+
+/// How TLS connections to upstreams are verified
+#[derive(Clone, Default)]
+pub struct UpstreamTls {
+    /// Trust anchors replacing the system store, if configured
+    ca: Option<Arc<CaType>>,
+    /// Skip certificate and hostname verification entirely
+    insecure: bool,
+}
+
+impl UpstreamTls {
+    /// Builds the upstream TLS settings, loading `ca_file` as a PEM bundle.
+    pub fn new(ca_file: Option<&str>, insecure: bool) -> Result<Self> {
+        let ca = match ca_file {
+            Some(path) => {
+                let pem = std::fs::read(path)
+                    .or_err_with(FileReadError, || format!("reading CA bundle {path}"))?;
+                let certs = X509::stack_from_pem(&pem)
+                    .or_err_with(InvalidCert, || format!("parsing CA bundle {path}"))?;
+                if certs.is_empty() {
+                    return Error::e_explain(InvalidCert, format!("no certificates in {path}"));
+                }
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+        if insecure {
+            warn!("Upstream TLS certificate verification is disabled");
+        }
+        Ok(UpstreamTls { ca, insecure })
+    }
+
+    /// Applies these settings to the options of a TLS peer.
+    pub fn apply(&self, options: &mut PeerOptions) {
+        if self.insecure {
+            options.verify_cert = false;
+            options.verify_hostname = false;
+        }
+        if let Some(ca) = &self.ca {
+            options.ca = Some(ca.clone());
+        }
+    }
+}
+
+/// How often the listener certificate files are checked for changes
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A certificate chain and the private key it was issued for
+struct CertKey {
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl CertKey {
+    fn load(cert_path: &str, key_path: &str) -> Result<Self> {
+        let pem = std::fs::read(cert_path)
+            .or_err_with(FileReadError, || format!("reading certificate {cert_path}"))?;
+        let chain = X509::stack_from_pem(&pem)
+            .or_err_with(InvalidCert, || format!("parsing certificate {cert_path}"))?;
+        let pem = std::fs::read(key_path)
+            .or_err_with(FileReadError, || format!("reading private key {key_path}"))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .or_err_with(InvalidCert, || format!("parsing private key {key_path}"))?;
+
+        let leaf = chain
+            .first()
+            .or_err_with(InvalidCert, || format!("no certificate in {cert_path}"))?;
+        let matches = leaf
+            .public_key()
+            .map(|public| public.public_eq(&key))
+            .unwrap_or(false);
+        if !matches {
+            return Error::e_explain(
+                InvalidCert,
+                format!("private key {key_path} does not match certificate {cert_path}"),
+            );
+        }
+        Ok(CertKey { chain, key })
+    }
+}
+
+/// The certificate served by TLS listeners, reloaded when its files change.
+///
+/// The certificate is handed out per handshake, so a reload only affects new
+/// connections and established ones are left alone.
+#[derive(Clone)]
+pub struct CertStore {
+    cert_path: String,
+    key_path: String,
+    current: Arc<RwLock<Arc<CertKey>>>,
+    modified: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl CertStore {
+    /// Loads the certificate chain and key, failing if either is unusable.
+    pub fn new(cert_path: &str, key_path: &str) -> Result<Self> {
+        let cert_key = CertKey::load(cert_path, key_path)?;
+        let store = CertStore {
+            cert_path: cert_path.to_string(),
+            key_path: key_path.to_string(),
+            current: Arc::new(RwLock::new(Arc::new(cert_key))),
+            modified: Arc::new(Mutex::new(None)),
+        };
+        *store.modified.lock().unwrap() = store.last_modified();
+        Ok(store)
+    }
+
+    /// Builds the settings of a TLS listener serving this certificate.
+    ///
+    /// With `http2`, ALPN offers HTTP/2 and falls back to HTTP/1.1.
+    pub fn listener_settings(&self, http2: bool) -> Result<TlsSettings> {
+        let mut settings = TlsSettings::with_callbacks(Box::new(self.clone()))?;
+        if http2 {
+            settings.enable_h2();
+        }
+        Ok(settings)
+    }
+
+    /// The latest modification time of the certificate and key files
+    fn last_modified(&self) -> Option<SystemTime> {
+        let mtime = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        mtime(&self.cert_path).max(mtime(&self.key_path))
+    }
+
+    /// Reloads the certificate if its files changed since the last load.
+    ///
+    /// A certificate that fails to load keeps the previous one in service.
+    fn reload_if_changed(&self) {
+        let modified = self.last_modified();
+        if modified.is_none() || *self.modified.lock().unwrap() == modified {
+            return;
+        }
+        match CertKey::load(&self.cert_path, &self.key_path) {
+            Ok(cert_key) => {
+                *self.current.write().unwrap() = Arc::new(cert_key);
+                *self.modified.lock().unwrap() = modified;
+                info!("Reloaded TLS certificate {}", self.cert_path);
+            }
+            // The files may be mid-write, retry on the next tick
+            Err(e) => warn!("Keeping previous TLS certificate: {e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl TlsAccept for CertStore {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) {
+        let cert_key = self.current.read().unwrap().clone();
+        let (leaf, intermediates) = cert_key.chain.split_first().unwrap();
+        if let Err(e) = ssl_use_certificate(ssl, leaf)
+            .and_then(|_| ssl_use_private_key(ssl, &cert_key.key))
+            .and_then(|_| {
+                intermediates
+                    .iter()
+                    .try_for_each(|cert| ssl_add_chain_cert(ssl, cert))
+            })
+        {
+            error!("Failed to set TLS certificate: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CertStore {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(CERT_RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => self.reload_if_changed(),
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use bytes::Bytes;
+use futures::FutureExt;
+use http::header::UPGRADE;
+use log::debug;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+use pingora::protocols::http::ServerSession;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::protocols::l4::stream::Stream as L4Stream;
+use pingora::protocols::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+use crate::authority::Authority;
+use crate::dns::Resolver;
+
+/// Size of the buffer used for each direction of a tunnel
+const TUNNEL_BUF_SIZE: usize = 16 * 1024;
+
+/// Byte counts of a finished CONNECT tunnel
+#[derive(Debug, Clone)]
+pub struct TunnelStats {
+    /// The client address, which the session no longer knows once detached
+    pub client_addr: Option<SocketAddr>,
+    /// The `host:port` the tunnel was opened to
+    pub target: String,
+    /// Bytes received from the client and sent upstream
+    pub bytes_in: u64,
+    /// Bytes received from upstream and sent to the client
+    pub bytes_out: u64,
+}
+
+/// Serves a `CONNECT` request by splicing the client connection to `target`,
+/// connecting to the first of `addrs` that accepts.
+///
+/// The client connection is taken out of `session` once the tunnel is
+/// established; a detached copy of the request is left in its place so that
+/// the rest of the request lifecycle, including `logging`, still sees it.
+pub async fn serve_connect(
+    session: &mut Session,
+    target: &Authority,
+    addrs: &[std::net::SocketAddr],
+    resolver: &Resolver,
+    request_id: &str,
+) -> Result<TunnelStats> {
+    if session.as_downstream().is_http2() {
+        return Error::e_explain(HTTPStatus(405), "CONNECT is only supported over HTTP/1.1");
+    }
+
+    let mut upstream = resolver
+        .connect(addrs)
+        .await
+        .map_err(|e| Error::because(HTTPStatus(502), format!("opening tunnel to {target}"), e))?;
+    upstream.set_nodelay(true).ok();
+
+    let mut resp = ResponseHeader::build(200, None)?;
+    resp.set_reason_phrase(Some("Connection Established"))?;
+    session.write_response_header(Box::new(resp), false).await?;
+
+    let client_addr = session.client_addr().cloned();
+    let pipelined = take_pipelined(session)?;
+    let mut client = take_downstream(session).await?;
+
+    let (mut bytes_in, mut bytes_out) = (0, 0);
+    if !pipelined.is_empty() {
+        upstream
+            .write_all(&pipelined)
+            .await
+            .or_err_with(WriteError, || format!("writing to tunnel to {target}"))?;
+        bytes_in += pipelined.len() as u64;
+    }
+    {
+        let (mut client_read, mut client_write) = tokio::io::split(&mut client);
+        let (mut upstream_read, mut upstream_write) = upstream.split();
+        // An error in either direction tears down the whole tunnel
+        if let Err(e) = tokio::try_join!(
+            splice(&mut client_read, &mut upstream_write, &mut bytes_in),
+            splice(&mut upstream_read, &mut client_write, &mut bytes_out),
+        ) {
+            debug!("[{request_id}] Tunnel to {target} closed with error: {e}");
+        }
+    }
+
+    Ok(TunnelStats {
+        client_addr,
+        target: target.to_string(),
+        bytes_in,
+        bytes_out,
+    })
+}
+
+/// Extracts the authority-form target (`host:port`) of a `CONNECT` request.
+pub fn connect_target(req: &RequestHeader) -> Result<Authority> {
+    // Pingora stores the authority-form request-target as the path
+    let raw = std::str::from_utf8(req.raw_path()).unwrap_or_default();
+    let target = Authority::parse(raw)?;
+    if target.port.is_none() {
+        return Error::e_explain(HTTPStatus(400), format!("CONNECT target {raw:?} has no port"));
+    }
+    Ok(target)
+}
+
+/// Takes the bytes the client sent after the `CONNECT` header that were read
+/// along with it, which the raw stream no longer has.
+fn take_pipelined(session: &mut Session) -> Result<Bytes> {
+    // Bytes after a header without a length are only read as body, until the
+    // connection closes, for upgrade requests
+    session.req_header_mut().insert_header(UPGRADE, "connect")?;
+    // The first read returns what was buffered; waiting for more would stall
+    let read = session.as_downstream_mut().read_request_body().now_or_never();
+    session.req_header_mut().remove_header(&UPGRADE);
+    Ok(read.transpose()?.flatten().unwrap_or_default())
+}
+
+/// Swaps the client connection out of `session` and returns its raw stream.
+async fn take_downstream(session: &mut Session) -> Result<Stream> {
+    let (detached, mut feed) =
+        UnixStream::pair().or_err(InternalError, "creating detached session")?;
+    feed.write_all(&session.as_downstream().to_h1_raw())
+        .await
+        .or_err(InternalError, "creating detached session")?;
+    let mut placeholder = ServerSession::new_http1(Box::new(L4Stream::from(detached)));
+    placeholder.read_request().await?;
+    placeholder.set_keepalive(None);
+
+    match std::mem::replace(&mut *session.downstream_session, placeholder) {
+        ServerSession::H1(h1) => Ok(h1.into_inner()),
+        ServerSession::H2(_) => Error::e_explain(InternalError, "cannot detach an HTTP/2 stream"),
+    }
+}
+
+/// Copies bytes from `reader` to `writer` until EOF, counting them into `total`.
+///
+/// The write side is shut down on EOF so the half-close propagates through the
+/// tunnel.
+async fn splice<R, W>(reader: &mut R, writer: &mut W, total: &mut u64) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; TUNNEL_BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&buf[..n]).await?;
+        // The client stream is buffered, interactive protocols need every chunk
+        writer.flush().await?;
+        *total += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+    use crate::authority::Host;
+
+    fn target(raw: &str) -> Result<Authority> {
+        connect_target(&RequestHeader::build("CONNECT", raw.as_bytes(), None).unwrap())
+    }
+
+    #[test]
+    fn test_connect_target() {
+        let authority = target("example.com:443").unwrap();
+        assert_eq!(authority.host, Host::Domain("example.com".to_string()));
+        assert_eq!(authority.port, Some(443));
+        assert_eq!(target("Example.COM:8443").unwrap().to_string(), "example.com:8443");
+
+        let authority = target("127.0.0.1:22").unwrap();
+        assert_eq!(authority.host, Host::Ipv4(Ipv4Addr::LOCALHOST));
+        let authority = target("[::1]:443").unwrap();
+        assert_eq!(authority.host, Host::Ipv6(Ipv6Addr::LOCALHOST));
+        assert_eq!(authority.port, Some(443));
+    }
+
+    #[test]
+    fn test_invalid_target() {
+        for raw in [
+            // Missing port
+            "example.com",
+            "example.com:",
+            "[::1]",
+            "[::1]:",
+            // A bare IPv6 literal cannot carry a port
+            "::1",
+            "::1:443",
+            // Not the authority-form
+            "/",
+            "/example.com:443",
+            "http://example.com:443",
+            "http://example.com:443/",
+            "user@example.com:443",
+            "example.com:443/path",
+            // Bad ports and literals
+            "example.com:0",
+            "example.com:65536",
+            "[::1:443",
+            "[example.com]:443",
+        ] {
+            let e = target(raw).err().unwrap_or_else(|| panic!("{raw} accepted"));
+            assert_eq!(*e.etype(), HTTPStatus(400), "{raw}");
+        }
+    }
+}
@@ -0,0 +1,445 @@
+use std::time::{Duration, Instant};
+
+use http::header::{CONNECTION, UPGRADE};
+use http::StatusCode;
+use pingora::http::ResponseHeader;
+use pingora::prelude::*;
+
+use crate::config::{UpgradesConfig, CONFIG_ERROR};
+
+/// Errors closing a WebSocket connection that exceeded a size limit
+pub const WEBSOCKET_LIMIT: ErrorType = ErrorType::Custom("WebSocketLimit");
+
+/// HTTP/2 over cleartext is negotiated with the proxy, not passed through it
+const H2C: &str = "h2c";
+
+/// Which protocol upgrades are passed upstream, and the limits of upgraded
+/// connections.
+pub struct Upgrades {
+    /// Lowercase, any but `h2c` if empty
+    protocols: Vec<String>,
+    idle_timeout: Option<Duration>,
+    limits: WebSocketLimits,
+}
+
+impl Upgrades {
+    pub fn new(config: &UpgradesConfig) -> Result<Self> {
+        let protocols: Vec<String> = config
+            .protocols
+            .iter()
+            .map(|p| p.to_ascii_lowercase())
+            .collect();
+        if protocols.iter().any(|p| p == H2C) {
+            return Error::e_explain(CONFIG_ERROR, "h2c upgrades are not supported");
+        }
+        Ok(Upgrades {
+            protocols,
+            idle_timeout: config.idle_timeout_secs.map(Duration::from_secs),
+            limits: WebSocketLimits {
+                max_frame_size: config.max_frame_size,
+                max_message_size: config.max_message_size,
+            },
+        })
+    }
+
+    /// The upgrade a request asks for, with the protocols that may be passed
+    /// upstream; `None` if it asks for none or none may.
+    pub fn request(&self, req: &RequestHeader) -> Option<Upgrade> {
+        let requested = req.headers.get(UPGRADE)?.to_str().ok()?;
+        let allowed: Vec<&str> = requested
+            .split(',')
+            .map(str::trim)
+            .filter(|offer| {
+                // Offers may carry a version, as in `websocket/13`
+                let name = offer.split('/').next().unwrap_or_default();
+                !name.is_empty()
+                    && !name.eq_ignore_ascii_case(H2C)
+                    && (self.protocols.is_empty()
+                        || self.protocols.iter().any(|p| name.eq_ignore_ascii_case(p)))
+            })
+            .collect();
+        if allowed.is_empty() {
+            return None;
+        }
+        let websocket = allowed.iter().any(|p| p.eq_ignore_ascii_case("websocket"));
+        Some(Upgrade {
+            protocols: allowed.join(", "),
+            idle_timeout: self.idle_timeout,
+            limits: self.limits,
+            websocket,
+            response_timeout: None,
+            sent: None,
+            switched: None,
+            client_frames: FrameReader::default(),
+            upstream_frames: FrameReader::default(),
+        })
+    }
+}
+
+/// Size limits of WebSocket connections
+#[derive(Debug, Clone, Copy)]
+struct WebSocketLimits {
+    max_frame_size: Option<u64>,
+    max_message_size: Option<u64>,
+}
+
+impl WebSocketLimits {
+    fn any(&self) -> bool {
+        self.max_frame_size.is_some() || self.max_message_size.is_some()
+    }
+}
+
+/// An upgrade a client asked for and that is passed upstream
+pub struct Upgrade {
+    /// The `Upgrade` value sent upstream
+    protocols: String,
+    idle_timeout: Option<Duration>,
+    limits: WebSocketLimits,
+    websocket: bool,
+    /// How long the upstream may take to respond, the route's read timeout
+    /// up to the total timeout
+    response_timeout: Option<Duration>,
+    /// When the request was sent upstream
+    sent: Option<Instant>,
+    /// When the upstream switched protocols
+    switched: Option<Instant>,
+    client_frames: FrameReader,
+    upstream_frames: FrameReader,
+}
+
+impl Upgrade {
+    /// Applies the idle timeout to the client connection.
+    pub fn downstream(&self, session: &mut Session) {
+        if self.idle_timeout.is_some() {
+            session.set_read_timeout(self.idle_timeout);
+        }
+    }
+
+    /// Applies the idle timeout to the upstream connection, `read` being
+    /// the route's read timeout, which stands in for it if there is none.
+    ///
+    /// pingora keeps the read timeout of a connection for as long as it
+    /// lasts, so it bounds both the wait for the response and, once
+    /// switched, the time without traffic in either direction: both sides
+    /// wait for either to send something. The longer of the two is set, and
+    /// [`Self::response`] holds the response to the read timeout the peer
+    /// has, which the total timeout may have cut short.
+    pub fn peer(&mut self, peer: &mut HttpPeer, read: Duration) {
+        self.response_timeout = peer.options.read_timeout;
+        let idle = self.idle_timeout.unwrap_or(read);
+        let wait = self.response_timeout.unwrap_or(idle);
+        peer.options.read_timeout = Some(wait.max(idle));
+    }
+
+    /// Restores the upgrade headers that hop-by-hop stripping removed from a
+    /// request sent upstream.
+    pub fn upstream_request(&mut self, req: &mut RequestHeader) -> Result<()> {
+        self.sent = Some(Instant::now());
+        req.insert_header(UPGRADE, self.protocols.as_str())?;
+        req.insert_header(CONNECTION, "upgrade")
+    }
+
+    /// Checks the upstream's response, which fails if it came after the
+    /// route's read timeout, and notes a `101 Switching Protocols`.
+    pub fn response(&mut self, resp: &ResponseHeader) -> Result<()> {
+        if let (Some(sent), Some(timeout)) = (self.sent, self.response_timeout) {
+            if sent.elapsed() > timeout {
+                let context = format!("reading response header, timeout: {timeout:?}");
+                return Err(Error::explain(ReadTimedout, context).into_up());
+            }
+        }
+        if resp.status == StatusCode::SWITCHING_PROTOCOLS {
+            self.switched.get_or_insert_with(Instant::now);
+        }
+        Ok(())
+    }
+
+    /// How long the connection has been upgraded, `None` if it never was
+    pub fn duration(&self) -> Option<Duration> {
+        self.switched.map(|switched| switched.elapsed())
+    }
+
+    /// Checks data the client sent over the upgraded connection.
+    pub fn client_data(&mut self, data: &[u8]) -> Result<()> {
+        if self.checks_frames() {
+            self.client_frames.read(data, &self.limits, "client")?;
+        }
+        Ok(())
+    }
+
+    /// Checks data the upstream sent over the upgraded connection.
+    pub fn upstream_data(&mut self, data: &[u8]) -> Result<()> {
+        if self.checks_frames() {
+            self.upstream_frames.read(data, &self.limits, "upstream")?;
+        }
+        Ok(())
+    }
+
+    fn checks_frames(&self) -> bool {
+        self.switched.is_some() && self.websocket && self.limits.any()
+    }
+}
+
+/// Follows the WebSocket frames (RFC 6455 section 5.2) sent in one
+/// direction, across however the stream is split into chunks.
+#[derive(Default)]
+struct FrameReader {
+    /// The part of the current frame header read so far
+    header: Vec<u8>,
+    /// Payload bytes of the current frame still to come
+    payload_left: u64,
+    /// Payload bytes of the current data message so far
+    message_size: u64,
+}
+
+impl FrameReader {
+    fn read(&mut self, mut data: &[u8], limits: &WebSocketLimits, sender: &str) -> Result<()> {
+        while !data.is_empty() {
+            if self.payload_left > 0 {
+                let left = usize::try_from(self.payload_left).unwrap_or(usize::MAX);
+                let skipped = data.len().min(left);
+                self.payload_left -= skipped as u64;
+                data = &data[skipped..];
+                continue;
+            }
+            self.header.push(data[0]);
+            data = &data[1..];
+            let Some(frame) = parse_frame_header(&self.header) else {
+                continue;
+            };
+            self.header.clear();
+
+            if limits.max_frame_size.is_some_and(|max| frame.len > max) {
+                return Error::e_explain(
+                    WEBSOCKET_LIMIT,
+                    format!("{sender} sent a WebSocket frame of {} bytes", frame.len),
+                );
+            }
+            // Control frames may come between the frames of a message
+            if frame.opcode & 0x8 == 0 {
+                self.message_size += frame.len;
+                if let Some(max) = limits.max_message_size.filter(|max| self.message_size > *max) {
+                    return Error::e_explain(
+                        WEBSOCKET_LIMIT,
+                        format!("{sender} sent a WebSocket message over {max} bytes"),
+                    );
+                }
+                if frame.fin {
+                    self.message_size = 0;
+                }
+            }
+            self.payload_left = frame.len;
+        }
+        Ok(())
+    }
+}
+
+struct FrameHeader {
+    fin: bool,
+    opcode: u8,
+    /// Payload length
+    len: u64,
+}
+
+/// Parses a frame header, `None` if `header` does not hold all of it yet.
+fn parse_frame_header(header: &[u8]) -> Option<FrameHeader> {
+    let (&first, rest) = header.split_first()?;
+    let (&second, rest) = rest.split_first()?;
+    let masked = second & 0x80 != 0;
+    let (len, rest) = match second & 0x7f {
+        126 => (u64::from(u16::from_be_bytes(rest.get(..2)?.try_into().ok()?)), &rest[2..]),
+        127 => (u64::from_be_bytes(rest.get(..8)?.try_into().ok()?), &rest[8..]),
+        len => (u64::from(len), rest),
+    };
+    if masked && rest.len() < 4 {
+        return None;
+    }
+    Some(FrameHeader {
+        fin: first & 0x80 != 0,
+        opcode: first & 0x0f,
+        len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: u8 = 0x1;
+    const BINARY: u8 = 0x2;
+    const CONTINUATION: u8 = 0x0;
+    const PING: u8 = 0x9;
+
+    /// A frame with a payload of `len` bytes, masked as clients send them
+    fn frame(fin: bool, opcode: u8, len: u64, masked: bool) -> Vec<u8> {
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        let mask = if masked { 0x80 } else { 0 };
+        match len {
+            0..=125 => frame.push(mask | len as u8),
+            126..=0xffff => {
+                frame.push(mask | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            _ => {
+                frame.push(mask | 127);
+                frame.extend_from_slice(&len.to_be_bytes());
+            }
+        }
+        if masked {
+            frame.extend_from_slice(&[0x37, 0xfa, 0x21, 0x3d]);
+        }
+        frame.resize(frame.len() + len as usize, 0xa5);
+        frame
+    }
+
+    fn limits(max_frame_size: Option<u64>, max_message_size: Option<u64>) -> WebSocketLimits {
+        WebSocketLimits {
+            max_frame_size,
+            max_message_size,
+        }
+    }
+
+    /// Feeds `data` in chunks of `chunk` bytes.
+    fn read(
+        reader: &mut FrameReader,
+        data: &[u8],
+        chunk: usize,
+        limits: &WebSocketLimits,
+    ) -> Result<()> {
+        data.chunks(chunk)
+            .try_for_each(|chunk| reader.read(chunk, limits, "client"))
+    }
+
+    #[test]
+    fn test_frame_lengths() {
+        for (len, masked) in [(0, false), (125, true), (126, false), (300, true), (70_000, true)] {
+            let data = [frame(true, BINARY, len, masked), frame(true, TEXT, 1, masked)].concat();
+            for chunk in [1, 2, 3, 7, data.len()] {
+                let mut reader = FrameReader::default();
+                let exact = limits(Some(len.max(1)), None);
+                assert!(read(&mut reader, &data, chunk, &exact).is_ok(), "{len} {chunk}");
+                // Nothing of a frame is left over once it is through
+                assert!(reader.header.is_empty() && reader.payload_left == 0);
+
+                let mut reader = FrameReader::default();
+                let below = limits(Some(len.max(1) - 1), None);
+                let e = read(&mut reader, &data, chunk, &below).unwrap_err();
+                assert_eq!(e.etype(), &WEBSOCKET_LIMIT, "{len} {chunk}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_header() {
+        // A 64-bit length split at every byte, then the frame after it
+        let data = [frame(true, BINARY, 70_000, true), frame(true, BINARY, 200, true)].concat();
+        let limits = limits(Some(100_000), None);
+        let mut reader = FrameReader::default();
+        for (i, byte) in data[..14].iter().enumerate() {
+            reader.read(&[*byte], &limits, "client").unwrap();
+            assert_eq!(reader.header.len(), (i + 1) % 14);
+        }
+        assert_eq!(reader.payload_left, 70_000);
+        reader.read(&data[14..14 + 69_999], &limits, "client").unwrap();
+        assert_eq!(reader.payload_left, 1);
+        // The end of one frame and the start of the next in one chunk
+        reader.read(&data[14 + 69_999..14 + 70_002], &limits, "client").unwrap();
+        assert_eq!(reader.header.len(), 2);
+        reader.read(&data[14 + 70_002..], &limits, "client").unwrap();
+        assert_eq!(reader.payload_left, 0);
+    }
+
+    #[test]
+    fn test_message_size() {
+        let limits = limits(None, Some(100));
+        // A message of 3 frames with a ping between them, which does not count
+        let message = [
+            frame(false, TEXT, 40, true),
+            frame(true, PING, 60, true),
+            frame(false, CONTINUATION, 40, true),
+            frame(true, CONTINUATION, 20, true),
+        ]
+        .concat();
+        let mut reader = FrameReader::default();
+        read(&mut reader, &message, 5, &limits).unwrap();
+        // The size starts over with each message
+        read(&mut reader, &message, 5, &limits).unwrap();
+        assert_eq!(reader.message_size, 0);
+
+        let over = [message.clone(), frame(true, BINARY, 101, false)].concat();
+        let e = read(&mut FrameReader::default(), &over, 64, &limits).unwrap_err();
+        assert_eq!(e.etype(), &WEBSOCKET_LIMIT);
+        let over = [frame(false, BINARY, 60, false), frame(true, CONTINUATION, 41, false)].concat();
+        let e = read(&mut FrameReader::default(), &over, 3, &limits).unwrap_err();
+        assert_eq!(e.etype(), &WEBSOCKET_LIMIT);
+    }
+
+    fn new_upgrade(idle_timeout_secs: Option<u64>) -> Upgrade {
+        let upgrades = Upgrades::new(&UpgradesConfig {
+            idle_timeout_secs,
+            max_frame_size: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header(UPGRADE, "websocket").unwrap();
+        upgrades.request(&req).unwrap()
+    }
+
+    fn new_peer(read_timeout: Duration) -> HttpPeer {
+        let mut peer = HttpPeer::new("127.0.0.1:80", false, String::new());
+        peer.options.read_timeout = Some(read_timeout);
+        peer
+    }
+
+    #[test]
+    fn test_peer_read_timeout() {
+        let secs = Duration::from_secs;
+        // Without an idle timeout the route's read timeout is kept
+        let mut peer = new_peer(secs(30));
+        new_upgrade(None).peer(&mut peer, secs(30));
+        assert_eq!(peer.options.read_timeout, Some(secs(30)));
+
+        let mut peer = new_peer(secs(30));
+        new_upgrade(Some(300)).peer(&mut peer, secs(30));
+        assert_eq!(peer.options.read_timeout, Some(secs(300)));
+        let mut peer = new_peer(secs(30));
+        new_upgrade(Some(10)).peer(&mut peer, secs(30));
+        assert_eq!(peer.options.read_timeout, Some(secs(30)));
+
+        // The total timeout only cuts the wait for the response short
+        let mut peer = new_peer(secs(5));
+        let mut upgrade = new_upgrade(None);
+        upgrade.peer(&mut peer, secs(30));
+        assert_eq!(peer.options.read_timeout, Some(secs(30)));
+        assert_eq!(upgrade.response_timeout, Some(secs(5)));
+    }
+
+    #[test]
+    fn test_response() {
+        let switching = ResponseHeader::build(101, None).unwrap();
+        let mut upgrade = new_upgrade(Some(300));
+        upgrade.peer(&mut new_peer(Duration::from_secs(30)), Duration::from_secs(30));
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        upgrade.upstream_request(&mut req).unwrap();
+        // Frames are only followed once switched
+        upgrade.client_data(&frame(true, BINARY, 20, true)).unwrap();
+        upgrade.response(&switching).unwrap();
+        assert!(upgrade.duration().is_some());
+        assert!(upgrade.client_data(&frame(true, BINARY, 20, true)).is_err());
+
+        // A response later than the route's read timeout is not taken
+        let mut upgrade = new_upgrade(Some(300));
+        upgrade.peer(&mut new_peer(Duration::from_millis(1)), Duration::from_secs(30));
+        upgrade.upstream_request(&mut req).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        let e = upgrade.response(&switching).unwrap_err();
+        assert_eq!(e.etype(), &ReadTimedout);
+        assert_eq!(e.esource(), &ErrorSource::Upstream);
+        assert!(upgrade.duration().is_none());
+
+        let mut refused = new_upgrade(None);
+        refused.response(&ResponseHeader::build(200, None).unwrap()).unwrap();
+        assert!(refused.duration().is_none());
+    }
+}
@@ -0,0 +1,872 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use futures::FutureExt;
+use log::{info, warn};
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::health_check::{HealthCheck, HttpHealthCheck, TcpHealthCheck};
+use pingora::lb::selection::{BackendIter, BackendSelection, Consistent, RoundRobin};
+use pingora::lb::{Backend, Backends, LoadBalancer};
+use pingora::prelude::*;
+use pingora::protocols::l4::socket::SocketAddr;
+use pingora::upstreams::peer::PeerOptions;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+
+use crate::authority::{Authority, Host};
+use crate::config::{
+    Algorithm, HashKeyConfig, HealthCheckConfig, HealthCheckKind, UpstreamConfig,
+    UpstreamHttpVersion, CONFIG_ERROR,
+};
+use crate::dns::Resolver;
+use crate::reload::SharedSettings;
+use crate::retry::RetryPolicy;
+use crate::timeouts::Timeouts;
+use crate::tls::UpstreamTls;
+
+/// Errors of requests to an upstream none of whose backends is usable
+pub const NO_HEALTHY_BACKEND: ErrorType = ErrorType::Custom("NoHealthyBackend");
+
+/// Bound on the backends visited when looking for a usable one
+const MAX_SELECT_ITERATIONS: usize = 256;
+
+/// Concurrent requests multiplexed over one HTTP/2 connection to a backend
+const MAX_H2_STREAMS: usize = 100;
+
+/// The host name a backend was configured with, used as its default SNI
+#[derive(Clone)]
+struct BackendHost(String);
+
+/// A configured backend, which is a backend of the pool for every address
+/// its host resolves to
+struct BackendAddress {
+    host: Host,
+    port: u16,
+    weight: usize,
+}
+
+/// The backends a pool resolved to last, which its load balancer discovers
+struct Resolved(Arc<ArcSwap<BTreeSet<Backend>>>);
+
+#[async_trait]
+impl ServiceDiscovery for Resolved {
+    async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+        Ok(((**self.0.load()).clone(), HashMap::new()))
+    }
+}
+
+/// Request accounting of a backend, shared by all requests sent to it
+struct BackendState {
+    /// Requests currently in flight
+    active: AtomicUsize,
+    /// Consecutive proxy errors since the last success
+    failures: AtomicUsize,
+    /// Set while the backend is ejected by passive health checking
+    ejected_until: Mutex<Option<Instant>>,
+}
+
+impl BackendState {
+    fn is_ejected(&self) -> bool {
+        self.ejected_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| until > Instant::now())
+    }
+}
+
+/// The backend selection of a pool
+enum Balancer {
+    RoundRobin(LoadBalancer<RoundRobin>),
+    /// The counter rotates the starting backend so ties are spread out
+    LeastConnections(LoadBalancer<RoundRobin>, AtomicUsize),
+    ConsistentHash(LoadBalancer<Consistent>, HashKeyConfig),
+}
+
+impl Balancer {
+    fn backends(&self) -> &Backends {
+        match self {
+            Balancer::RoundRobin(lb) | Balancer::LeastConnections(lb, _) => lb.backends(),
+            Balancer::ConsistentHash(lb, _) => lb.backends(),
+        }
+    }
+
+    /// Picks up the backends the pool resolved to.
+    async fn update(&self) -> Result<()> {
+        match self {
+            Balancer::RoundRobin(lb) | Balancer::LeastConnections(lb, _) => lb.update().await,
+            Balancer::ConsistentHash(lb, _) => lb.update().await,
+        }
+    }
+}
+
+/// A named pool of backend servers that routes forward to
+pub struct Upstream {
+    pub name: String,
+    /// What the pool was built from, to carry it over reloads that keep it
+    config: UpstreamConfig,
+    balancer: Balancer,
+    tls: bool,
+    sni: Option<String>,
+    upstream_tls: UpstreamTls,
+    http_version: UpstreamHttpVersion,
+    addresses: Vec<BackendAddress>,
+    resolved: Arc<ArcSwap<BTreeSet<Backend>>>,
+    /// Set once every backend host resolved
+    ready: AtomicBool,
+    /// Set while some backend host fails to resolve
+    resolve_failing: AtomicBool,
+    state: ArcSwap<HashMap<SocketAddr, Arc<BackendState>>>,
+    /// How often the active health check runs, if there is one
+    health_check_interval: Option<Duration>,
+    next_health_check: Mutex<Instant>,
+    max_failures: usize,
+    ejection: Duration,
+    /// Limit of requests in flight, 0 is unlimited
+    max_requests: usize,
+    requests: Arc<AtomicUsize>,
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+}
+
+impl Upstream {
+    pub fn new(
+        name: &str,
+        config: &UpstreamConfig,
+        timeouts: Timeouts,
+        upstream_tls: &UpstreamTls,
+    ) -> Result<Self> {
+        let context = |e: Box<Error>| e.more_context(format!("upstream {name}"));
+        if config.backends.is_empty() {
+            return Error::e_explain(CONFIG_ERROR, format!("upstream {name} has no backends"));
+        }
+        if config.http_version == UpstreamHttpVersion::Auto && !config.tls {
+            // Without ALPN there is nothing to negotiate with
+            return Error::e_explain(
+                CONFIG_ERROR,
+                format!("upstream {name}: http_version auto needs tls"),
+            );
+        }
+
+        let mut addresses = Vec::with_capacity(config.backends.len());
+        let mut first_host = None;
+        for backend in &config.backends {
+            let authority = Authority::parse(&backend.address).map_err(context)?;
+            let port = authority.port_or_default(config.tls);
+            let weight = match config.algorithm {
+                Algorithm::Weighted | Algorithm::LeastConnections => backend.weight,
+                Algorithm::RoundRobin | Algorithm::ConsistentHash => 1,
+            };
+            if weight == 0 {
+                return Error::e_explain(
+                    CONFIG_ERROR,
+                    format!("upstream {name}: backend {} has weight 0", backend.address),
+                );
+            }
+            first_host.get_or_insert_with(|| authority.host.to_string());
+            addresses.push(BackendAddress {
+                host: authority.host,
+                port,
+                weight,
+            });
+        }
+
+        let resolved = Arc::new(ArcSwap::from_pointee(BTreeSet::new()));
+        let mut discovery = Backends::new(Box::new(Resolved(resolved.clone())));
+        if let Some(check) = &config.health_check {
+            let host = check
+                .host
+                .clone()
+                .or_else(|| config.sni.clone())
+                .or(first_host)
+                .unwrap_or_default();
+            let check = health_check(check, &host, config, upstream_tls);
+            discovery.set_health_check(check);
+        }
+        let balancer = match config.algorithm {
+            Algorithm::RoundRobin | Algorithm::Weighted => {
+                Balancer::RoundRobin(load_balancer(discovery)?)
+            }
+            Algorithm::LeastConnections => {
+                Balancer::LeastConnections(load_balancer(discovery)?, AtomicUsize::new(0))
+            }
+            Algorithm::ConsistentHash => {
+                Balancer::ConsistentHash(load_balancer(discovery)?, config.hash_key.clone())
+            }
+        };
+
+        Ok(Upstream {
+            name: name.to_string(),
+            config: config.clone(),
+            balancer,
+            tls: config.tls,
+            sni: config.sni.clone(),
+            upstream_tls: upstream_tls.clone(),
+            http_version: config.http_version,
+            addresses,
+            resolved,
+            ready: AtomicBool::new(false),
+            resolve_failing: AtomicBool::new(false),
+            state: ArcSwap::default(),
+            health_check_interval: config
+                .health_check
+                .as_ref()
+                .map(|check| Duration::from_secs(check.interval_secs.max(1))),
+            next_health_check: Mutex::new(Instant::now()),
+            max_failures: config.passive_health.max_failures,
+            ejection: Duration::from_secs(config.passive_health.ejection_secs),
+            max_requests: config.max_concurrent_requests,
+            requests: Arc::new(AtomicUsize::new(0)),
+            timeouts: Timeouts::new(&config.timeouts).map_err(context)?.or(timeouts),
+            retry: RetryPolicy::new(&config.retry).map_err(context)?,
+        })
+    }
+
+    /// Whether the pool is the one `config` builds, so it can be kept with
+    /// its backend health and request accounting instead.
+    pub fn is_built_from(&self, config: &UpstreamConfig, timeouts: Timeouts) -> bool {
+        self.config == *config
+            && Timeouts::new(&config.timeouts).is_ok_and(|own| own.or(timeouts) == self.timeouts)
+    }
+
+    /// Admits a request under the concurrency limit, `None` when the upstream
+    /// is at the limit.
+    ///
+    /// The request counts as in flight until the permit is dropped.
+    pub fn admit(&self) -> Option<RequestPermit> {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed);
+        let permit = RequestPermit(self.requests.clone());
+        (self.max_requests == 0 || requests < self.max_requests).then_some(permit)
+    }
+
+    /// Picks a healthy backend for the request and builds the peer to
+    /// connect to.
+    ///
+    /// The backend counts the request as in flight until the returned
+    /// [`Selection`] is dropped.
+    pub fn select(&self, session: &Session) -> Result<(Box<HttpPeer>, Selection)> {
+        let state = self.state.load();
+        let accept = |backend: &Backend, healthy: bool| {
+            healthy && !state.get(&backend.addr).is_some_and(|s| s.is_ejected())
+        };
+        let backend = match &self.balancer {
+            Balancer::RoundRobin(lb) => lb.select_with(b"", MAX_SELECT_ITERATIONS, accept),
+            Balancer::ConsistentHash(lb, key) => {
+                lb.select_with(&hash_key(session, key), MAX_SELECT_ITERATIONS, accept)
+            }
+            Balancer::LeastConnections(lb, next) => {
+                let active = |backend: &Backend| {
+                    state
+                        .get(&backend.addr)
+                        .map_or(0, |s| s.active.load(Ordering::Relaxed))
+                };
+                let backends = lb.backends().get_backend();
+                let start = next.fetch_add(1, Ordering::Relaxed) % backends.len().max(1);
+                backends
+                    .iter()
+                    .cycle()
+                    .skip(start)
+                    .take(backends.len())
+                    .filter(|backend| accept(backend, lb.backends().ready(backend)))
+                    // Fewest in flight per unit of weight, compared without division
+                    .min_by(|a, b| (active(a) * b.weight).cmp(&(active(b) * a.weight)))
+                    .cloned()
+            }
+        };
+        let backend = backend.or_err_with(NO_HEALTHY_BACKEND, || {
+            format!("no healthy backend in upstream {}", self.name)
+        })?;
+
+        let sni = match (&self.sni, backend.ext.get::<BackendHost>()) {
+            (Some(sni), _) => sni.clone(),
+            (None, Some(BackendHost(host))) => host.clone(),
+            (None, None) => String::new(),
+        };
+        let mut peer = Box::new(HttpPeer::new(&backend, self.tls, sni));
+        if self.tls {
+            self.upstream_tls.apply(&mut peer.options);
+        }
+        set_http_version(&mut peer.options, self.http_version);
+
+        let state = state.get(&backend.addr).cloned().or_err(InternalError, "unknown backend")?;
+        state.active.fetch_add(1, Ordering::Relaxed);
+        let selection = Selection {
+            upstream: self.name.clone(),
+            addr: backend.addr,
+            state,
+            max_failures: self.max_failures,
+            ejection: self.ejection,
+        };
+        Ok((peer, selection))
+    }
+
+    /// Resolves the backend hosts unless that was done already, failing
+    /// only if the pool has no backends.
+    pub async fn resolve_once(&self, resolver: &Resolver) -> Result<()> {
+        if self.ready.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        match self.resolve(resolver).await {
+            Err(e) if self.resolved.load().is_empty() => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Resolves the backend hosts and updates the pool if their addresses
+    /// changed.
+    ///
+    /// Hosts that fail to resolve keep the addresses they had; the error is
+    /// returned once the others are updated.
+    pub async fn resolve(&self, resolver: &Resolver) -> Result<()> {
+        let previous = self.resolved.load_full();
+        let mut backends = BTreeSet::new();
+        let mut failure = None;
+        for address in &self.addresses {
+            let host = address.host.to_string();
+            match resolver.resolve(&address.host, address.port).await {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        let mut backend =
+                            Backend::new_with_weight(&addr.to_string(), address.weight)?;
+                        backend.ext.insert(BackendHost(host.clone()));
+                        backends.insert(backend);
+                    }
+                }
+                Err(e) => {
+                    let kept = previous.iter().filter(|backend| {
+                        backend.addr.as_inet().is_some_and(|a| a.port() == address.port)
+                            && backend.ext.get::<BackendHost>().is_some_and(|h| h.0 == host)
+                    });
+                    backends.extend(kept.cloned());
+                    failure = Some(e.more_context(format!("upstream {}", self.name)));
+                }
+            }
+        }
+        if *previous != backends {
+            if !previous.is_empty() {
+                info!("Backends of upstream {} changed to {}", self.name, addr_list(&backends));
+            }
+            // Backends are only selected once they have a state
+            let mut state = (**self.state.load()).clone();
+            for backend in &backends {
+                state.entry(backend.addr.clone()).or_insert_with(|| {
+                    Arc::new(BackendState {
+                        active: AtomicUsize::new(0),
+                        failures: AtomicUsize::new(0),
+                        ejected_until: Mutex::new(None),
+                    })
+                });
+            }
+            self.state.store(Arc::new(state.clone()));
+            let backends = Arc::new(backends);
+            self.resolved.store(backends.clone());
+            self.balancer.update().await?;
+            state.retain(|addr, _| backends.iter().any(|backend| &backend.addr == addr));
+            self.state.store(Arc::new(state));
+        }
+        match failure {
+            Some(e) => {
+                if !self.resolve_failing.swap(true, Ordering::Relaxed) {
+                    warn!("Cannot resolve all backends: {e}");
+                }
+                Err(e)
+            }
+            None => {
+                if self.resolve_failing.swap(false, Ordering::Relaxed) {
+                    info!("Backends of upstream {} resolve again", self.name);
+                }
+                self.ready.store(true, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs the active health check of this pool if it is due.
+    async fn health_check_if_due(&self) {
+        let Some(interval) = self.health_check_interval else {
+            return;
+        };
+        {
+            let mut next = self.next_health_check.lock().unwrap();
+            let now = Instant::now();
+            if *next > now {
+                return;
+            }
+            *next = now + interval;
+        }
+        self.balancer.backends().run_health_check(true).await;
+    }
+}
+
+/// Builds a load balancer over the backends of `discovery`.
+fn load_balancer<S>(discovery: Backends) -> Result<LoadBalancer<S>>
+where
+    S: BackendSelection + 'static,
+    S::Iter: BackendIter,
+{
+    let lb = LoadBalancer::from_backends(discovery);
+    // Discovery reads the resolved backends without awaiting anything
+    lb.update()
+        .now_or_never()
+        .or_err(InternalError, "backend discovery blocked")??;
+    Ok(lb)
+}
+
+/// The addresses of `backends`, for logs
+fn addr_list(backends: &BTreeSet<Backend>) -> String {
+    let addrs: Vec<String> = backends.iter().map(|backend| backend.addr.to_string()).collect();
+    addrs.join(", ")
+}
+
+fn health_check(
+    config: &HealthCheckConfig,
+    host: &str,
+    upstream: &UpstreamConfig,
+    upstream_tls: &UpstreamTls,
+) -> Box<dyn HealthCheck + Send + Sync> {
+    let tls = upstream.tls;
+    let timeout = Some(Duration::from_millis(config.timeout_ms));
+    match config.kind {
+        HealthCheckKind::Tcp => {
+            let mut check = if tls {
+                TcpHealthCheck::new_tls(host)
+            } else {
+                TcpHealthCheck::new()
+            };
+            check.consecutive_success = config.healthy_threshold.max(1);
+            check.consecutive_failure = config.unhealthy_threshold.max(1);
+            check.peer_template.options.connection_timeout = timeout;
+            if tls {
+                upstream_tls.apply(&mut check.peer_template.options);
+            }
+            check
+        }
+        HealthCheckKind::Http => {
+            let mut check = HttpHealthCheck::new(host, tls);
+            check.consecutive_success = config.healthy_threshold.max(1);
+            check.consecutive_failure = config.unhealthy_threshold.max(1);
+            check.req.set_uri(
+                config
+                    .path
+                    .parse()
+                    .unwrap_or_else(|_| http::Uri::from_static("/")),
+            );
+            check.peer_template.options.connection_timeout = timeout;
+            check.peer_template.options.read_timeout = timeout;
+            if tls {
+                upstream_tls.apply(&mut check.peer_template.options);
+            }
+            set_http_version(&mut check.peer_template.options, upstream.http_version);
+            Box::new(check)
+        }
+    }
+}
+
+/// Sets the HTTP versions a backend connection may use.
+fn set_http_version(options: &mut PeerOptions, version: UpstreamHttpVersion) {
+    match version {
+        UpstreamHttpVersion::Http1 => options.set_http_version(1, 1),
+        UpstreamHttpVersion::Http2 => options.set_http_version(2, 2),
+        UpstreamHttpVersion::Auto => options.set_http_version(2, 1),
+    }
+    if version != UpstreamHttpVersion::Http1 {
+        options.max_h2_streams = MAX_H2_STREAMS;
+    }
+}
+
+/// The bytes hashed to pick a backend under `consistent_hash`
+fn hash_key(session: &Session, key: &HashKeyConfig) -> Vec<u8> {
+    let req = session.req_header();
+    let value = match key {
+        HashKeyConfig::ClientIp => None,
+        HashKeyConfig::Header(name) => req.headers.get(name.as_str()).map(|v| v.as_bytes()),
+        HashKeyConfig::Cookie(name) => req
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .flat_map(|v| v.as_bytes().split(|&b| b == b';'))
+            .find_map(|pair| {
+                let pair = pair.trim_ascii_start();
+                pair.strip_prefix(name.as_bytes())?.strip_prefix(b"=")
+            }),
+    };
+    match value {
+        Some(value) => value.to_vec(),
+        None => session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(|addr| addr.ip().to_string().into_bytes())
+            .unwrap_or_default(),
+    }
+}
+
+/// A request admitted under the concurrency limit of an upstream
+pub struct RequestPermit(Arc<AtomicUsize>);
+
+impl Drop for RequestPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A backend chosen for a request
+pub struct Selection {
+    upstream: String,
+    pub addr: SocketAddr,
+    state: Arc<BackendState>,
+    max_failures: usize,
+    ejection: Duration,
+}
+
+impl Selection {
+    /// Records the outcome of proxying to the backend, ejecting it after too
+    /// many consecutive failures.
+    pub fn report(&self, success: bool) {
+        if success {
+            self.state.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+        let failures = self.state.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.max_failures > 0 && failures >= self.max_failures {
+            self.state.failures.store(0, Ordering::Relaxed);
+            *self.state.ejected_until.lock().unwrap() = Some(Instant::now() + self.ejection);
+            warn!(
+                "Ejecting backend {} of upstream {} for {}s after {} consecutive failures",
+                self.addr,
+                self.upstream,
+                self.ejection.as_secs(),
+                failures
+            );
+        }
+    }
+}
+
+impl Drop for Selection {
+    fn drop(&mut self) {
+        self.state.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// How often upstreams are looked at for due health checks
+const HEALTH_CHECK_TICK: Duration = Duration::from_secs(1);
+
+/// Resolves the backends of the upstream pools in service, and runs their
+/// active health checks.
+///
+/// Pools are picked up from the current settings on every tick, so pools
+/// added by a configuration reload are checked too.
+pub struct HealthChecks {
+    settings: SharedSettings,
+}
+
+impl HealthChecks {
+    pub fn new(settings: SharedSettings) -> Self {
+        HealthChecks { settings }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for HealthChecks {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let mut interval = tokio::time::interval(HEALTH_CHECK_TICK);
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return,
+                _ = interval.tick() => {
+                    let settings = self.settings.load_full();
+                    let checks = settings.routes.upstreams.iter().map(|upstream| async {
+                        // The resolver caches answers for their TTL, and
+                        // failures are logged by the pool
+                        upstream.resolve(&settings.dns).await.ok();
+                        upstream.health_check_if_due().await;
+                    });
+                    futures::future::join_all(checks).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::DnsConfig;
+
+    /// Builds the upstream `config` describes, resolved and ready to select from
+    async fn upstream(config: &str) -> Upstream {
+        let config: UpstreamConfig = toml::from_str(config).unwrap();
+        let upstream_tls = UpstreamTls::new(None, false).unwrap();
+        let upstream = Upstream::new("test", &config, Timeouts::default(), &upstream_tls).unwrap();
+        let resolver = Resolver::new(&DnsConfig {
+            nameservers: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        upstream.resolve(&resolver).await.unwrap();
+        upstream
+    }
+
+    async fn session(headers: &str) -> Session {
+        let (mut client, server) = tokio::io::duplex(4096);
+        let mut session = Session::new_h1(Box::new(server));
+        let request = format!("GET / HTTP/1.1\r\nHost: example.com\r\n{headers}\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        assert!(session.read_request().await.unwrap());
+        session
+    }
+
+    /// The port of the backend each selection went to, holding the selections
+    fn select(upstream: &Upstream, session: &Session, n: usize) -> (Vec<u16>, Vec<Selection>) {
+        (0..n)
+            .map(|_| {
+                let (_, selection) = upstream.select(session).unwrap();
+                (selection.addr.as_inet().unwrap().port(), selection)
+            })
+            .unzip()
+    }
+
+    fn count(ports: &[u16], port: u16) -> usize {
+        ports.iter().filter(|p| **p == port).count()
+    }
+
+    #[tokio::test]
+    async fn test_round_robin() {
+        let upstream = upstream(
+            r#"backends = [
+                { address = "127.0.0.1:9001" },
+                { address = "127.0.0.1:9002" },
+                { address = "127.0.0.1:9003" },
+            ]"#,
+        )
+        .await;
+        let session = session("").await;
+        let (ports, _) = select(&upstream, &session, 9);
+        // Every backend in turn
+        for (i, port) in ports.iter().enumerate().skip(3) {
+            assert_eq!(*port, ports[i - 3]);
+        }
+        for port in [9001, 9002, 9003] {
+            assert_eq!(count(&ports, port), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_weighted() {
+        let upstream = upstream(
+            r#"
+            algorithm = "weighted"
+            backends = [
+                { address = "127.0.0.1:9001", weight = 3 },
+                { address = "127.0.0.1:9002" },
+            ]"#,
+        )
+        .await;
+        let session = session("").await;
+        let (ports, _) = select(&upstream, &session, 40);
+        assert_eq!(count(&ports, 9001), 30);
+        assert_eq!(count(&ports, 9002), 10);
+
+        // Weights only count for the algorithms that use them
+        let round_robin = self::upstream(
+            r#"backends = [
+                { address = "127.0.0.1:9001", weight = 3 },
+                { address = "127.0.0.1:9002" },
+            ]"#,
+        )
+        .await;
+        let (ports, _) = select(&round_robin, &session, 40);
+        assert_eq!(count(&ports, 9001), 20);
+    }
+
+    #[tokio::test]
+    async fn test_least_connections() {
+        let upstream = upstream(
+            r#"
+            algorithm = "least_connections"
+            backends = [
+                { address = "127.0.0.1:9001", weight = 2 },
+                { address = "127.0.0.1:9002" },
+                { address = "127.0.0.1:9003" },
+            ]"#,
+        )
+        .await;
+        let session = session("").await;
+        // Requests in flight go to the least loaded backend, per unit of weight
+        let (ports, held) = select(&upstream, &session, 8);
+        assert_eq!(count(&ports, 9001), 4);
+        assert_eq!(count(&ports, 9002), 2);
+        assert_eq!(count(&ports, 9003), 2);
+
+        // Finished requests free their backend up again
+        let freed: Vec<Selection> =
+            held.into_iter().filter(|s| s.addr.as_inet().unwrap().port() != 9003).collect();
+        let (ports, _) = select(&upstream, &session, 2);
+        assert_eq!(ports, [9003, 9003]);
+        drop(freed);
+
+        // Ties are spread over the backends
+        let (ports, _) = select(&upstream, &session, 1);
+        let (next, _) = select(&upstream, &session, 1);
+        assert_ne!(ports, next);
+    }
+
+    #[tokio::test]
+    async fn test_consistent_hash() {
+        let upstream = upstream(
+            r#"
+            algorithm = "consistent_hash"
+            hash_key = { header = "x-user" }
+            backends = [
+                { address = "127.0.0.1:9001" },
+                { address = "127.0.0.1:9002" },
+                { address = "127.0.0.1:9003" },
+            ]"#,
+        )
+        .await;
+        let mut seen = BTreeSet::new();
+        for user in 0..50 {
+            let session = session(&format!("X-User: {user}\r\n")).await;
+            let (ports, _) = select(&upstream, &session, 5);
+            // The same key sticks to one backend
+            assert!(ports.iter().all(|port| *port == ports[0]), "{ports:?}");
+            seen.insert(ports[0]);
+        }
+        assert_eq!(seen.len(), 3);
+
+        // A key whose backend is ejected moves, and comes back with it
+        let session = session("X-User: alice\r\n").await;
+        let (_, mut selection) = select(&upstream, &session, 1);
+        let selection = selection.remove(0);
+        let home = selection.addr.clone();
+        *selection.state.ejected_until.lock().unwrap() = Some(Instant::now() + Duration::MAX / 4);
+        let (_, moved) = select(&upstream, &session, 1);
+        assert_ne!(moved[0].addr, home);
+        *selection.state.ejected_until.lock().unwrap() = None;
+        let (_, back) = select(&upstream, &session, 1);
+        assert_eq!(back[0].addr, home);
+    }
+
+    #[tokio::test]
+    async fn test_passive_ejection() {
+        let upstream = upstream(
+            r#"
+            passive_health = { max_failures = 3, ejection_secs = 60 }
+            backends = [{ address = "127.0.0.1:9001" }, { address = "127.0.0.1:9002" }]
+            "#,
+        )
+        .await;
+        let session = session("").await;
+        let failing = |upstream: &Upstream| {
+            let (_, selection) = select(upstream, &session, 2);
+            selection.into_iter().find(|s| s.addr.as_inet().unwrap().port() == 9001).unwrap()
+        };
+
+        // A success in between resets the count
+        failing(&upstream).report(false);
+        failing(&upstream).report(false);
+        failing(&upstream).report(true);
+        failing(&upstream).report(false);
+        failing(&upstream).report(false);
+        let (ports, _) = select(&upstream, &session, 4);
+        assert_eq!(count(&ports, 9001), 2);
+
+        let selection = failing(&upstream);
+        selection.report(false);
+        let (ports, _) = select(&upstream, &session, 4);
+        assert_eq!(ports, [9002; 4]);
+
+        // Back once the ejection is over
+        *selection.state.ejected_until.lock().unwrap() = Some(Instant::now());
+        let (ports, _) = select(&upstream, &session, 4);
+        assert_eq!(count(&ports, 9001), 2);
+
+        // Nothing is selected with every backend out
+        for backend in upstream.state.load().values() {
+            *backend.ejected_until.lock().unwrap() = Some(Instant::now() + Duration::from_secs(60));
+        }
+        let e = upstream.select(&session).err().unwrap();
+        assert_eq!(*e.etype(), NO_HEALTHY_BACKEND);
+
+        // Ejection can be turned off
+        let never = self::upstream(
+            r#"
+            passive_health = { max_failures = 0 }
+            backends = [{ address = "127.0.0.1:9001" }]
+            "#,
+        )
+        .await;
+        for _ in 0..10 {
+            let (_, selection) = select(&never, &session, 1);
+            selection[0].report(false);
+        }
+        assert!(never.select(&session).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_admit() {
+        let upstream = upstream(
+            r#"
+            max_concurrent_requests = 2
+            backends = [{ address = "127.0.0.1:9001" }]
+            "#,
+        )
+        .await;
+        let first = upstream.admit().unwrap();
+        let second = upstream.admit().unwrap();
+        assert!(upstream.admit().is_none());
+        assert!(upstream.admit().is_none());
+        drop(first);
+        let third = upstream.admit().unwrap();
+        assert!(upstream.admit().is_none());
+        drop((second, third));
+        assert_eq!(upstream.requests.load(Ordering::Relaxed), 0);
+
+        let unlimited = self::upstream(r#"backends = [{ address = "127.0.0.1:9001" }]"#).await;
+        let permits: Vec<_> = (0..1000).map(|_| unlimited.admit().unwrap()).collect();
+        assert_eq!(permits.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let up = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let down = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (up_addr, down_addr) = (up.local_addr().unwrap(), down.local_addr().unwrap());
+        drop(down);
+        let upstream = upstream(&format!(
+            r#"
+            health_check = {{ type = "tcp", unhealthy_threshold = 2, healthy_threshold = 2 }}
+            backends = [{{ address = "{up_addr}" }}, {{ address = "{down_addr}" }}]
+            "#
+        ))
+        .await;
+        let session = session("").await;
+        let check = || upstream.balancer.backends().run_health_check(false);
+        let ports = || select(&upstream, &session, 4).0;
+
+        // Backends are in rotation until enough checks fail
+        check().await;
+        assert_eq!(count(&ports(), down_addr.port()), 2);
+        check().await;
+        assert_eq!(ports(), [up_addr.port(); 4]);
+
+        // And out until enough succeed
+        let Ok(_down) = TcpListener::bind(down_addr).await else {
+            return; // The port was taken in the meantime
+        };
+        check().await;
+        assert_eq!(ports(), [up_addr.port(); 4]);
+        check().await;
+        assert_eq!(count(&ports(), down_addr.port()), 2);
+
+        drop(up);
+        check().await;
+        check().await;
+        assert_eq!(ports(), [down_addr.port(); 4]);
+    }
+}